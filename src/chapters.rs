@@ -0,0 +1,178 @@
+use crate::parsers::mkvinfo::Chapter;
+use regex::Regex;
+
+/// Picks chapters of the first edition, by name or by position
+#[derive(Debug, Clone)]
+pub enum ChapterMatcher {
+    /// Every chapter whose name matches
+    Name(Regex),
+    /// A chapter by its position in the first edition, starting at 0
+    Index(usize),
+}
+
+impl ChapterMatcher {
+    /// Parses a comma separated list, where numbers are indices and anything
+    /// else is a name pattern, e.g. `Opening,Ending,Preview` or `0,5`
+    pub fn parse_list(input: &str) -> Result<Vec<Self>, String> {
+        input
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| match item.parse::<usize>() {
+                Ok(index) => Ok(ChapterMatcher::Index(index)),
+                Err(_) => Regex::new(item)
+                    .map(ChapterMatcher::Name)
+                    .map_err(|e| format!("Invalid chapter pattern: {}", e)),
+            })
+            .collect()
+    }
+
+    pub fn matches(&self, index: usize, chapter: &Chapter) -> bool {
+        match *self {
+            ChapterMatcher::Name(ref regex) => chapter
+                .name
+                .as_ref()
+                .map(|name| regex.is_match(name))
+                .unwrap_or(false),
+            ChapterMatcher::Index(wanted) => index == wanted,
+        }
+    }
+}
+
+pub fn is_skipped(skip: &[ChapterMatcher], index: usize, chapter: &Chapter) -> bool {
+    skip.iter().any(|matcher| matcher.matches(index, chapter))
+}
+
+/// Places each chapter on the output as an inclusive frame range, or `None`
+/// if it is skipped or has no end. Ordered chapters, and the chapters left
+/// after skipping some, are played one after another whatever their times
+/// in the source.
+pub fn chapter_output_ranges(
+    chapters: &[Chapter],
+    ordered: bool,
+    skip: &[ChapterMatcher],
+) -> Vec<Option<(u64, u64)>> {
+    let skipped: Vec<bool> = chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| is_skipped(skip, i, chapter))
+        .collect();
+    let spliced = ordered || skipped.contains(&true);
+    let mut position = 0u64;
+    chapters
+        .iter()
+        .zip(skipped)
+        .map(|(chapter, skipped)| {
+            let end_frame = chapter.end_frame?;
+            if skipped || end_frame < chapter.start_frame {
+                return None;
+            }
+            if !spliced {
+                return Some((chapter.start_frame, end_frame));
+            }
+            let start = position;
+            position += end_frame + 1 - chapter.start_frame;
+            Some((start, position - 1))
+        })
+        .collect()
+}
+
+/// Formats chapters as an OGM chapter file, which mkvmerge and most players
+/// understand. Chapters without a range are left out.
+pub fn format_ogm_chapters(
+    chapters: &[Chapter],
+    ranges: &[Option<(u64, u64)>],
+    fps: f64,
+) -> String {
+    let mut output = String::new();
+    let kept = chapters
+        .iter()
+        .zip(ranges)
+        .filter_map(|(chapter, range)| Some((chapter, (*range)?)));
+    for (i, (chapter, (start, _))) in kept.enumerate() {
+        let millis = (start as f64 * 1000f64 / fps).round() as u64;
+        output.push_str(&format!(
+            "CHAPTER{:02}={:02}:{:02}:{:02}.{:03}\n",
+            i + 1,
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000
+        ));
+        output.push_str(&format!(
+            "CHAPTER{:02}NAME={}\n",
+            i + 1,
+            chapter
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("Chapter {:02}", i + 1))
+        ));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(name: &str, start_frame: u64, end_frame: Option<u64>) -> Chapter {
+        Chapter {
+            name: Some(name.to_owned()),
+            start_frame,
+            end_frame,
+            foreign_uuid: None,
+            foreign_file: None,
+        }
+    }
+
+    #[test]
+    fn skip_chapters_by_name_and_index() {
+        let chapters = vec![
+            chapter("Opening", 0, Some(2157)),
+            chapter("Part A", 2158, Some(14999)),
+            chapter("Part B", 15000, Some(31999)),
+            chapter("Ending", 32000, Some(34157)),
+            chapter("Preview", 34158, None),
+        ];
+        assert_eq!(
+            chapter_output_ranges(&chapters, false, &[]),
+            vec![
+                Some((0, 2157)),
+                Some((2158, 14999)),
+                Some((15000, 31999)),
+                Some((32000, 34157)),
+                None
+            ]
+        );
+        let skip = ChapterMatcher::parse_list("Opening, Ending,4").unwrap();
+        let ranges = chapter_output_ranges(&chapters, false, &skip);
+        assert_eq!(
+            ranges,
+            vec![None, Some((0, 12841)), Some((12842, 29841)), None, None]
+        );
+        assert_eq!(
+            format_ogm_chapters(&chapters, &ranges, 24_000f64 / 1_001f64),
+            "CHAPTER01=00:00:00.000\nCHAPTER01NAME=Part A\n\
+             CHAPTER02=00:08:55.618\nCHAPTER02NAME=Part B\n"
+        );
+        assert!(ChapterMatcher::parse_list("(").is_err());
+    }
+
+    #[test]
+    fn ordered_chapters_follow_each_other() {
+        let chapters = vec![
+            chapter("Prologue", 0, Some(239)),
+            chapter("Opening", 0, Some(2157)),
+            chapter("Part A", 240, Some(479)),
+        ];
+        assert_eq!(
+            chapter_output_ranges(&chapters, true, &[]),
+            vec![Some((0, 239)), Some((240, 2397)), Some((2398, 2637))]
+        );
+        let skip = ChapterMatcher::parse_list("1").unwrap();
+        assert_eq!(
+            chapter_output_ranges(&chapters, true, &skip),
+            vec![Some((0, 239)), None, Some((240, 479))]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use crate::parsers::ifo::read_title_layout;
+use crate::parsers::ifo::read_titles;
+use crate::parsers::ifo::DvdTitle;
+use crate::parsers::ifo::TitleLayout;
+use crate::parsers::ifo::SECTOR_SIZE;
+use crate::parsers::mkvinfo::Chapter;
+use std::fs;
+use std::fs::File;
+use std::io::copy;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Finds the `VIDEO_TS` directory of a disc, given either the disc, the
+/// directory itself or its `VIDEO_TS.IFO`
+pub fn find_video_ts(path: &Path) -> Option<PathBuf> {
+    let candidates = if path.is_file() {
+        let name = path.file_name()?.to_str()?;
+        if !name.eq_ignore_ascii_case("VIDEO_TS.IFO") {
+            return None;
+        }
+        vec![path.parent()?.to_owned()]
+    } else {
+        vec![path.to_owned(), path.join("VIDEO_TS")]
+    };
+    candidates
+        .into_iter()
+        .find(|dir| dir.join("VIDEO_TS.IFO").is_file())
+}
+
+/// Reads every title of a disc along with the cells it plays
+pub fn read_disc(video_ts: &Path) -> Result<Vec<(DvdTitle, TitleLayout)>, String> {
+    read_titles(video_ts)?
+        .into_iter()
+        .map(|title| Ok((title, read_title_layout(video_ts, &title)?)))
+        .collect()
+}
+
+/// Picks the given title, or the longest one, which is usually the main feature
+pub fn select_title(
+    titles: Vec<(DvdTitle, TitleLayout)>,
+    number: Option<usize>,
+) -> Result<(DvdTitle, TitleLayout), String> {
+    let count = titles.len();
+    let selected = match number {
+        Some(number) => titles.into_iter().find(|(title, _)| title.number == number),
+        None => titles
+            .into_iter()
+            .max_by_key(|(_, layout)| layout.frame_count()),
+    };
+    selected.ok_or_else(|| match number {
+        Some(number) => format!("Title {} does not exist, the disc has {}", number, count),
+        None => "The disc has no titles".to_owned(),
+    })
+}
+
+/// Describes a title for `--list-titles`
+pub fn describe_title(title: &DvdTitle, layout: &TitleLayout) -> String {
+    let seconds = (layout.frame_count() as f64 / layout.frame_rate).round() as u64;
+    format!(
+        "{:2}: {:02}:{:02}:{:02}, {} chapters, {} angles, {} cells (VTS_{:02})",
+        title.number,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        layout.chapter_cells.len(),
+        title.angles,
+        layout.cells.len(),
+        title.vts
+    )
+}
+
+/// Titles are extracted to `<dir>/<disc>.title<nn>.vob`
+pub fn title_path(video_ts: &Path, title: &DvdTitle, dir: &Path) -> PathBuf {
+    let disc = match video_ts.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.eq_ignore_ascii_case("VIDEO_TS") => {
+            video_ts.parent().unwrap_or_else(|| Path::new(""))
+        }
+        _ => video_ts,
+    };
+    let name = disc
+        .canonicalize()
+        .ok()
+        .and_then(|disc| {
+            disc.file_name()
+                .map(|name| name.to_str().unwrap().to_owned())
+        })
+        .unwrap_or_else(|| "dvd".to_owned());
+    dir.join(format!("{}.title{:02}.vob", name, title.number))
+}
+
+/// Copies the cells of a title out of its title set's VOBs into one file, so
+/// that it can be indexed on its own. Titles which were already copied are kept.
+pub fn extract_title(
+    video_ts: &Path,
+    title: &DvdTitle,
+    layout: &TitleLayout,
+    out_path: &Path,
+) -> Result<(), String> {
+    let size: u64 = layout
+        .cells
+        .iter()
+        .map(|cell| u64::from(cell.last_sector + 1 - cell.first_sector) * SECTOR_SIZE)
+        .sum();
+    if fs::metadata(out_path).ok().map(|meta| meta.len()) == Some(size) {
+        return Ok(());
+    }
+
+    // The title set's VOBs are one address space, split into files of up to 1 GiB
+    let vobs: Vec<(PathBuf, u64)> = (1..10)
+        .map(|i| video_ts.join(format!("VTS_{:02}_{}.VOB", title.vts, i)))
+        .map_while(|path| fs::metadata(&path).ok().map(|meta| (path, meta.len())))
+        .collect();
+    if vobs.is_empty() {
+        return Err(format!(
+            "{} has no VOBs for VTS_{:02}",
+            video_ts.to_str().unwrap(),
+            title.vts
+        ));
+    }
+
+    let mut out =
+        File::create(out_path).map_err(|e| format!("{}: {}", out_path.to_str().unwrap(), e))?;
+    for cell in &layout.cells {
+        let mut start = u64::from(cell.first_sector) * SECTOR_SIZE;
+        let mut remaining = u64::from(cell.last_sector + 1 - cell.first_sector) * SECTOR_SIZE;
+        for (path, length) in &vobs {
+            if remaining == 0 {
+                break;
+            }
+            if start >= *length {
+                start -= length;
+                continue;
+            }
+            let mut vob = File::open(path).map_err(|e| e.to_string())?;
+            vob.seek(SeekFrom::Start(start))
+                .map_err(|e| e.to_string())?;
+            let copied = copy(&mut vob.take(remaining), &mut out).map_err(|e| e.to_string())?;
+            remaining -= copied;
+            start = 0;
+        }
+        if remaining > 0 {
+            return Err(format!(
+                "Cell at sector {} runs past the end of the VOBs of VTS_{:02}",
+                cell.first_sector, title.vts
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The chapters of a title, i.e. the entry points of its programs, in frames
+/// at 120fps instead of the title's own rate with `to_cfr`
+pub fn title_chapters(layout: &TitleLayout, to_cfr: bool) -> Vec<Chapter> {
+    let scale = if to_cfr {
+        120_000f64 / 1_001f64 / layout.frame_rate
+    } else {
+        1f64
+    };
+    let to_frame = |frame: u64| (frame as f64 * scale).round() as u64;
+    let starts: Vec<u64> = layout.chapter_frames().into_iter().map(to_frame).collect();
+    let total = to_frame(layout.frame_count());
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start_frame)| Chapter {
+            name: None,
+            start_frame,
+            end_frame: Some(
+                starts
+                    .get(i + 1)
+                    .copied()
+                    .unwrap_or(total)
+                    .saturating_sub(1),
+            ),
+            foreign_uuid: None,
+            foreign_file: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::ifo::DvdCell;
+
+    #[test]
+    fn chapters_of_title() {
+        let cell = |frames| DvdCell {
+            first_sector: 0,
+            last_sector: 0,
+            frames,
+        };
+        let layout = TitleLayout {
+            cells: vec![cell(300), cell(600), cell(150), cell(885)],
+            chapter_cells: vec![0, 1, 3],
+            frame_rate: 25f64,
+        };
+        let chapters = title_chapters(&layout, false);
+        assert_eq!(
+            chapters
+                .iter()
+                .map(|chapter| (chapter.start_frame, chapter.end_frame))
+                .collect::<Vec<_>>(),
+            vec![(0, Some(299)), (300, Some(1049)), (1050, Some(1934))]
+        );
+        assert_eq!(
+            title_chapters(&layout, true)
+                .iter()
+                .map(|chapter| (chapter.start_frame, chapter.end_frame))
+                .collect::<Vec<_>>(),
+            vec![(0, Some(1438)), (1439, Some(5034)), (5035, Some(9278))]
+        );
+        assert_eq!(
+            describe_title(
+                &DvdTitle {
+                    number: 3,
+                    angles: 1,
+                    chapter_count: 3,
+                    vts: 2,
+                    vts_title: 1,
+                },
+                &layout
+            ),
+            " 3: 00:01:17, 3 chapters, 1 angles, 4 cells (VTS_02)"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use crate::parsers::ffprobe::ColorInfo;
+use crate::parsers::ffprobe::HdrMetadata;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoder {
+    X264,
+    X265,
+    SvtAv1,
+}
+
+/// Builds the arguments which tag the encode with the source's color metadata
+pub fn build_vui_args(color: &ColorInfo, encoder: Encoder) -> Vec<String> {
+    let mut args = Vec::new();
+    match encoder {
+        Encoder::X264 | Encoder::X265 => {
+            if let Some(ref matrix) = color.matrix {
+                args.push("--colormatrix".to_owned());
+                args.push(if matrix == "gbr" { "GBR" } else { matrix }.to_owned());
+            }
+            if let Some(ref primaries) = color.primaries {
+                args.push("--colorprim".to_owned());
+                args.push(primaries.clone());
+            }
+            if let Some(ref transfer) = color.transfer {
+                args.push("--transfer".to_owned());
+                args.push(transfer.clone());
+            }
+            if color.range.is_some() {
+                args.push("--range".to_owned());
+                args.push(
+                    match (encoder, color.is_full_range()) {
+                        (Encoder::X264, true) => "pc",
+                        (Encoder::X264, false) => "tv",
+                        (_, true) => "full",
+                        (_, false) => "limited",
+                    }
+                    .to_owned(),
+                );
+            }
+        }
+        Encoder::SvtAv1 => {
+            if let Some(matrix) = color.matrix_code() {
+                args.push("--matrix-coefficients".to_owned());
+                args.push(matrix.to_string());
+            }
+            if let Some(primaries) = color.primaries_code() {
+                args.push("--color-primaries".to_owned());
+                args.push(primaries.to_string());
+            }
+            if let Some(transfer) = color.transfer_code() {
+                args.push("--transfer-characteristics".to_owned());
+                args.push(transfer.to_string());
+            }
+            if color.range.is_some() {
+                args.push("--color-range".to_owned());
+                args.push(if color.is_full_range() { "1" } else { "0" }.to_owned());
+            }
+        }
+    }
+    args
+}
+
+/// Builds the arguments which pass static HDR metadata on to the encoder
+pub fn build_hdr_args(metadata: &HdrMetadata, encoder: Encoder) -> Vec<String> {
+    let mut args = Vec::new();
+    match encoder {
+        // x264 has no way to signal HDR10 metadata
+        Encoder::X264 => (),
+        Encoder::X265 => {
+            if let Some(display) = metadata.mastering_display {
+                // x265 wants chromaticities in units of 0.00002 and luminance in 0.0001 cd/m²
+                let point = |(x, y): (f64, f64)| {
+                    format!("({},{})", (x * 50_000f64).round(), (y * 50_000f64).round())
+                };
+                args.push("--master-display".to_owned());
+                args.push(format!(
+                    "G{}B{}R{}WP{}L({},{})",
+                    point(display.green),
+                    point(display.blue),
+                    point(display.red),
+                    point(display.white_point),
+                    (display.max_luminance * 10_000f64).round(),
+                    (display.min_luminance * 10_000f64).round()
+                ));
+            }
+            if let Some(light) = metadata.content_light_level {
+                args.push("--max-cll".to_owned());
+                args.push(format!("{},{}", light.max_content, light.max_average));
+            }
+        }
+        Encoder::SvtAv1 => {
+            if let Some(display) = metadata.mastering_display {
+                let point = |(x, y): (f64, f64)| format!("({:.4},{:.4})", x, y);
+                args.push("--mastering-display".to_owned());
+                args.push(format!(
+                    "G{}B{}R{}WP{}L({:.4},{:.4})",
+                    point(display.green),
+                    point(display.blue),
+                    point(display.red),
+                    point(display.white_point),
+                    display.max_luminance,
+                    display.min_luminance
+                ));
+            }
+            if let Some(light) = metadata.content_light_level {
+                args.push("--content-light".to_owned());
+                args.push(format!("{},{}", light.max_content, light.max_average));
+            }
+        }
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parsers::ffprobe::ContentLightLevel;
+    use crate::parsers::ffprobe::MasteringDisplay;
+
+    fn hdr10() -> HdrMetadata {
+        HdrMetadata {
+            mastering_display: Some(MasteringDisplay {
+                red: (0.68, 0.32),
+                green: (0.265, 0.69),
+                blue: (0.15, 0.06),
+                white_point: (0.3127, 0.329),
+                min_luminance: 0.005,
+                max_luminance: 1000f64,
+            }),
+            content_light_level: Some(ContentLightLevel {
+                max_content: 1000,
+                max_average: 400,
+            }),
+        }
+    }
+
+    fn bt709() -> ColorInfo {
+        ColorInfo {
+            matrix: Some("bt709".to_owned()),
+            range: Some("tv".to_owned()),
+            primaries: Some("bt709".to_owned()),
+            transfer: Some("bt709".to_owned()),
+        }
+    }
+
+    #[test]
+    fn x265_vui_args() {
+        assert_eq!(
+            build_vui_args(&bt709(), Encoder::X265).join(" "),
+            "--colormatrix bt709 --colorprim bt709 --transfer bt709 --range limited"
+        );
+    }
+
+    #[test]
+    fn svt_av1_vui_args() {
+        assert_eq!(
+            build_vui_args(&bt709(), Encoder::SvtAv1).join(" "),
+            "--matrix-coefficients 1 --color-primaries 1 --transfer-characteristics 1 --color-range 0"
+        );
+    }
+
+    #[test]
+    fn x265_hdr_args() {
+        assert_eq!(
+            build_hdr_args(&hdr10(), Encoder::X265),
+            vec![
+                "--master-display",
+                "G(13250,34500)B(7500,3000)R(34000,16000)WP(15635,16450)L(10000000,50)",
+                "--max-cll",
+                "1000,400",
+            ]
+        );
+    }
+
+    #[test]
+    fn svt_av1_hdr_args() {
+        assert_eq!(
+            build_hdr_args(&hdr10(), Encoder::SvtAv1),
+            vec![
+                "--mastering-display",
+                "G(0.2650,0.6900)B(0.1500,0.0600)R(0.6800,0.3200)WP(0.3127,0.3290)L(1000.0000,0.0050)",
+                "--content-light",
+                "1000,400",
+            ]
+        );
+    }
+}
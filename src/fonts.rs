@@ -0,0 +1,370 @@
+use crate::parsers::ass::FontUsage;
+use crate::parsers::sfnt::read_font_faces;
+use crate::parsers::sfnt::FontFace;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Lists every font installed into a directory, as `<hash>\t<file name>` lines
+const INSTALL_RECORD: &str = "avsser-fonts.txt";
+
+/// 64-bit FNV-1a, which is plenty to tell font files apart
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Names the font directory of a show after the directory its files are in
+pub fn show_name(input: &Path) -> String {
+    let input = input.canonicalize().unwrap_or_else(|_| input.to_owned());
+    let dir = if input.is_dir() {
+        Some(input.as_path())
+    } else {
+        input.parent()
+    };
+    dir.and_then(|dir| dir.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "default".to_owned())
+}
+
+/// `$XDG_DATA_HOME/fonts/avsser/<show>`, falling back to `~/.local/share`
+pub fn default_font_dir(show: &str) -> Result<PathBuf, String> {
+    let data_home = match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".local").join("share"))
+            .ok_or_else(|| "Neither XDG_DATA_HOME nor HOME is set".to_owned())?,
+    };
+    Ok(data_home.join("fonts").join("avsser").join(show))
+}
+
+fn hash_file(path: &Path) -> Result<u64, String> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    Ok(fnv1a_64(&contents))
+}
+
+fn read_install_record(dir: &Path) -> Result<Vec<(u64, String)>, String> {
+    let path = dir.join(INSTALL_RECORD);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| e.to_string())?;
+    Ok(parse_install_record(&contents))
+}
+
+fn parse_install_record(contents: &str) -> Vec<(u64, String)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (hash, name) = line.split_once('\t')?;
+            Some((u64::from_str_radix(hash, 16).ok()?, name.to_owned()))
+        })
+        .collect()
+}
+
+fn write_install_record(dir: &Path, record: &[(u64, String)]) -> Result<(), String> {
+    let mut file = File::create(dir.join(INSTALL_RECORD)).map_err(|e| e.to_string())?;
+    for (hash, name) in record {
+        writeln!(file, "{:016x}\t{}", hash, name).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Tells apart different fonts with the same name, e.g. `font-<hash>.ttf`
+pub fn hashed_file_name(font: &Path, hash: u64) -> String {
+    let stem = font.file_stem().unwrap().to_string_lossy();
+    match font.extension() {
+        Some(extension) => format!("{}-{:016x}.{}", stem, hash, extension.to_string_lossy()),
+        None => format!("{}-{:016x}", stem, hash),
+    }
+}
+
+/// Copies fonts into a directory, skipping any whose contents are already
+/// installed there or sit there under the same name already. A font which
+/// clashes by name with a different file is installed under its name with
+/// the hash appended. Returns the newly installed files.
+pub fn install_fonts(fonts: &[PathBuf], dir: &Path) -> Result<Vec<PathBuf>, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.to_str().unwrap(), e))?;
+    let mut record = read_install_record(dir)?;
+    let mut installed = Vec::new();
+    for font in fonts {
+        let hash = hash_file(font)?;
+        if record.iter().any(|&(existing, _)| existing == hash) {
+            continue;
+        }
+        let file_name = font.file_name().unwrap().to_string_lossy().into_owned();
+        let existing = dir.join(&file_name);
+        let target_name = if !existing.exists() {
+            file_name
+        } else if hash_file(&existing)? == hash {
+            continue;
+        } else {
+            hashed_file_name(font, hash)
+        };
+        let target = dir.join(&target_name);
+        // A clash installed before, under a name that carries its hash
+        if target.exists() {
+            continue;
+        }
+        fs::copy(font, &target).map_err(|e| format!("{}: {}", target.to_str().unwrap(), e))?;
+        record.push((hash, target_name));
+        installed.push(target);
+    }
+    write_install_record(dir, &record)?;
+    if !installed.is_empty() {
+        refresh_font_cache(dir);
+    }
+    Ok(installed)
+}
+
+/// Removes every font recorded by `install_fonts`, and the directory too if
+/// nothing else is left in it. Returns the removed files.
+pub fn uninstall_fonts(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let record = read_install_record(dir)?;
+    let mut removed = Vec::with_capacity(record.len());
+    for (_, name) in &record {
+        let font = dir.join(name);
+        if font.exists() {
+            fs::remove_file(&font).map_err(|e| format!("{}: {}", font.to_str().unwrap(), e))?;
+            removed.push(font);
+        }
+    }
+    let record_path = dir.join(INSTALL_RECORD);
+    if record_path.exists() {
+        fs::remove_file(&record_path).map_err(|e| e.to_string())?;
+    }
+    if dir.exists() && dir.read_dir().map_err(|e| e.to_string())?.next().is_none() {
+        fs::remove_dir(dir).map_err(|e| e.to_string())?;
+    }
+    if !removed.is_empty() {
+        refresh_font_cache(dir);
+    }
+    Ok(removed)
+}
+
+/// fontconfig picks new fonts up on its own eventually, but not before the
+/// next encode starts
+fn refresh_font_cache(dir: &Path) {
+    let target = if dir.exists() {
+        dir
+    } else {
+        dir.parent().unwrap_or(dir)
+    };
+    // Not every system has fontconfig, so this is only best effort
+    let _ = Command::new("fc-cache").arg(target).status();
+}
+
+/// Where fonts are usually installed, for the coverage check
+pub fn system_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(home) = env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        dirs.push(home.join(".fonts"));
+        dirs.push(home.join(".local").join("share").join("fonts"));
+    }
+    if let Some(data_home) = env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        dirs.push(PathBuf::from(data_home).join("fonts"));
+    }
+    if let Some(windir) = env::var_os("WINDIR") {
+        dirs.push(PathBuf::from(windir).join("Fonts"));
+    }
+    if let Some(local) = env::var_os("LOCALAPPDATA") {
+        dirs.push(
+            PathBuf::from(local)
+                .join("Microsoft")
+                .join("Windows")
+                .join("Fonts"),
+        );
+    }
+    dirs.push(PathBuf::from("/usr/share/fonts"));
+    dirs.push(PathBuf::from("/usr/local/share/fonts"));
+    dirs.push(PathBuf::from("/Library/Fonts"));
+    dirs.dedup();
+    dirs
+}
+
+fn is_font_file(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(extension) => matches!(
+            extension.to_lowercase().as_str(),
+            "ttf" | "otf" | "ttc" | "otc"
+        ),
+        None => false,
+    }
+}
+
+/// Reads the faces of every font in the given files and directories,
+/// searching directories recursively. Unreadable fonts are skipped.
+pub fn collect_font_faces(paths: &[PathBuf]) -> Vec<FontFace> {
+    let mut faces = Vec::new();
+    collect_font_faces_into(paths, &mut HashSet::new(), &mut faces);
+    faces
+}
+
+/// Symlinked directories are followed, but each only once, so that loops end
+fn collect_font_faces_into(
+    paths: &[PathBuf],
+    visited: &mut HashSet<PathBuf>,
+    faces: &mut Vec<FontFace>,
+) {
+    for path in paths {
+        if path.is_dir() {
+            if !path.canonicalize().is_ok_and(|dir| visited.insert(dir)) {
+                continue;
+            }
+            if let Ok(entries) = path.read_dir() {
+                let children: Vec<PathBuf> = entries
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .collect();
+                collect_font_faces_into(&children, visited, faces);
+            }
+        } else if is_font_file(path) {
+            match read_font_faces(path) {
+                Ok(font_faces) => faces.extend(font_faces),
+                Err(e) => eprintln!("Warning: {}", e),
+            }
+        }
+    }
+}
+
+/// Why a font used by the subtitles cannot be rendered as intended
+#[derive(Debug, Clone, PartialEq)]
+pub enum MissingFont {
+    /// No font provides the family at all
+    Family(FontUsage),
+    /// The family exists, but not in this weight or slant, so it will be faked
+    Face(FontUsage),
+}
+
+/// Checks every used font against the available faces
+pub fn check_font_coverage(used: &[FontUsage], faces: &[FontFace]) -> Vec<MissingFont> {
+    used.iter()
+        .filter_map(|usage| {
+            let family: Vec<&FontFace> = faces
+                .iter()
+                .filter(|face| face.has_name(&usage.family))
+                .collect();
+            if family.is_empty() {
+                Some(MissingFont::Family(usage.clone()))
+            } else if family
+                .iter()
+                .any(|face| face.is_bold() == usage.bold && face.italic == usage.italic)
+            {
+                None
+            } else {
+                Some(MissingFont::Face(usage.clone()))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_reference_values() {
+        assert_eq!(fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a_64(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a_64(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn install_and_uninstall_fonts() {
+        let root = env::temp_dir().join(format!("avsser-fonts-{}", std::process::id()));
+        let source = root.join("source");
+        let dir = root.join("installed");
+        fs::create_dir_all(source.join("other")).unwrap();
+        let font = source.join("font.ttf");
+        let duplicate = source.join("copy.ttf");
+        let clashing = source.join("other").join("font.ttf");
+        fs::write(&font, b"font one").unwrap();
+        fs::write(&duplicate, b"font one").unwrap();
+        fs::write(&clashing, b"font two").unwrap();
+
+        let installed = install_fonts(&[font, duplicate, clashing], &dir).unwrap();
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed[0], dir.join("font.ttf"));
+        assert_eq!(
+            installed[1],
+            dir.join(format!("font-{:016x}.ttf", fnv1a_64(b"font two")))
+        );
+        assert_eq!(read_install_record(&dir).unwrap().len(), 2);
+
+        let removed = uninstall_fonts(&dir).unwrap();
+        assert_eq!(removed, installed);
+        assert!(!dir.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn install_skips_identical_files() {
+        let root = env::temp_dir().join(format!("avsser-fonts-same-{}", std::process::id()));
+        let source = root.join("source");
+        let dir = root.join("installed");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        let font = source.join("font.ttf");
+        fs::write(&font, b"font one").unwrap();
+        fs::write(dir.join("font.ttf"), b"font one").unwrap();
+
+        assert!(install_fonts(std::slice::from_ref(&font), &dir)
+            .unwrap()
+            .is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn font_search_survives_symlink_loops() {
+        let root = env::temp_dir().join(format!("avsser-font-loop-{}", std::process::id()));
+        fs::create_dir_all(root.join("fonts")).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("fonts").join("loop")).unwrap();
+        assert!(collect_font_faces(std::slice::from_ref(&root)).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn report_missing_fonts() {
+        let face = |name: &str, weight, italic| FontFace {
+            names: vec![name.to_owned()],
+            weight,
+            italic,
+        };
+        let usage = |family: &str, bold, italic| FontUsage {
+            family: family.to_owned(),
+            bold,
+            italic,
+        };
+        let faces = vec![face("Open Sans", 400, false), face("Open Sans", 700, false)];
+        assert_eq!(
+            check_font_coverage(
+                &[
+                    usage("open sans", false, false),
+                    usage("Open Sans", true, false),
+                    usage("Open Sans", false, true),
+                    usage("Kaushan Script", false, false),
+                ],
+                &faces
+            ),
+            vec![
+                MissingFont::Face(usage("Open Sans", false, true)),
+                MissingFont::Family(usage("Kaushan Script", false, false)),
+            ]
+        );
+    }
+}
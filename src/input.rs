@@ -9,6 +9,11 @@ pub enum InputTypes {
     Avi,
     DgIndex,
     DgAvc,
+    /// A Blu-ray playlist, which is only ever a list of clips
+    Mpls,
+    M2ts,
+    /// A DVD title copied out of its title set
+    Vob,
     Other,
 }
 
@@ -28,7 +33,7 @@ pub fn get_list_of_files(path: &Path, recursive: bool) -> Result<Vec<PathBuf>, S
     Ok(files)
 }
 
-fn get_recursive_files(path: &Path, mut files: &mut Vec<PathBuf>, recursive: bool) {
+fn get_recursive_files(path: &Path, files: &mut Vec<PathBuf>, recursive: bool) {
     let paths = fs::read_dir(path).unwrap();
     for path in paths {
         let next = path.unwrap().path();
@@ -36,7 +41,7 @@ fn get_recursive_files(path: &Path, mut files: &mut Vec<PathBuf>, recursive: boo
             files.push(next.clone());
         }
         if recursive && next.is_dir() {
-            get_recursive_files(next.as_ref(), &mut files, recursive);
+            get_recursive_files(next.as_ref(), files, recursive);
         }
     }
 }
@@ -51,6 +56,9 @@ pub fn determine_input_type(path: &Path) -> Option<InputTypes> {
         "avi" => Some(InputTypes::Avi),
         "d2v" => Some(InputTypes::DgIndex),
         "dga" => Some(InputTypes::DgAvc),
+        "mpls" => Some(InputTypes::Mpls),
+        "m2ts" | "mts" => Some(InputTypes::M2ts),
+        "vob" => Some(InputTypes::Vob),
         "mpeg" | "mpg" | "wmv" | "mov" | "flv" | "webm" | "ivf" => Some(InputTypes::Other),
         _ => None,
     }
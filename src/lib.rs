@@ -1,9 +1,24 @@
 #![warn(clippy::all)]
 
+pub mod chapters;
+pub mod dvd;
+pub mod encoder;
+pub mod fonts;
 pub mod input;
 pub mod output;
+pub mod ranges;
+pub mod selector;
+pub mod subtitles;
 
 pub mod parsers {
+    pub mod ass;
+    pub mod cropdetect;
+    pub mod d2v;
+    pub mod edl;
     pub mod ffprobe;
+    pub mod ifo;
     pub mod mkvinfo;
+    pub mod mpls;
+    pub mod sfnt;
+    pub mod timecodes;
 }
@@ -1,12 +1,40 @@
 #![warn(clippy::all)]
 
+use avsser::dvd::describe_title;
+use avsser::dvd::extract_title;
+use avsser::dvd::find_video_ts;
+use avsser::dvd::read_disc;
+use avsser::dvd::select_title;
+use avsser::dvd::title_chapters;
+use avsser::dvd::title_path;
+use avsser::encoder::build_hdr_args;
+use avsser::encoder::build_vui_args;
+use avsser::encoder::Encoder;
+use avsser::fonts::check_font_coverage;
+use avsser::fonts::collect_font_faces;
+use avsser::fonts::default_font_dir;
+use avsser::fonts::install_fonts;
+use avsser::fonts::show_name;
+use avsser::fonts::system_font_dirs;
+use avsser::fonts::uninstall_fonts;
+use avsser::fonts::MissingFont;
 use avsser::input::determine_input_type;
 use avsser::input::get_list_of_files;
 use avsser::output::*;
+use avsser::parsers::ass::AssScript;
+use avsser::parsers::edl::parse_timecode;
+use avsser::parsers::edl::read_edl;
+use avsser::parsers::ffprobe::get_video_info;
+use avsser::parsers::mkvinfo::get_attachments;
+use avsser::parsers::mkvinfo::AttachmentKind;
+use avsser::parsers::sfnt::FontFace;
+use avsser::subtitles::find_subtitle_file;
 use clap::App;
 use clap::Arg;
 use clap::ArgMatches;
+use regex::Regex;
 use std::path::Path;
+use std::path::PathBuf;
 
 fn main() {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -19,51 +47,471 @@ fn main() {
             .long("subtitle")
             .help("include subtitles with TextSub(input_filename.ass)"))
         .arg(Arg::with_name("sub-extract").short("S").long("sub-extract").help("extract subtitles from the input files (defaults to track 0)"))
-        .arg(Arg::with_name("sub-track").short("T").long("sub-track").help("select which subtitle track to extract, 0-indexed (does nothing without -S)")
+        .arg(Arg::with_name("sub-track").short("T").long("sub-track").help("select which subtitle track to extract by 0-indexed number, lang=CODE, title~TEXT, codec=NAME or forced, \
+         comma-separated to combine them (with --sub-extract-all, this limits the extracted tracks and picks the one used by -s)")
             .takes_value(true).value_name("TRACK"))
+        .arg(Arg::with_name("sub-extract-all").long("sub-extract-all").help("extract every subtitle track to <name>.<track>.<lang>.<title>.<ext> \
+         and list them in <name>.subtitles.tsv"))
+        .arg(Arg::with_name("sub-renderer").long("sub-renderer").help("render text subtitles with assrender, vsfilter, xy-vsfilter or vsfiltermod \
+         (AviSynth), or sub, assrender or xyvsf (VapourSynth)").takes_value(true).value_name("RENDERER"))
+        .arg(Arg::with_name("sub-matrix").long("sub-matrix").help("render subtitles for this YCbCr matrix instead of the one in the script \
+            (the VSFilter family gets a copy of the script with its YCbCr Matrix set)")
+            .takes_value(true).possible_values(&["601", "709", "2020"]).value_name("MATRIX"))
+        .arg(Arg::with_name("sub-fontdir").long("sub-fontdir").help("load subtitle fonts from this directory (assrender and sub)")
+            .takes_value(true).value_name("DIR"))
+        .arg(Arg::with_name("sub-charset").long("sub-charset").help("charset of the subtitles, numeric for the VSFilter family").takes_value(true).value_name("CHARSET"))
         .arg(Arg::with_name("audio").short("a").long("audio").help("include audio from video"))
         .arg(Arg::with_name("audio-ext").short("A").long("audio-ext").help("include audio from separate file with specified extension (takes precedence over audio \
          from video)").takes_value(true).value_name("EXT"))
+        .arg(Arg::with_name("audio-track").long("audio-track").help("select the audio track by 0-indexed number, language code or title")
+            .takes_value(true).value_name("TRACK"))
+        .arg(Arg::with_name("audio-delay").long("audio-delay").help("delay the audio by this many milliseconds, instead of the offset between the streams")
+            .takes_value(true).allow_hyphen_values(true).value_name("MS"))
+        .arg(Arg::with_name("audio-intermediate").long("audio-intermediate").help("decode the audio to a lossless file with ffmpeg and use that instead")
+            .takes_value(true).possible_values(&["flac", "w64"]).value_name("FORMAT"))
         .arg(Arg::with_name("fonts").short("f").long("fonts").help("extract fonts from mkv container"))
-        .arg(Arg::with_name("resize").short("R").long("resize").help("resize video to the given width and height").takes_value(true).value_name("W,H"))
-        .arg(Arg::with_name("filters").short("F").long("filters").help("use a custom filter chain instead of RemoveGrain(1)"))
+        .arg(Arg::with_name("list-attachments").long("list-attachments").help("list the font and other attachments of mkv containers"))
+        .arg(Arg::with_name("extract-attachments").long("extract-attachments").help("extract attachments which are not fonts, such as cover images, \
+         to <name>.attachments"))
+        .arg(Arg::with_name("install-fonts").long("install-fonts").help("extract fonts and install them into $XDG_DATA_HOME/fonts/avsser/<show>"))
+        .arg(Arg::with_name("uninstall-fonts").long("uninstall-fonts").help("remove the fonts installed for this show by --install-fonts, then exit")
+            .conflicts_with("install-fonts"))
+        .arg(Arg::with_name("check-fonts").long("check-fonts").help("report fonts used by the subtitles which are neither extracted, installed \
+         nor in --sub-fontdir"))
+        .arg(Arg::with_name("font-dir").long("font-dir").help("install fonts into this directory instead").takes_value(true).value_name("DIR"))
+        .arg(Arg::with_name("crop").long("crop").help("crop video before resizing, either detected automatically or by the given number of pixels")
+            .takes_value(true).value_name("auto|L,T,R,B"))
+        .arg(Arg::with_name("resize").short("R").long("resize").help("resize video to the given width and height, use -1 for either to keep the aspect ratio").takes_value(true).value_name("W,H"))
+        .arg(Arg::with_name("resize-mod").long("resize-mod").help("round a computed resize dimension to this mod (defaults to 2)").takes_value(true).value_name("MOD"))
+        .arg(Arg::with_name("filters").short("F").long("filters").help("use a custom filter chain instead of RemoveGrain(1)").takes_value(true).value_name("FILTERS"))
+        .arg(Arg::with_name("heavy-filters").long("heavy-filters").help("like --filters, but left out of the fast first-pass script").takes_value(true).value_name("FILTERS"))
+        .arg(Arg::with_name("zone").long("zone").help("apply a filter to part of the output only, where RANGE is ch:REGEX (matching chapter names), \
+            ch#N (0-indexed chapter) or comma-separated START-END ranges in frames or timestamps (may be repeated)")
+            .takes_value(true).multiple(true).number_of_values(1).value_name("RANGE=FILTER"))
+        .arg(Arg::with_name("skip-chapters").long("skip-chapters").help("leave chapters out of the output, given as comma-separated name patterns \
+            or 0-indexed numbers, e.g. \"Opening,Ending,Preview\"").takes_value(true).value_name("CHAPTERS"))
+        .arg(Arg::with_name("trim").long("trim").help("only keep the given comma-separated START-END ranges of the input, in frames or timestamps \
+            (e.g. 00:01:30.500-00:22:10,25000-), ignoring ordered chapters").takes_value(true).conflicts_with("skip-chapters").value_name("RANGES"))
+        .arg(Arg::with_name("edl").long("edl").help("splice together the cuts of a CMX3600 or mpv EDL instead of following ordered chapters, \
+            where cuts may come from other files").takes_value(true).conflicts_with_all(&["trim", "skip-chapters"]).value_name("FILE"))
+        .arg(Arg::with_name("edl-start").long("edl-start").help("the timecode the sources of a CMX3600 EDL start at, e.g. 01:00:00:00, \
+            instead of the one tagged in their metadata").takes_value(true).requires("edl").value_name("TIMECODE"))
+        .arg(Arg::with_name("export-chapters").long("export-chapters").help("write the chapters of the output, without skipped ones, to input_filename.chapters.txt"))
+        .arg(Arg::with_name("title").long("title").help("the title to use when the input is a DVD's VIDEO_TS directory (defaults to the longest), \
+            sourced from <disc>.title<nn>.d2v in --title-dir if its VOBs were indexed there, or else <disc>.title<nn>.vob").takes_value(true).value_name("N"))
+        .arg(Arg::with_name("extract-title").long("extract-title").help("copy the DVD title out of its VOBs to <disc>.title<nn>.vob in --title-dir, \
+            unless that was done before"))
+        .arg(Arg::with_name("title-dir").long("title-dir").help("the directory DVD titles are extracted to and looked for in (defaults to the current directory)")
+            .takes_value(true).value_name("DIR"))
+        .arg(Arg::with_name("list-titles").long("list-titles").help("list the titles of a DVD, then exit"))
         .arg(Arg::with_name("remove-grain").short("G").long("remove-grain").help("simple add a RemoveGrain(1) filter"))
         .arg(Arg::with_name("120").long("120").help("convert VFR to 120fps CFR (only works with MKVs)"))
-        .arg(Arg::with_name("downsample").long("downsample").alias("ds").help("downsample video to YUV420P8"))
+        .arg(Arg::with_name("color-props").long("color-props").help("tag frames with the source's color matrix, range, primaries and transfer (VapourSynth only)"))
+        .arg(Arg::with_name("downsample").long("downsample").alias("ds").help("downsample video to YUV420 at the source's bit depth, or the format given with --format"))
+        .arg(Arg::with_name("format").long("format").help("output format for --downsample (implies --downsample)")
+            .takes_value(true).possible_values(&["YUV420P8", "YUV420P10", "YUV420P12", "YUV444P16", "RGB24"]).value_name("FORMAT"))
+        .arg(Arg::with_name("dither").long("dither").help("dither type used when reducing bit depth").takes_value(true)
+            .possible_values(&["none", "ordered", "error_diffusion"]).value_name("TYPE"))
+        .arg(Arg::with_name("kernel").long("kernel").help("kernel used for resizing and chroma resampling (defaults to spline64)").takes_value(true)
+            .value_name("bicubic[:B:C]|lanczos[:TAPS]|spline16|spline36|spline64|point"))
         .arg(Arg::with_name("vapour").long("vs").help("generate a vapoursynth script instead"))
-        .arg(Arg::with_name("fast-fp").alias("with-fp").long("fast-fp").help("also generate a fast .fp.vpy script"))
+        .arg(Arg::with_name("fast-fp").alias("with-fp").long("fast-fp").help("also generate a fast .fp script without subtitles or heavy filters"))
+        .arg(Arg::with_name("fast-fp-skip").long("fast-fp-skip").help("only keep every Nth frame in the fast .fp script (does nothing without --fast-fp)")
+            .takes_value(true).value_name("N"))
+        .arg(Arg::with_name("ivtc").long("ivtc").help("IVTC with two-pass TFM/TDecimate, also generates a .pass1 script which writes the metrics"))
+        .arg(Arg::with_name("ivtc-one-pass").long("ivtc-one-pass").help("IVTC to CFR in one pass with TFM/TDecimate, or VFM/VDecimate for --vs, without any metrics files"))
+        .arg(Arg::with_name("encoder-args").long("encoder-args").help("print the arguments which pass the source's color tags and HDR metadata on to the encoder")
+            .takes_value(true).possible_values(&["x264", "x265", "svt-av1"]).value_name("ENCODER"))
         .get_matches();
 
-    let input = matches.value_of("input").unwrap();
-    let input = get_list_of_files(Path::new(&input), false).unwrap();
+    let input = Path::new(matches.value_of("input").unwrap());
+    // Only looked up when needed, as there may be no default on this platform
+    let font_dir = if matches.is_present("install-fonts") || matches.is_present("uninstall-fonts") {
+        Some(font_dir_opt(&matches, input).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }))
+    } else if matches.is_present("check-fonts") {
+        font_dir_opt(&matches, input).ok()
+    } else {
+        None
+    };
+    if let (true, Some(font_dir)) = (matches.is_present("uninstall-fonts"), &font_dir) {
+        match uninstall_fonts(font_dir) {
+            Ok(removed) => println!(
+                "Removed {} fonts from {}",
+                removed.len(),
+                font_dir.to_str().unwrap()
+            ),
+            Err(e) => eprintln!("An error occurred removing fonts: {}", e),
+        }
+        return;
+    }
+
+    // Installed fonts are the same for every file, so only read them once
+    let mut installed_faces: Option<Vec<FontFace>> = None;
+    let video_ts = find_video_ts(input);
+    // DVD titles carry no chapters themselves, they come from the program chain
+    let mut dvd_chapters: Vec<Chapter> = Vec::new();
+    let input = match video_ts {
+        Some(ref video_ts) => match prepare_dvd_title(video_ts, &matches) {
+            Ok(Some((path, chapters))) => {
+                dvd_chapters = chapters;
+                vec![path]
+            }
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("An error occurred on {}: {}", video_ts.to_str().unwrap(), e);
+                std::process::exit(1)
+            }
+        },
+        None => get_list_of_files(input, false).unwrap(),
+    };
     for path in input {
         if determine_input_type(path.as_ref()).is_none() {
             continue;
         }
-        if matches.is_present("fonts") {
-            extract_fonts(path.as_ref()).unwrap();
+        if matches.is_present("list-attachments") {
+            if let Err(e) = list_attachments(&path) {
+                eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e);
+            }
+        }
+        if matches.is_present("extract-attachments") {
+            if let Err(e) = extract_other_attachments(&path) {
+                eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e);
+            }
         }
-        if let Err(e) = create_output(&path, &matches) {
+        if matches.is_present("fonts") || matches.is_present("install-fonts") {
+            match (extract_segment_fonts(path.as_ref()), &font_dir) {
+                (Ok(fonts), Some(font_dir)) if matches.is_present("install-fonts") => {
+                    match install_fonts(&fonts, font_dir) {
+                        Ok(installed) => {
+                            for font in installed {
+                                println!("Installed {}", font.to_str().unwrap());
+                            }
+                        }
+                        Err(e) => eprintln!("An error occurred installing fonts: {}", e),
+                    }
+                }
+                (Ok(_), _) => (),
+                (Err(e), _) => {
+                    eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e)
+                }
+            }
+        }
+        if let Err(e) = create_output(&path, &matches, &dvd_chapters) {
             eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e);
         }
+        if matches.is_present("export-chapters") {
+            let exported = skip_chapters_opt(&matches).and_then(|skip| {
+                export_chapters(&path, &dvd_chapters, matches.is_present("120"), &skip)
+            });
+            match exported {
+                Ok(chapters) => println!("Exported {}", chapters.to_str().unwrap()),
+                Err(e) => eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e),
+            }
+        }
+        if matches.is_present("check-fonts") {
+            let faces = installed_faces.get_or_insert_with(|| {
+                let mut dirs = system_font_dirs();
+                dirs.extend(font_dir.clone());
+                if let Some(dir) = matches.value_of("sub-fontdir") {
+                    dirs.push(PathBuf::from(dir));
+                }
+                collect_font_faces(&dirs)
+            });
+            if let Err(e) = check_fonts(&path, &matches, faces) {
+                eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e);
+            }
+        }
+        if let Some(encoder) = matches.value_of("encoder-args") {
+            if let Err(e) = print_encoder_args(&path, encoder) {
+                eprintln!("An error occurred on {}: {}", path.to_str().unwrap(), e);
+            }
+        }
+    }
+}
+
+/// The directory fonts are installed to, by default one per show
+fn font_dir_opt(matches: &ArgMatches, input: &Path) -> Result<PathBuf, String> {
+    match matches.value_of("font-dir") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => default_font_dir(&show_name(input)),
     }
 }
 
-fn resize_opt_into_dimensions(pair: &str) -> (u32, u32) {
-    let items: Vec<&str> = pair.split(|c| c == ',' || c == 'x' || c == 'X').collect();
+/// Finds the selected title of a DVD, copying it out of its VOBs if asked to,
+/// and returns the file to generate the script for along with the title's
+/// chapters, or `None` if only listing titles
+fn prepare_dvd_title(
+    video_ts: &Path,
+    matches: &ArgMatches,
+) -> Result<Option<(PathBuf, Vec<Chapter>)>, String> {
+    let titles = read_disc(video_ts)?;
+    if matches.is_present("list-titles") {
+        for (title, layout) in &titles {
+            println!("{}", describe_title(title, layout));
+        }
+        return Ok(None);
+    }
+    let number = match matches.value_of("title") {
+        Some(number) => Some(
+            number
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid title: {}", number))?,
+        ),
+        None => None,
+    };
+    let (title, layout) = select_title(titles, number)?;
+    let dir = Path::new(matches.value_of("title-dir").unwrap_or("."));
+    let path = title_path(video_ts, &title, dir);
+    // A DGIndex project of the title decodes MPEG-2 more reliably than LWLibav,
+    // and needs no copy of the title as it can index the disc's VOBs directly
+    let d2v = path.with_extension("d2v");
+    let source = if d2v.is_file() {
+        d2v
+    } else if matches.is_present("extract-title") {
+        extract_title(video_ts, &title, &layout, &path)?;
+        path
+    } else if path.is_file() {
+        path
+    } else {
+        return Err(format!(
+            "Title {} was neither indexed to {} nor extracted to {}, \
+             index its VOBs with DGIndex or pass --extract-title",
+            title.number,
+            d2v.to_str().unwrap(),
+            path.to_str().unwrap()
+        ));
+    };
+    println!(
+        "Using title {} as {}",
+        title.number,
+        source.to_str().unwrap()
+    );
+    let chapters = title_chapters(&layout, matches.is_present("120"));
+    Ok(Some((source, chapters)))
+}
+
+fn list_attachments(path: &Path) -> Result<(), String> {
+    let attachments = get_attachments(path)?;
+    println!("{}:", path.to_str().unwrap());
+    for (heading, kinds) in [
+        (
+            "Fonts",
+            &[AttachmentKind::Font, AttachmentKind::Unknown][..],
+        ),
+        ("Other attachments", &[AttachmentKind::Other][..]),
+    ] {
+        println!("  {}:", heading);
+        for attachment in attachments
+            .iter()
+            .filter(|attachment| kinds.contains(&attachment.kind()))
+        {
+            println!(
+                "    {}: {} ({}){}",
+                attachment.id,
+                attachment.file_name,
+                attachment.mime_type,
+                if attachment.kind() == AttachmentKind::Unknown {
+                    ", if it is a font"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_fonts(path: &Path, matches: &ArgMatches, installed: &[FontFace]) -> Result<(), String> {
+    let merged = path.with_extension("merged.ass");
+    let subtitles = if merged.exists() {
+        merged
+    } else {
+        let selector = matches.value_of("sub-track").map(StreamSelector::parse);
+        find_subtitle_file(path, selector.as_ref())?
+    };
+    match subtitles.extension().and_then(|ext| ext.to_str()) {
+        Some("ass") | Some("ssa") if subtitles.exists() => (),
+        _ => return Ok(()),
+    }
+
+    // Extracted fonts are dropped next to the video
+    let siblings: Vec<PathBuf> = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .read_dir()
+        .map_err(|e| e.to_string())?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    let mut faces = collect_font_faces(&siblings);
+    faces.extend_from_slice(installed);
+
+    let used = AssScript::open(&subtitles)?.used_fonts();
+    for missing in check_font_coverage(&used, &faces) {
+        let (usage, message) = match missing {
+            MissingFont::Family(ref usage) => (usage, "missing font"),
+            MissingFont::Face(ref usage) => (usage, "missing face, it will be synthesized"),
+        };
+        println!(
+            "{}: {} {}{}{}",
+            subtitles.to_str().unwrap(),
+            message,
+            usage.family,
+            if usage.bold { " Bold" } else { "" },
+            if usage.italic { " Italic" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+fn print_encoder_args(path: &Path, encoder: &str) -> Result<(), String> {
+    let encoder = match encoder {
+        "x264" => Encoder::X264,
+        "x265" => Encoder::X265,
+        _ => Encoder::SvtAv1,
+    };
+    let info = get_video_info(path)?;
+    let mut args = build_vui_args(&info.color.with_guessed_matrix(info.height), encoder);
+    args.extend(build_hdr_args(&info.hdr_metadata, encoder));
+    // Mastering display values contain parentheses, which need quoting in a shell
+    let args = args
+        .into_iter()
+        .map(|arg| {
+            if arg.contains('(') {
+                format!("\"{}\"", arg)
+            } else {
+                arg
+            }
+        })
+        .collect::<Vec<String>>();
+    println!("{}: {}", path.to_str().unwrap(), args.join(" "));
+    Ok(())
+}
+
+fn resize_opt_into_dimensions(pair: &str) -> Result<(Option<u32>, Option<u32>), String> {
+    let items: Vec<&str> = pair.split([',', 'x', 'X']).collect();
     if items.len() != 2 {
-        panic!("Expected exactly 2 arguments (comma-separated or x-separated) for 'resize'");
+        return Err(
+            "Expected exactly 2 arguments (comma-separated or x-separated) for 'resize'".to_owned(),
+        );
+    }
+    let dimension = |item: &str, name: &str| -> Result<Option<u32>, String> {
+        match item.trim() {
+            "-1" => Ok(None),
+            item => item
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid {} supplied to resizer: {}", name, item)),
+        }
+    };
+
+    Ok((
+        dimension(items[0], "width")?,
+        dimension(items[1], "height")?,
+    ))
+}
+
+fn kernel_opt_into_kernel(opt: &str) -> Result<ResizeKernel, String> {
+    let mut items = opt.split(':');
+    let name = items.next().unwrap_or_default().to_lowercase();
+    let params = items
+        .map(|item| item.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| format!("Invalid kernel parameters supplied: {}", opt))?;
+    match (name.as_ref(), params.as_slice()) {
+        ("bicubic", []) => Ok(ResizeKernel::Bicubic {
+            b: 1f64 / 3f64,
+            c: 1f64 / 3f64,
+        }),
+        ("bicubic", [b, c]) => Ok(ResizeKernel::Bicubic { b: *b, c: *c }),
+        ("lanczos", []) => Ok(ResizeKernel::Lanczos { taps: 3 }),
+        ("lanczos", [taps]) if *taps >= 1f64 && taps.fract() == 0f64 => {
+            Ok(ResizeKernel::Lanczos { taps: *taps as u32 })
+        }
+        ("spline16", []) => Ok(ResizeKernel::Spline16),
+        ("spline36", []) => Ok(ResizeKernel::Spline36),
+        ("spline64", []) => Ok(ResizeKernel::Spline64),
+        ("point", []) => Ok(ResizeKernel::Point),
+        _ => Err(format!("Invalid kernel supplied: {}", opt)),
+    }
+}
+
+fn renderer_opt_into_renderer(opt: &str, vapoursynth: bool) -> Result<SubtitleRenderer, String> {
+    match (opt.to_lowercase().as_ref(), vapoursynth) {
+        ("assrender", _) => Ok(SubtitleRenderer::AssRender),
+        ("vsfilter", false) => Ok(SubtitleRenderer::VsFilter),
+        ("xy-vsfilter", false) => Ok(SubtitleRenderer::XyVsFilter),
+        ("vsfiltermod", false) => Ok(SubtitleRenderer::VsFilterMod),
+        ("sub", true) => Ok(SubtitleRenderer::Sub),
+        ("xyvsf", true) => Ok(SubtitleRenderer::XyVsf),
+        _ => Err(format!(
+            "Invalid subtitle renderer for {}: {}",
+            if vapoursynth {
+                "VapourSynth"
+            } else {
+                "AviSynth"
+            },
+            opt
+        )),
+    }
+}
+
+fn skip_chapters_opt(matches: &ArgMatches) -> Result<Vec<ChapterMatcher>, String> {
+    match matches.value_of("skip-chapters") {
+        Some(chapters) => ChapterMatcher::parse_list(chapters),
+        None => Ok(Vec::new()),
     }
+}
 
-    (
-        items[0].parse().expect("Invalid width supplied to resizer"),
-        items[1]
-            .parse()
-            .expect("Invalid height supplied to resizer"),
-    )
+fn zone_opt_into_zone(opt: &str) -> Result<Zone, String> {
+    let (range, filter) = opt
+        .split_once('=')
+        .ok_or_else(|| format!("Expected RANGE=FILTER for zone: {}", opt))?;
+    let range = if let Some(pattern) = range.strip_prefix("ch:") {
+        ZoneRange::Chapter(ChapterMatcher::Name(
+            Regex::new(pattern).map_err(|e| format!("Invalid chapter pattern for zone: {}", e))?,
+        ))
+    } else if let Some(index) = range.strip_prefix("ch#") {
+        ZoneRange::Chapter(ChapterMatcher::Index(
+            index
+                .parse()
+                .map_err(|_| format!("Invalid chapter index for zone: {}", index))?,
+        ))
+    } else {
+        ZoneRange::Ranges(Range::parse_list(range)?)
+    };
+    Ok(Zone {
+        range,
+        filter: filter.trim_start_matches('.').to_string(),
+    })
 }
 
-fn create_output(path: &Path, matches: &ArgMatches) -> Result<(), String> {
+fn crop_opt_into_mode(opt: &str) -> Result<CropMode, String> {
+    if opt == "auto" {
+        return Ok(CropMode::Auto);
+    }
+    let items = opt
+        .split(',')
+        .map(|item| item.trim().parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| format!("Invalid value supplied for crop: {}", opt))?;
+    if items.len() != 4 {
+        return Err("Expected 'auto' or exactly 4 comma-separated arguments for 'crop'".to_owned());
+    }
+
+    Ok(CropMode::Manual(Crop {
+        left: items[0],
+        top: items[1],
+        right: items[2],
+        bottom: items[3],
+    }))
+}
+
+fn create_output(path: &Path, matches: &ArgMatches, chapters: &[Chapter]) -> Result<(), String> {
     let opts = AvsOptions {
         filters: if matches.is_present("filters") {
             // FIXME: This is probably broken with avs, definitely broken with vpy
@@ -75,37 +523,159 @@ fn create_output(path: &Path, matches: &ArgMatches) -> Result<(), String> {
         } else {
             vec![]
         },
+        heavy_filters: matches
+            .value_of("heavy-filters")
+            .map(|filters| vec![filters.trim_start_matches('.').to_string()])
+            .unwrap_or_default(),
         ass: matches.is_present("subtitle"),
-        ass_extract: if matches.is_present("sub-extract") {
-            Some(
+        ass_track: matches.value_of("sub-track").map(StreamSelector::parse),
+        sub_extract: if matches.is_present("sub-extract-all") {
+            Some(SubtitleExtraction::All(
+                matches.value_of("sub-track").map(StreamSelector::parse),
+            ))
+        } else if matches.is_present("sub-extract") {
+            Some(SubtitleExtraction::Single(
                 matches
                     .value_of("sub-track")
-                    .map(|track| track.parse().expect("Invalid argument supplied for track"))
-                    .unwrap_or(0),
-            )
+                    .map(StreamSelector::parse)
+                    .unwrap_or(StreamSelector::Index(0)),
+            ))
         } else {
             None
         },
+        sub_renderer: match matches.value_of("sub-renderer") {
+            Some(renderer) => Some(renderer_opt_into_renderer(
+                renderer,
+                matches.is_present("vapour"),
+            )?),
+            None => None,
+        },
+        sub_matrix: matches.value_of("sub-matrix").map(|matrix| match matrix {
+            "601" => SubtitleMatrix::Bt601,
+            "2020" => SubtitleMatrix::Bt2020,
+            _ => SubtitleMatrix::Bt709,
+        }),
+        sub_fontdir: matches.value_of("sub-fontdir").map(PathBuf::from),
+        sub_charset: matches
+            .value_of("sub-charset")
+            .map(|charset| charset.to_string()),
         audio: (
             matches.is_present("audio"),
             matches.value_of("audio-ext").map(|ext| ext.to_string()),
         ),
-        resize: matches
-            .value_of("resize")
-            .map(|resize| resize_opt_into_dimensions(resize)),
+        audio_track: matches.value_of("audio-track").map(StreamSelector::parse),
+        audio_intermediate: match matches.value_of("audio-intermediate") {
+            Some("flac") => Some(AudioIntermediate::Flac),
+            Some(_) => Some(AudioIntermediate::W64),
+            None => None,
+        },
+        audio_delay: match matches.value_of("audio-delay") {
+            Some(delay) => Some(
+                delay
+                    .parse()
+                    .map_err(|_| "Invalid argument supplied for audio-delay")?,
+            ),
+            None => None,
+        },
+        crop: match matches.value_of("crop") {
+            Some(crop) => Some(crop_opt_into_mode(crop)?),
+            None => None,
+        },
+        resize: match matches.value_of("resize") {
+            Some(resize) => Some(resize_opt_into_dimensions(resize)?),
+            None => None,
+        },
+        resize_kernel: match matches.value_of("kernel") {
+            Some(kernel) => kernel_opt_into_kernel(kernel)?,
+            None => ResizeKernel::default(),
+        },
+        resize_mod: match matches.value_of("resize-mod") {
+            Some(modulus) => Some(
+                modulus
+                    .parse()
+                    .ok()
+                    .filter(|&modulus| modulus > 0)
+                    .ok_or("Invalid argument supplied for resize-mod")?,
+            ),
+            None => None,
+        },
         to_cfr: matches.is_present("120"),
-        downsample: matches.is_present("downsample"),
+        color_props: matches.is_present("color-props"),
+        downsample: matches.is_present("downsample") || matches.is_present("format"),
+        output_format: matches.value_of("format").map(|format| match format {
+            "YUV420P10" => OutputFormat::Yuv420P10,
+            "YUV420P12" => OutputFormat::Yuv420P12,
+            "YUV444P16" => OutputFormat::Yuv444P16,
+            "RGB24" => OutputFormat::Rgb24,
+            _ => OutputFormat::Yuv420P8,
+        }),
+        dither: match matches.value_of("dither") {
+            Some("ordered") => DitherType::Ordered,
+            Some("error_diffusion") => DitherType::ErrorDiffusion,
+            _ => DitherType::None,
+        },
         fast_fp: matches.is_present("fast-fp"),
+        fast_fp_skip: match matches.value_of("fast-fp-skip") {
+            Some(cycle) => Some(
+                cycle
+                    .parse()
+                    .ok()
+                    .filter(|&cycle| cycle > 0)
+                    .ok_or("Invalid argument supplied for fast-fp-skip")?,
+            ),
+            None => None,
+        },
+        ivtc: matches.is_present("ivtc") || matches.is_present("ivtc-one-pass"),
+        ivtc_one_pass: matches.is_present("ivtc-one-pass"),
+        skip_chapters: skip_chapters_opt(matches)?,
+        chapters: chapters.to_vec(),
+        trim: match matches.value_of("trim") {
+            Some(trim) => Range::parse_list(trim)?,
+            None => Vec::new(),
+        },
+        edl: match matches.value_of("edl") {
+            Some(edl) => read_edl(Path::new(edl))?,
+            None => Vec::new(),
+        },
+        edl_start: match matches.value_of("edl-start") {
+            Some(start) => Some(parse_timecode(start, false)?),
+            None => None,
+        },
+        zones: match matches.values_of("zone") {
+            Some(zones) => zones.map(zone_opt_into_zone).collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        },
     };
+    if !(opts.trim.is_empty() && opts.edl.is_empty())
+        && opts
+            .zones
+            .iter()
+            .any(|zone| matches!(zone.range, ZoneRange::Chapter(_)))
+    {
+        return Err("Chapter zones cannot be combined with --trim or --edl".to_owned());
+    }
+    let renderer = opts
+        .sub_renderer
+        .unwrap_or(if matches.is_present("vapour") {
+            SubtitleRenderer::Sub
+        } else {
+            SubtitleRenderer::VsFilter
+        });
+    if opts.sub_fontdir.is_some() && !renderer.supports_fontdir() {
+        eprintln!("Warning: {:?} cannot load fonts from a directory", renderer);
+    }
+    if let Some(ref charset) = opts.sub_charset {
+        if renderer.numeric_charset() && charset.parse::<u8>().is_err() {
+            return Err(format!(
+                "{:?} needs a numeric charset, such as 128 for Shift-JIS",
+                renderer
+            ));
+        }
+    }
     let mut writer: Box<dyn ScriptFormat> = if matches.is_present("vapour") {
         Box::new(VapoursynthWriter::new(
             opts,
             matches.is_present("remove-grain"),
-            if matches.is_present("audio") {
-                Some(path.with_extension("flac"))
-            } else {
-                None
-            },
         ))
     } else {
         Box::new(AvisynthWriter::new(
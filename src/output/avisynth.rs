@@ -7,34 +7,23 @@ pub struct AvisynthWriter {
 
 impl ScriptFormat for AvisynthWriter {
     fn build_video_filter_string(&self, current_filename: &Path, is_preload: bool) -> String {
-        let video_filter = self.get_video_filter_full_name(&current_filename);
+        let video_filter = self.get_video_filter_full_name(current_filename);
         let timecodes_path = current_filename.with_extension("timecodes.txt");
         if self.opts.to_cfr && !timecodes_path.exists() {
             File::create(&timecodes_path).ok();
         }
         let mut filter_opts = String::new();
-        if self.opts.downsample {
-            filter_opts.push_str(", format = \"YUV420P8\"");
-        }
         if self.opts.to_cfr && is_preload {
             filter_opts.push_str(&format!(
                 ", timecodes=\"{}\"",
-                timecodes_path
-                    .canonicalize()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
+                timecodes_path.canonicalize().unwrap().to_str().unwrap(),
             ));
         }
 
         format!(
-            "{}({}{})",
+            "{}(\"{}\"{})",
             video_filter,
-            format!(
-                "\"{}\"",
-                current_filename.canonicalize().unwrap().to_str().unwrap()
-            ),
+            current_filename.canonicalize().unwrap().to_str().unwrap(),
             filter_opts
         )
     }
@@ -49,10 +38,56 @@ impl ScriptFormat for AvisynthWriter {
         "avs"
     }
 
-    fn build_downsample_string(&self) -> Option<String> {
+    fn build_color_props_string(&self, _color: &ColorInfo) -> Option<String> {
+        // Conversions are given the matrix explicitly instead
         None
     }
 
+    fn build_downsample_string(&self, format: OutputFormat, color: &ColorInfo) -> Option<String> {
+        let chromaresample = match self.opts.resize_kernel {
+            ResizeKernel::Bicubic { .. } => "bicubic",
+            ResizeKernel::Lanczos { .. } => "lanczos",
+            ResizeKernel::Spline16 => "spline16",
+            ResizeKernel::Spline36 => "spline36",
+            ResizeKernel::Spline64 => "spline64",
+            ResizeKernel::Point => "point",
+        };
+        let convert = match format {
+            OutputFormat::Yuv420P8 | OutputFormat::Yuv420P10 | OutputFormat::Yuv420P12 => {
+                "ConvertToYUV420"
+            }
+            OutputFormat::Yuv444P16 => "ConvertToYUV444",
+            OutputFormat::Rgb24 => "ConvertToPlanarRGB",
+        };
+        let dither = match self.opts.dither {
+            DitherType::None => String::new(),
+            DitherType::Ordered => ", dither=0".to_owned(),
+            DitherType::ErrorDiffusion => ", dither=1".to_owned(),
+        };
+        let matrix = match color.matrix.as_deref() {
+            Some("bt709") => Some("709"),
+            Some("bt470bg") | Some("smpte170m") => Some("601"),
+            Some("bt2020nc") | Some("bt2020c") => Some("2020"),
+            _ => None,
+        }
+        .map(|matrix| {
+            format!(
+                "matrix=\"{}{}\", ",
+                if color.is_full_range() { "PC." } else { "Rec" },
+                matrix
+            )
+        })
+        .unwrap_or_default();
+        Some(format!(
+            "{}({}chromaresample=\"{}\").ConvertBits({}{})",
+            convert,
+            matrix,
+            chromaresample,
+            format.bit_depth(),
+            dither
+        ))
+    }
+
     fn build_vfr_string(&self, timecodes_path: &Path) -> String {
         format!(
             "vfrtocfr(timecodes=\"{}\", fpsnum=120000, fpsden=1001)",
@@ -60,49 +95,184 @@ impl ScriptFormat for AvisynthWriter {
         )
     }
 
-    fn build_audio_dub_string(&mut self, audio_filename: &Path) -> String {
-        format!(
-            "AudioDub(FFAudioSource(\"{}\"))",
-            audio_filename.to_str().unwrap()
-        )
+    fn build_audio_dub_string(
+        &mut self,
+        audio_filename: &Path,
+        track: Option<usize>,
+        delay: i64,
+    ) -> Result<SegmentFilter, String> {
+        // Use the same indexer as the video so that only one index gets created
+        let (audio_filter, track_opt) = if self.opts.downsample {
+            ("LWLibavAudioSource", "stream_index")
+        } else {
+            ("FFAudioSource", "track")
+        };
+        let mut source_opts = track
+            .map(|track| format!(", {}={}", track_opt, track))
+            .unwrap_or_default();
+        // FFAudioSource compensates for the delay by default, which would apply it twice
+        if delay != 0 && audio_filter == "FFAudioSource" {
+            source_opts.push_str(", adjustdelay=-3");
+        }
+        let mut filter = format!(
+            "AudioDub({}(\"{}\"{}))",
+            audio_filter,
+            audio_filename.to_str().unwrap(),
+            source_opts
+        );
+        if delay != 0 {
+            filter.push_str(&format!(".DelayAudio({:.3})", delay as f64 / 1000f64));
+        }
+        Ok(SegmentFilter::Full(filter))
     }
 
     fn build_subtitle_string(&self, subtitle_filename: &Path) -> String {
-        format!("TextSub(\"{}\")", subtitle_filename.to_str().unwrap())
+        let filename = subtitle_filename.to_str().unwrap();
+        match subtitle_filename.extension().and_then(|ext| ext.to_str()) {
+            Some("sup") => return format!("SupTitle(\"{}\")", filename),
+            Some("idx") => return format!("VobSub(\"{}\")", filename),
+            _ => (),
+        }
+
+        let renderer = self.subtitle_renderer();
+        let mut args = vec![format!("\"{}\"", filename)];
+        if let Some(ref charset) = self.opts.sub_charset {
+            args.push(if renderer.numeric_charset() {
+                format!("charset={}", charset)
+            } else {
+                format!("charset=\"{}\"", charset)
+            });
+        }
+        if renderer == SubtitleRenderer::AssRender {
+            if let Some(ref fontdir) = self.opts.sub_fontdir {
+                args.push(format!("fontdir=\"{}\"", fontdir.to_str().unwrap()));
+            }
+            if let Some(matrix) = self.opts.sub_matrix {
+                args.push(format!(
+                    "colorspace=\"{}\"",
+                    match matrix {
+                        SubtitleMatrix::Bt601 => "BT.601",
+                        SubtitleMatrix::Bt709 => "BT.709",
+                        SubtitleMatrix::Bt2020 => "BT.2020",
+                    }
+                ));
+            }
+        }
+        let filter = match renderer {
+            SubtitleRenderer::AssRender => "assrender",
+            // xy-VSFilter ships as VSFilter.dll, and the plugin prefix keeps its
+            // TextSub apart from those of other renderers which are loaded too
+            SubtitleRenderer::XyVsFilter => "VSFilter_TextSub",
+            SubtitleRenderer::VsFilterMod => "TextSubMod",
+            _ => "TextSub",
+        };
+        format!("{}({})", filter, args.join(", "))
+    }
+
+    fn subtitle_renderer(&self) -> SubtitleRenderer {
+        self.opts.sub_renderer.unwrap_or(SubtitleRenderer::VsFilter)
+    }
+
+    fn build_crop_string(&self, crop: Crop) -> String {
+        format!(
+            "Crop({}, {}, -{}, -{})",
+            crop.left, crop.top, crop.right, crop.bottom
+        )
     }
 
     fn build_resize_string(&self, width: u32, height: u32) -> String {
-        format!("Spline64Resize({}, {})", width, height)
+        match self.opts.resize_kernel {
+            ResizeKernel::Bicubic { b, c } => {
+                format!("BicubicResize({}, {}, b={}, c={})", width, height, b, c)
+            }
+            ResizeKernel::Lanczos { taps } => {
+                format!("LanczosResize({}, {}, taps={})", width, height, taps)
+            }
+            ResizeKernel::Spline16 => format!("Spline16Resize({}, {})", width, height),
+            ResizeKernel::Spline36 => format!("Spline36Resize({}, {})", width, height),
+            ResizeKernel::Spline64 => format!("Spline64Resize({}, {})", width, height),
+            ResizeKernel::Point => format!("PointResize({}, {})", width, height),
+        }
+    }
+
+    fn build_fast_resize_string(&self, width: u32, height: u32) -> String {
+        format!("BilinearResize({}, {})", width, height)
+    }
+
+    fn build_frame_skip_string(&self, cycle: u32) -> String {
+        format!("SelectEvery({}, 0)", cycle)
     }
 
     fn build_trim_string(&self, breakpoint: BreakPoint) -> String {
         format!("Trim({},{})", breakpoint.start_frame, breakpoint.end_frame)
     }
 
-    fn write_segments(&self, segments: &[Vec<String>], script: &mut File) -> Result<(), String> {
+    fn build_zone_string(&self, filter: &str, ranges: &[(u64, u64)]) -> String {
+        format!(
+            "ReplaceFramesSimple({}, mappings=\"{}\")",
+            apply_filter("video", filter),
+            ranges
+                .iter()
+                .map(|(start, end)| format!("[{} {}]", start, end))
+                .collect::<Vec<String>>()
+                .join(" ")
+        )
+    }
+
+    fn build_ivtc_strings(&self, pass: IvtcPass, files: &IvtcFiles) -> Vec<String> {
+        let tfm_metrics = files.tfm_metrics.to_str().unwrap();
+        let tdecimate_metrics = files.tdecimate_metrics.to_str().unwrap();
+        match pass {
+            IvtcPass::First => vec![
+                format!("TFM(output=\"{}\")", tfm_metrics),
+                format!("TDecimate(mode=4, output=\"{}\")", tdecimate_metrics),
+            ],
+            IvtcPass::Second => vec![
+                format!("TFM(input=\"{}\")", tfm_metrics),
+                format!(
+                    "TDecimate(mode=5, hybrid=2, dupThresh=1.0, input=\"{}\", tfmIn=\"{}\", mkvOut=\"{}\")",
+                    tdecimate_metrics,
+                    tfm_metrics,
+                    files.timecodes.to_str().unwrap()
+                ),
+            ],
+            IvtcPass::Single => vec!["TFM()".to_owned(), "TDecimate()".to_owned()],
+        }
+    }
+
+    fn write_segments(
+        &self,
+        segments: &[ScriptSegment],
+        post_filters: &[String],
+        _fast: bool,
+        script: &mut File,
+    ) -> Result<(), String> {
         for (i, segment) in segments.iter().enumerate() {
             let video_label = format!("video{}", i + 1);
-            for (j, mut filter) in segment.clone().into_iter().enumerate() {
+            // Audio is dubbed into the video, so there is no separate audio to write
+            for (j, mut filter) in segment.video.clone().into_iter().enumerate() {
                 if j > 0 {
-                    filter = if filter.contains("()") {
-                        filter.replacen("()", &format!("({})", video_label), 1)
-                    } else {
-                        filter.replacen("(", &&format!("({}, ", video_label), 1)
-                    };
+                    filter = apply_filter(&video_label, &filter);
                 }
                 writeln!(script, "{} = {}", video_label, filter).map_err(|e| e.to_string())?;
             }
             writeln!(script).map_err(|e| e.to_string())?;
         }
-        writeln!(
-            script,
-            "{}",
-            (0..segments.len())
-                .map(|i| format!("video{}", i + 1))
-                .collect::<Vec<String>>()
-                .join(" + ")
-        )
-        .map_err(|e| e.to_string())?;
+        let joined = (0..segments.len())
+            .map(|i| format!("video{}", i + 1))
+            .collect::<Vec<String>>()
+            .join(" + ");
+        if post_filters.is_empty() {
+            writeln!(script, "{}", joined).map_err(|e| e.to_string())?;
+        } else {
+            writeln!(script, "video = {}", joined).map_err(|e| e.to_string())?;
+            for filter in post_filters {
+                writeln!(script, "video = {}", apply_filter("video", filter))
+                    .map_err(|e| e.to_string())?;
+            }
+            writeln!(script).map_err(|e| e.to_string())?;
+            writeln!(script, "video").map_err(|e| e.to_string())?;
+        }
 
         Ok(())
     }
@@ -112,7 +282,7 @@ impl AvisynthWriter {
     pub fn new(mut opts: AvsOptions, apply_default_filters: bool) -> Self {
         let default_filters: &[String] = &["RemoveGrain(1)".to_string()];
         if apply_default_filters {
-            opts.filters.extend_from_slice(default_filters);
+            opts.heavy_filters.extend_from_slice(default_filters);
         }
         AvisynthWriter { opts }
     }
@@ -121,6 +291,8 @@ impl AvisynthWriter {
         match determine_input_type(path) {
             Some(InputTypes::DgIndex) => "DGDecode_MPEG2Source",
             Some(InputTypes::DgAvc) => "AVCSource",
+            // ffms2 can't seek in transport streams reliably
+            Some(InputTypes::M2ts) | Some(InputTypes::Vob) => "LWLibAvVideoSource",
             Some(_) => "FFVideoSource",
             None => panic!("Invalid input type"),
         }
@@ -130,7 +302,7 @@ impl AvisynthWriter {
         if self.opts.downsample {
             "LWLibAvVideoSource"
         } else {
-            self.determine_video_source_filter(&current_filename)
+            self.determine_video_source_filter(current_filename)
         }
     }
 }
@@ -156,15 +328,7 @@ mod tests {
         let in_file = Path::new("files/example.mkv");
         let out_file = Path::new("files/avs_basic.avs");
         let expected = Path::new("files/avs_basic.avs.expected");
-        let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
-            to_cfr: false,
-            downsample: false,
-        };
+        let opts = AvsOptions::default();
         let mut writer = AvisynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
         assert_eq!(&read_file(out_file), &read_file(expected));
@@ -176,13 +340,8 @@ mod tests {
         let out_file = Path::new("files/avs_audio.avs");
         let expected = Path::new("files/avs_audio.avs.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
             audio: (true, None),
-            resize: None,
-            to_cfr: false,
-            downsample: false,
+            ..Default::default()
         };
         let mut writer = AvisynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -195,13 +354,8 @@ mod tests {
         let out_file = Path::new("files/avs_downsample.avs");
         let expected = Path::new("files/avs_downsample.avs.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
-            to_cfr: false,
             downsample: true,
+            ..Default::default()
         };
         let mut writer = AvisynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -214,13 +368,8 @@ mod tests {
         let out_file = Path::new("files/avs_cfr.avs");
         let expected = Path::new("files/avs_cfr.avs.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
             to_cfr: true,
-            downsample: false,
+            ..Default::default()
         };
         let mut writer = AvisynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -233,13 +382,8 @@ mod tests {
         let out_file = Path::new("files/avs_resize.avs");
         let expected = Path::new("files/avs_resize.avs.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: Some((640, 480)),
-            to_cfr: false,
-            downsample: false,
+            resize: Some((Some(640), Some(480))),
+            ..Default::default()
         };
         let mut writer = AvisynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -252,16 +396,152 @@ mod tests {
         let out_file = Path::new("files/avs_ass.avs");
         let expected = Path::new("files/avs_ass.avs.expected");
         let opts = AvsOptions {
-            filters: vec![],
             ass: true,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
-            to_cfr: false,
-            downsample: false,
+            ..Default::default()
         };
         let mut writer = AvisynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
         assert_eq!(&read_file(out_file), &read_file(expected));
     }
+
+    #[test]
+    fn create_script_avs_ivtc() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/avs_ivtc.avs");
+        let expected = Path::new("files/avs_ivtc.avs.expected");
+        let first_pass_file = Path::new("files/avs_ivtc.pass1.avs");
+        let first_pass_expected = Path::new("files/avs_ivtc.pass1.avs.expected");
+        let opts = AvsOptions {
+            ivtc: true,
+            ..Default::default()
+        };
+        let mut writer = AvisynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(out_file), &read_file(expected));
+        assert_eq!(&read_file(first_pass_file), &read_file(first_pass_expected));
+    }
+
+    #[test]
+    fn create_script_avs_fast_fp() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/avs_fast_fp.avs");
+        let fast_file = Path::new("files/avs_fast_fp.fp.avs");
+        let expected = Path::new("files/avs_fast_fp.fp.avs.expected");
+        let opts = AvsOptions {
+            ass: true,
+            resize: Some((Some(640), Some(480))),
+            fast_fp: true,
+            fast_fp_skip: Some(4),
+            ..Default::default()
+        };
+        let mut writer = AvisynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(fast_file), &read_file(expected));
+    }
+
+    #[test]
+    fn create_script_avs_crop() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/avs_crop.avs");
+        let expected = Path::new("files/avs_crop.avs.expected");
+        let opts = AvsOptions {
+            crop: Some(CropMode::Manual(Crop {
+                left: 0,
+                top: 140,
+                right: 0,
+                bottom: 140,
+            })),
+            resize: Some((Some(640), Some(480))),
+            ..Default::default()
+        };
+        let mut writer = AvisynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(out_file), &read_file(expected));
+    }
+
+    #[test]
+    fn create_script_avs_audio_delay() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/avs_audio_delay.avs");
+        let expected = Path::new("files/avs_audio_delay.avs.expected");
+        let opts = AvsOptions {
+            audio: (true, None),
+            audio_delay: Some(-42),
+            ..Default::default()
+        };
+        let mut writer = AvisynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(out_file), &read_file(expected));
+    }
+
+    #[test]
+    fn subtitle_filter_by_format() {
+        let writer = AvisynthWriter::new(AvsOptions::default(), false);
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "TextSub(\"ep01.ass\")"
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.sup")),
+            "SupTitle(\"ep01.sup\")"
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.idx")),
+            "VobSub(\"ep01.idx\")"
+        );
+    }
+
+    #[test]
+    fn subtitle_renderer_options() {
+        let writer = AvisynthWriter::new(
+            AvsOptions {
+                sub_renderer: Some(SubtitleRenderer::AssRender),
+                sub_matrix: Some(SubtitleMatrix::Bt709),
+                sub_fontdir: Some(PathBuf::from("fonts")),
+                sub_charset: Some("UTF-8".to_owned()),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "assrender(\"ep01.ass\", charset=\"UTF-8\", fontdir=\"fonts\", colorspace=\"BT.709\")"
+        );
+        let writer = AvisynthWriter::new(
+            AvsOptions {
+                sub_renderer: Some(SubtitleRenderer::VsFilterMod),
+                sub_charset: Some("128".to_owned()),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "TextSubMod(\"ep01.ass\", charset=128)"
+        );
+        let writer = AvisynthWriter::new(
+            AvsOptions {
+                sub_renderer: Some(SubtitleRenderer::XyVsFilter),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "VSFilter_TextSub(\"ep01.ass\")"
+        );
+    }
+
+    #[test]
+    fn zone_replaces_frames() {
+        let writer = AvisynthWriter::new(AvsOptions::default(), false);
+        assert_eq!(
+            writer.build_zone_string("f3kdb(y=64)", &[(0, 2157), (30000, 32157)]),
+            "ReplaceFramesSimple(f3kdb(video, y=64), mappings=\"[0 2157] [30000 32157]\")"
+        );
+        assert_eq!(
+            apply_filter("video", &writer.build_zone_string("Blur(1.0)", &[(10, 20)])),
+            "ReplaceFramesSimple(video, Blur(video, 1.0), mappings=\"[10 20]\")"
+        );
+    }
 }
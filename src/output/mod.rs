@@ -1,11 +1,52 @@
 use super::input::InputTypes;
+use crate::chapters::chapter_output_ranges;
+use crate::chapters::format_ogm_chapters;
+use crate::chapters::is_skipped;
+pub use crate::chapters::ChapterMatcher;
+use crate::fonts::fnv1a_64;
+use crate::fonts::hashed_file_name;
 use crate::input::determine_input_type;
+use crate::parsers::ass::AssScript;
+use crate::parsers::cropdetect::detect_crop;
+pub use crate::parsers::cropdetect::Crop;
+use crate::parsers::d2v::get_d2v_video_info;
+use crate::parsers::edl::parse_timecode;
+pub use crate::parsers::edl::EdlEntry;
+use crate::parsers::ffprobe::get_start_timecode;
+use crate::parsers::ffprobe::get_video_info;
+pub use crate::parsers::ffprobe::ColorInfo;
+use crate::parsers::ffprobe::VideoInfo;
+use crate::parsers::mkvinfo::end_last_chapter;
+use crate::parsers::mkvinfo::get_attachments;
+use crate::parsers::mkvinfo::get_chapters;
 use crate::parsers::mkvinfo::get_file_uuid;
-use crate::parsers::mkvinfo::get_fonts_list;
-use crate::parsers::mkvinfo::get_ordered_chapters_list;
+use crate::parsers::mkvinfo::merge_chapters;
+use crate::parsers::mkvinfo::Attachment;
+use crate::parsers::mkvinfo::AttachmentKind;
 use crate::parsers::mkvinfo::BreakPoint;
+pub use crate::parsers::mkvinfo::Chapter;
+use crate::parsers::mpls::clip_path;
+use crate::parsers::mpls::read_mpls;
+use crate::parsers::mpls::MPLS_CLOCK;
+use crate::parsers::sfnt::sniff_font_file;
+use crate::parsers::timecodes::read_timecodes;
+use crate::ranges::FrameTiming;
+pub use crate::ranges::Position;
+pub use crate::ranges::Range;
+use crate::selector::get_stream_infos;
+use crate::selector::resolve_stream;
+pub use crate::selector::StreamSelector;
+use crate::subtitles::extract_all_subtitles;
+use crate::subtitles::extract_subtitle_stream;
+use crate::subtitles::find_subtitle_file;
+use crate::subtitles::is_bitmap_subtitle;
+use crate::subtitles::merge_retimed_scripts;
+use crate::subtitles::subtitle_extension;
+use crate::subtitles::RetimedPart;
 use std::collections::HashMap;
-use std::fs::{copy, File};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
@@ -20,12 +61,106 @@ pub use vapoursynth::*;
 
 pub trait ScriptFormat {
     fn create_script(&mut self, in_file: &Path, out_file: &Path) -> Result<(), String> {
-        let breakpoints = get_ordered_chapters_list(in_file, self.get_opts().to_cfr)?;
+        let ScriptSegments {
+            preloads,
+            segments,
+            zone_filters,
+            post_filters: spliced_filters,
+        } = self.build_segments(in_file)?;
+
+        let ivtc_files = if self.get_opts().ivtc {
+            Some(IvtcFiles::new(out_file)?)
+        } else {
+            None
+        };
+        let ivtc_filters = |pass: IvtcPass| match ivtc_files {
+            Some(ref files) => self.build_ivtc_strings(pass, files),
+            None => Vec::new(),
+        };
+        let second_pass = if self.get_opts().ivtc_one_pass {
+            IvtcPass::Single
+        } else {
+            IvtcPass::Second
+        };
+        let first_pass_filters = ivtc_filters(IvtcPass::First);
+        let second_pass_filters = ivtc_filters(second_pass);
+        // The metrics of the first pass may not exist yet, so IVTC in one go
+        let fast_filters = ivtc_filters(IvtcPass::Single);
+
+        let post_filters = |fast: bool| {
+            let mut filters =
+                resolve_segments(std::slice::from_ref(&spliced_filters), &[], fast, false)
+                    .remove(0)
+                    .video;
+            filters.extend_from_slice(&zone_filters);
+            filters
+        };
+        self.write_script(
+            out_file,
+            &preloads,
+            &resolve_segments(&segments, &second_pass_filters, false, false),
+            &post_filters(false),
+            false,
+        )?;
+
+        if self.get_opts().ivtc && !self.get_opts().ivtc_one_pass {
+            // The first pass only collects metrics, so nothing after IVTC matters to it
+            let first_pass_file =
+                out_file.with_extension(format!("pass1.{}", self.get_script_extension()));
+            self.write_script(
+                &first_pass_file,
+                &preloads,
+                &resolve_segments(&segments, &first_pass_filters, false, true),
+                &[],
+                false,
+            )?;
+        }
+        if self.get_opts().fast_fp {
+            let fast_file = out_file.with_extension(format!("fp.{}", self.get_script_extension()));
+            let mut post_filters = post_filters(true);
+            if let Some(cycle) = self.get_opts().fast_fp_skip {
+                post_filters.push(self.build_frame_skip_string(cycle));
+            }
+            self.write_script(
+                &fast_file,
+                &preloads,
+                &resolve_segments(&segments, &fast_filters, true, false),
+                &post_filters,
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Builds the filter chain for each segment of the output, for the full
+    /// script and the fast one alike.
+    ///
+    /// The fast chain is meant for first-pass encodes and previews, so it skips
+    /// audio, subtitles and heavy filters, and uses a cheap resizer.
+    fn build_segments(&mut self, in_file: &Path) -> Result<ScriptSegments, String> {
+        let mut cached_info: HashMap<PathBuf, VideoInfo> = HashMap::new();
+        let breakpoints = get_breakpoints(in_file, self.get_opts(), &mut cached_info)?;
+        if self.get_opts().ivtc && (breakpoints.is_some() || !self.get_opts().zones.is_empty()) {
+            return Err(
+                "IVTC changes the frame numbers, so it cannot be combined with \
+                        ordered or skipped chapters, trims, EDLs or zones"
+                    .to_owned(),
+            );
+        }
         let mut iter = 0usize;
         let mut current_breakpoint = None;
-        let mut segments: Vec<Vec<String>> = Vec::new();
+        let mut segments: Vec<Vec<SegmentFilter>> = Vec::new();
         let mut cached_uuids: HashMap<Uuid, PathBuf> = HashMap::new();
         let mut preloads: HashMap<PathBuf, String> = HashMap::new();
+        let mut cached_crops: HashMap<PathBuf, Crop> = HashMap::new();
+        // Each segment's subtitles are in its own time, so they are retimed
+        // into one script for the whole output instead
+        let merge_subtitles = self.get_opts().ass && breakpoints.is_some();
+        let mut subtitle_parts: Vec<(PathBuf, BreakPoint, FrameTiming)> = Vec::new();
+        // Resizing has to wait for subtitles rendered after splicing, which only
+        // works if every segment is the same size until then
+        let mut deferred_resize: Option<((u32, u32), Option<Crop>)> = None;
+        let mut subtitles_extracted: HashSet<PathBuf> = HashSet::new();
 
         loop {
             if breakpoints.is_some() {
@@ -36,97 +171,249 @@ pub trait ScriptFormat {
                 }
             }
 
-            let mut current_filename = in_file.to_owned();
-            if let Some(ref current_breakpoint) = current_breakpoint {
-                if let Some(current_uuid) = current_breakpoint.foreign_uuid {
-                    if let Some(filename) = cached_uuids.get(&current_uuid).cloned() {
-                        current_filename = filename.to_owned();
-                    } else {
-                        for external in in_file.parent().unwrap().read_dir().unwrap() {
-                            let path = external.unwrap().path();
-                            if path.extension().unwrap() != "mkv" || path.as_path() == in_file {
-                                continue;
-                            }
-                            if let Ok(uuid) = get_file_uuid(&path) {
-                                cached_uuids.insert(uuid, path.to_owned());
-                                if uuid == current_uuid {
-                                    current_filename = path.to_owned();
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    if current_filename.as_path() == in_file {
-                        return Err(
-                            "Could not find file linked through ordered chapters.".to_owned()
-                        );
-                    }
-                }
-            }
-
-            let mut current_filters = Vec::new();
+            let current_filename = match current_breakpoint {
+                Some(BreakPoint {
+                    foreign_uuid: Some(uuid),
+                    ..
+                }) => find_linked_file(in_file, uuid, &mut cached_uuids)?,
+                Some(BreakPoint {
+                    foreign_file: Some(ref file),
+                    ..
+                }) => file.clone(),
+                _ => in_file.to_owned(),
+            };
+            let mut current_filters: Vec<SegmentFilter> = Vec::new();
             if self.get_opts().to_cfr && !preloads.contains_key(&current_filename) {
                 preloads.insert(
                     current_filename.clone(),
                     self.build_video_filter_string(&current_filename, true),
                 );
             }
-            current_filters.push(self.build_video_filter_string(&current_filename, false));
+            current_filters.push(SegmentFilter::Video(
+                self.build_video_filter_string(&current_filename, false),
+            ));
+            // Only probe when something depends on the colors. Not every source
+            // can be probed, so carry on without color tags in that case.
+            let opts = self.get_opts();
+            let (color, bit_depth) = if opts.color_props
+                || opts.downsample
+                || opts.ivtc
+                || opts.ass
+                || !opts.filters.is_empty()
+                || !opts.heavy_filters.is_empty()
+            {
+                probe_video_info(&current_filename, &mut cached_info)
+                    .map(|info| {
+                        (
+                            info.color.with_guessed_matrix(info.height),
+                            info.bit_depth(),
+                        )
+                    })
+                    .unwrap_or((ColorInfo::default(), 8))
+            } else {
+                (ColorInfo::default(), 8)
+            };
+            if self.get_opts().color_props {
+                if let Some(filter) = self.build_color_props_string(&color) {
+                    current_filters.push(SegmentFilter::Video(filter));
+                }
+            }
             if self.get_opts().to_cfr {
                 // This needs to happen before the `AudioDub`
                 // Also, `vfrtocfr` requires the full path to the timecodes file
-                current_filters
-                    .push(self.build_vfr_string(&current_filename.with_extension("timecodes.txt")));
-            }
-            let audio = self.get_opts().audio.clone();
-            match audio {
-                (false, None) => (),
-                (true, None) => {
-                    current_filters.push(self.build_audio_dub_string(
-                        &current_filename.canonicalize().map_err(|e| e.to_string())?,
-                    ));
-                }
-                (_, Some(ref x)) => {
-                    current_filters.push(
+                current_filters.push(SegmentFilter::Video(
+                    self.build_vfr_string(&current_filename.with_extension("timecodes.txt")),
+                ));
+            }
+            if self.get_opts().ivtc {
+                // Field matching needs the untouched telecined source
+                current_filters.push(SegmentFilter::Ivtc);
+            }
+            let crop = match self.get_opts().crop {
+                Some(CropMode::Manual(crop)) => Some(crop),
+                Some(CropMode::Auto) => Some(match cached_crops.get(&current_filename) {
+                    Some(crop) => *crop,
+                    None => {
+                        let info = probe_video_info(&current_filename, &mut cached_info)?;
+                        let crop = detect_crop(&current_filename, &info)?;
+                        cached_crops.insert(current_filename.clone(), crop);
+                        crop
+                    }
+                }),
+                None => None,
+            };
+            // Filters shouldn't run on the black bars, nor subtitles be cut into
+            if let Some(crop) = crop.filter(|crop| !crop.is_empty()) {
+                current_filters.push(SegmentFilter::Video(self.build_crop_string(crop)));
+            }
+            let audio_filename = match self.get_opts().audio {
+                (false, None) => None,
+                (true, None) => Some(current_filename.clone()),
+                (_, Some(ref x)) => Some(current_filename.with_extension(x)),
+            };
+            if let Some(audio_filename) = audio_filename {
+                let track = self.resolve_audio_track(&audio_filename)?;
+                let delay = self.get_audio_delay(&current_filename, &audio_filename, track);
+                let filter = match self.get_opts().audio_intermediate {
+                    Some(format) => {
+                        // The track and delay are already applied by ffmpeg
+                        let intermediate = extract_audio(&audio_filename, track, delay, format)?;
                         self.build_audio_dub_string(
-                            &current_filename
-                                .with_extension(x)
-                                .canonicalize()
-                                .map_err(|e| e.to_string())?,
-                        ),
-                    );
+                            &intermediate.canonicalize().map_err(|e| e.to_string())?,
+                            None,
+                            0,
+                        )?
+                    }
+                    None => self.build_audio_dub_string(
+                        &audio_filename.canonicalize().map_err(|e| e.to_string())?,
+                        track,
+                        delay,
+                    )?,
+                };
+                current_filters.push(filter);
+            }
+            if color.is_hdr() {
+                if self.get_opts().ivtc {
+                    warn_hdr_clipping(&current_filename, "TFM/TDecimate only support 8-bit video");
+                }
+                if self.get_opts().ass {
+                    warn_hdr_clipping(&current_filename, "subtitles are rendered in 8-bit");
                 }
             }
+            // Keep sources at their native bit depth unless asked otherwise
+            let mut working_depth = bit_depth;
             if self.get_opts().downsample {
-                if let Some(filter) = self.build_downsample_string() {
-                    current_filters.push(filter);
+                let format = self
+                    .get_opts()
+                    .output_format
+                    .unwrap_or_else(|| OutputFormat::for_bit_depth(bit_depth));
+                if color.is_hdr() && format.bit_depth() == 8 {
+                    warn_hdr_clipping(&current_filename, "downsampling to 8-bit");
                 }
+                if let Some(filter) = self.build_downsample_string(format, &color) {
+                    current_filters.push(SegmentFilter::Video(filter));
+                }
+                working_depth = format.bit_depth();
+            }
+            if color.is_hdr()
+                && working_depth > 8
+                && (!self.get_opts().filters.is_empty()
+                    || !self.get_opts().heavy_filters.is_empty())
+            {
+                warn_hdr_clipping(
+                    &current_filename,
+                    "the filters get high bit depth video, and any that only support 8-bit",
+                );
             }
             if !self.get_opts().filters.is_empty() {
-                current_filters.extend_from_slice(&self.get_opts().filters);
+                current_filters.extend(
+                    self.get_opts()
+                        .filters
+                        .iter()
+                        .cloned()
+                        .map(SegmentFilter::Video),
+                );
             }
-            if let Some(sub_track) = self.get_opts().ass_extract {
-                if current_filename.with_extension("ass").exists() {
-                    println!(
-                        "Cowardly refusing to overwrite existing subtitles: {}",
-                        current_filename.with_extension("ass").to_string_lossy()
-                    );
-                } else {
-                    extract_subtitles(current_filename.as_ref(), sub_track)?;
+            if !self.get_opts().heavy_filters.is_empty() {
+                current_filters.extend(
+                    self.get_opts()
+                        .heavy_filters
+                        .iter()
+                        .cloned()
+                        .map(SegmentFilter::Full),
+                );
+            }
+            if !subtitles_extracted.insert(current_filename.clone()) {
+                // Files linked more than once only need extracting once
+            } else {
+                match self.get_opts().sub_extract {
+                    Some(SubtitleExtraction::Single(ref sub_track)) => {
+                        let stream = resolve_stream(&current_filename, "subtitle", sub_track)?;
+                        let sub_file = if is_bitmap_subtitle(&stream.codec_name) {
+                            current_filename
+                                .with_extension(subtitle_extension(&stream.codec_name).0)
+                        } else {
+                            current_filename.with_extension("ass")
+                        };
+                        if sub_file.exists() {
+                            println!(
+                                "Cowardly refusing to overwrite existing subtitles: {}",
+                                sub_file.to_string_lossy()
+                            );
+                        } else {
+                            extract_subtitle_stream(&current_filename, &stream, &sub_file)?;
+                        }
+                    }
+                    Some(SubtitleExtraction::All(ref selector)) => {
+                        extract_all_subtitles(&current_filename, selector.as_ref())?;
+                    }
+                    None => (),
                 }
             }
             if self.get_opts().ass {
-                let ass_file = current_filename
-                    .with_extension("ass")
-                    .canonicalize()
-                    .unwrap();
-                current_filters.push(self.build_subtitle_string(&ass_file));
+                let mut ass_file =
+                    find_subtitle_file(&current_filename, self.get_opts().ass_track.as_ref())?
+                        .canonicalize()
+                        .map_err(|e| e.to_string())?;
+                let is_text = matches!(
+                    ass_file.extension().and_then(|ext| ext.to_str()),
+                    Some("ass") | Some("ssa")
+                );
+                match self.get_opts().sub_matrix {
+                    Some(matrix) if is_text && !self.subtitle_renderer().supports_matrix() => {
+                        ass_file = tag_subtitle_matrix(&ass_file, matrix)?;
+                    }
+                    _ => (),
+                }
+                if merge_subtitles && is_text {
+                    let timing =
+                        source_timing(&current_filename, self.get_opts().to_cfr, &mut cached_info)?;
+                    subtitle_parts.push((ass_file, current_breakpoint.clone().unwrap(), timing));
+                } else {
+                    current_filters
+                        .push(SegmentFilter::Full(self.build_subtitle_string(&ass_file)));
+                }
             }
-            if let Some((width, height)) = self.get_opts().resize {
-                current_filters.push(self.build_resize_string(width, height));
+            if let Some(resize) = self.get_opts().resize {
+                let (width, height) = match resize {
+                    (Some(width), Some(height)) => (width, height),
+                    (width, height) => {
+                        let info = probe_video_info(&current_filename, &mut cached_info)?;
+                        resolve_resize_dimensions(
+                            width,
+                            height,
+                            &info,
+                            crop.unwrap_or_default(),
+                            self.get_opts().resize_mod.unwrap_or(2),
+                        )?
+                    }
+                };
+                if merge_subtitles {
+                    match deferred_resize {
+                        Some(resize) if resize != ((width, height), crop) => {
+                            return Err(
+                                "Merged subtitles are rendered after splicing, so every segment \
+                                 needs the same crop and size to resize them afterwards"
+                                    .to_owned(),
+                            );
+                        }
+                        _ => deferred_resize = Some(((width, height), crop)),
+                    }
+                } else {
+                    current_filters
+                        .push(SegmentFilter::Full(self.build_resize_string(width, height)));
+                    current_filters.push(SegmentFilter::Fast(
+                        self.build_fast_resize_string(width, height),
+                    ));
+                }
             }
             if breakpoints.is_some() {
-                current_filters.push(self.build_trim_string(current_breakpoint.unwrap()));
+                let breakpoint = current_breakpoint.clone().unwrap();
+                if let Some(filter) = self.build_audio_trim_string(&breakpoint, segments.len() + 1)
+                {
+                    current_filters.push(SegmentFilter::Audio(filter));
+                }
+                current_filters.push(SegmentFilter::Video(self.build_trim_string(breakpoint)));
                 segments.push(current_filters);
             } else {
                 segments.push(current_filters);
@@ -134,6 +421,73 @@ pub trait ScriptFormat {
             }
         }
 
+        let mut zone_filters = Vec::new();
+        if !self.get_opts().zones.is_empty() {
+            let fps = output_frame_rate(
+                in_file,
+                self.get_opts().to_cfr,
+                &mut cached_info,
+                "place zones",
+            )?;
+            let total_frames = match breakpoints {
+                Some(ref breakpoints) => breakpoints
+                    .iter()
+                    .map(|breakpoint| breakpoint.end_frame + 1 - breakpoint.start_frame)
+                    .sum(),
+                None => source_frame_count(in_file, fps, &mut cached_info).unwrap_or(0),
+            };
+            let (chapters, ordered) = read_chapters(
+                in_file,
+                &self.get_opts().chapters,
+                self.get_opts().to_cfr,
+                &mut cached_info,
+            )?;
+            let chapter_ranges =
+                chapter_output_ranges(&chapters, ordered, &self.get_opts().skip_chapters);
+            for zone in &self.get_opts().zones {
+                let ranges = resolve_zone(zone, &chapters, &chapter_ranges, fps, total_frames);
+                if ranges.is_empty() {
+                    eprintln!(
+                        "Warning: Zone for {} matches no frames of {}",
+                        zone.filter,
+                        in_file.to_str().unwrap()
+                    );
+                    continue;
+                }
+                zone_filters.push(self.build_zone_string(&zone.filter, &ranges));
+            }
+        }
+
+        let mut post_filters = Vec::new();
+        if !subtitle_parts.is_empty() {
+            let merged_file = merge_segment_subtitles(in_file, &subtitle_parts)?;
+            post_filters.push(SegmentFilter::Full(
+                self.build_subtitle_string(&merged_file),
+            ));
+        }
+        if let Some(((width, height), _)) = deferred_resize {
+            post_filters.push(SegmentFilter::Full(self.build_resize_string(width, height)));
+            post_filters.push(SegmentFilter::Fast(
+                self.build_fast_resize_string(width, height),
+            ));
+        }
+
+        Ok(ScriptSegments {
+            preloads: preloads.into_values().collect(),
+            segments,
+            zone_filters,
+            post_filters,
+        })
+    }
+
+    fn write_script(
+        &self,
+        out_file: &Path,
+        preloads: &[String],
+        segments: &[ScriptSegment],
+        post_filters: &[String],
+        fast: bool,
+    ) -> Result<(), String> {
         let mut script = match File::create(out_file) {
             Ok(x) => x,
             Err(x) => return Err(format!("{}", x)),
@@ -142,25 +496,12 @@ pub trait ScriptFormat {
         self.write_script_header(&mut script)?;
 
         if !preloads.is_empty() {
-            writeln!(
-                &mut script,
-                "{}",
-                preloads
-                    .values()
-                    .cloned()
-                    .collect::<Vec<String>>()
-                    .join("\n")
-            )
-            .map_err(|e| e.to_string())?;
+            writeln!(&mut script, "{}", preloads.join("\n")).map_err(|e| e.to_string())?;
             writeln!(&mut script).map_err(|e| e.to_string())?;
         }
 
-        self.write_segments(&segments, &mut script)?;
-        script.sync_all().map_err(|e| e.to_string())?;
-        if self.get_opts().fast_fp {
-            copy(out_file, &out_file.with_extension("fp.vpy")).map_err(|e| e.to_string())?;
-        }
-        Ok(())
+        self.write_segments(segments, post_filters, fast, &mut script)?;
+        script.sync_all().map_err(|e| e.to_string())
     }
 
     fn get_opts(&self) -> &AvsOptions;
@@ -169,77 +510,1267 @@ pub trait ScriptFormat {
 
     fn build_video_filter_string(&self, current_filename: &Path, is_preload: bool) -> String;
 
-    fn build_downsample_string(&self) -> Option<String>;
+    fn build_color_props_string(&self, color: &ColorInfo) -> Option<String>;
+
+    fn build_downsample_string(&self, format: OutputFormat, color: &ColorInfo) -> Option<String>;
 
     fn build_vfr_string(&self, timecodes_path: &Path) -> String;
 
-    fn build_audio_dub_string(&mut self, audio_filename: &Path) -> String;
+    /// Dubs in audio from the given file, `track` being the stream index if one was selected,
+    /// and `delay` how many milliseconds later than the video the audio should start
+    fn build_audio_dub_string(
+        &mut self,
+        audio_filename: &Path,
+        track: Option<usize>,
+        delay: i64,
+    ) -> Result<SegmentFilter, String>;
+
+    /// Cuts the audio of the given segment along with its video, if the
+    /// writer keeps audio apart from the video
+    fn build_audio_trim_string(&self, _breakpoint: &BreakPoint, _segment: usize) -> Option<String> {
+        None
+    }
+
+    /// Uses the override if there is one, and otherwise the difference between
+    /// the start times of the audio and video streams
+    fn get_audio_delay(
+        &self,
+        video_filename: &Path,
+        audio_filename: &Path,
+        track: Option<usize>,
+    ) -> i64 {
+        if let Some(delay) = self.get_opts().audio_delay {
+            return delay;
+        }
+        // Sources which can't be probed (e.g. d2v) get no compensation
+        let first_start_time = |path: &Path, codec_type: &str, index: Option<usize>| {
+            get_stream_infos(path, codec_type)
+                .ok()?
+                .into_iter()
+                .find(|stream| index.map(|index| index == stream.index).unwrap_or(true))?
+                .start_time
+        };
+        match (
+            first_start_time(video_filename, "video", None),
+            first_start_time(audio_filename, "audio", track),
+        ) {
+            (Some(video_start), Some(audio_start)) => {
+                ((audio_start - video_start) * 1000f64).round() as i64
+            }
+            _ => 0,
+        }
+    }
+
+    fn resolve_audio_track(&self, audio_filename: &Path) -> Result<Option<usize>, String> {
+        match self.get_opts().audio_track {
+            Some(ref selector) => Ok(Some(
+                resolve_stream(audio_filename, "audio", selector)?.index,
+            )),
+            None => Ok(None),
+        }
+    }
 
     fn build_subtitle_string(&self, subtitle_filename: &Path) -> String;
 
+    /// The renderer of text subtitles, which is the writer's default unless one was chosen
+    fn subtitle_renderer(&self) -> SubtitleRenderer;
+
+    fn build_crop_string(&self, crop: Crop) -> String;
+
     fn build_resize_string(&self, width: u32, height: u32) -> String;
 
+    fn build_fast_resize_string(&self, width: u32, height: u32) -> String;
+
+    fn build_frame_skip_string(&self, cycle: u32) -> String;
+
     fn build_trim_string(&self, breakpoint: BreakPoint) -> String;
 
+    /// Applies a filter to the given inclusive frame ranges of the output only
+    fn build_zone_string(&self, filter: &str, ranges: &[(u64, u64)]) -> String;
+
+    fn build_ivtc_strings(&self, pass: IvtcPass, files: &IvtcFiles) -> Vec<String>;
+
     fn write_script_header(&self, _script: &mut File) -> Result<(), String> {
         // Default to writing no header
         Ok(())
     }
 
-    fn write_segments(&self, segments: &[Vec<String>], script: &mut File) -> Result<(), String>;
+    fn write_segments(
+        &self,
+        segments: &[ScriptSegment],
+        post_filters: &[String],
+        fast: bool,
+        script: &mut File,
+    ) -> Result<(), String>;
 }
 
-#[derive(Debug, Clone)]
+/// The filter chains which make up a script
+#[derive(Debug, Clone, Default)]
+pub struct ScriptSegments {
+    /// Filters run once per source before anything else, such as timecode preloads
+    pub preloads: Vec<String>,
+    /// The filters of each segment, spliced together in order
+    pub segments: Vec<Vec<SegmentFilter>>,
+    /// Filters applied to parts of the spliced output, after the post filters
+    pub zone_filters: Vec<String>,
+    /// The end of every segment's chain, applied after splicing them instead,
+    /// such as subtitles merged from all segments
+    pub post_filters: Vec<SegmentFilter>,
+}
+
+/// A filter of one segment, some of which depend on the script being written
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentFilter {
+    Video(String),
+    /// Left out of the fast script
+    Full(String),
+    /// Only in the fast script
+    Fast(String),
+    /// Where the filters of the script's IVTC pass go, right after the source
+    Ivtc,
+    /// Audio kept apart from the video, left out of the fast script
+    Audio(String),
+}
+
+/// The filters of one segment of a script
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScriptSegment {
+    pub video: Vec<String>,
+    /// The audio node and its filters, for writers which don't dub it into the video
+    pub audio: Vec<String>,
+}
+
+/// Turns segments into the filters of a script with the given IVTC filters,
+/// cutting them off after IVTC if that is all the script needs
+fn resolve_segments(
+    segments: &[Vec<SegmentFilter>],
+    ivtc_filters: &[String],
+    fast: bool,
+    until_ivtc: bool,
+) -> Vec<ScriptSegment> {
+    segments
+        .iter()
+        .map(|segment| {
+            let mut resolved = ScriptSegment::default();
+            for filter in segment {
+                match filter {
+                    SegmentFilter::Video(filter) => resolved.video.push(filter.clone()),
+                    SegmentFilter::Full(filter) if !fast => resolved.video.push(filter.clone()),
+                    SegmentFilter::Fast(filter) if fast => resolved.video.push(filter.clone()),
+                    SegmentFilter::Audio(filter) if !fast => resolved.audio.push(filter.clone()),
+                    SegmentFilter::Full(_) | SegmentFilter::Fast(_) | SegmentFilter::Audio(_) => {}
+                    SegmentFilter::Ivtc => {
+                        resolved.video.extend_from_slice(ivtc_filters);
+                        if until_ivtc {
+                            break;
+                        }
+                    }
+                }
+            }
+            resolved
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct AvsOptions {
     pub filters: Vec<String>,
+    /// Filters which are left out of the fast first-pass script
+    pub heavy_filters: Vec<String>,
     pub ass: bool,
-    pub ass_extract: Option<u8>,
+    /// Picks the subtitles from the manifest written by `SubtitleExtraction::All`
+    pub ass_track: Option<StreamSelector>,
+    pub sub_extract: Option<SubtitleExtraction>,
+    /// Which plugin renders text subtitles, defaults to VSFilter or sub
+    pub sub_renderer: Option<SubtitleRenderer>,
+    /// Overrides the `YCbCr Matrix` of the subtitles
+    pub sub_matrix: Option<SubtitleMatrix>,
+    pub sub_fontdir: Option<PathBuf>,
+    pub sub_charset: Option<String>,
     pub audio: (bool, Option<String>),
-    pub resize: Option<(u32, u32)>,
+    /// Which audio track to use, defaults to the source filter's choice
+    pub audio_track: Option<StreamSelector>,
+    /// Audio delay in milliseconds, overriding the one computed from the streams
+    pub audio_delay: Option<i64>,
+    /// Decode the audio to a lossless file with ffmpeg first, for sources that can't be read directly
+    pub audio_intermediate: Option<AudioIntermediate>,
+    pub crop: Option<CropMode>,
+    /// Target `(width, height)`, a missing dimension is computed from the aspect ratio
+    pub resize: Option<(Option<u32>, Option<u32>)>,
+    /// Mod to round computed resize dimensions to, defaults to 2
+    pub resize_mod: Option<u32>,
+    /// Kernel used for resizing and chroma resampling
+    pub resize_kernel: ResizeKernel,
     pub to_cfr: bool,
+    /// Tag frames with the probed color properties, VapourSynth only
+    pub color_props: bool,
     pub downsample: bool,
+    /// Format to downsample to, defaults to YUV420 at the source's bit depth
+    pub output_format: Option<OutputFormat>,
+    pub dither: DitherType,
     pub fast_fp: bool,
+    /// Only keep every nth frame in the fast first-pass script
+    pub fast_fp_skip: Option<u32>,
+    pub ivtc: bool,
+    /// IVTC to CFR in a single pass instead, with VFM/VDecimate in VapourSynth
+    pub ivtc_one_pass: bool,
+    /// Filters applied to parts of the output only
+    pub zones: Vec<Zone>,
+    /// Chapters left out of the output, which splices the rest together even
+    /// without ordered chapters
+    pub skip_chapters: Vec<ChapterMatcher>,
+    /// Chapters of the input which it does not carry itself, like those of a
+    /// DVD title's program chain
+    pub chapters: Vec<Chapter>,
+    /// Parts of the input file to keep, spliced in order instead of following
+    /// any ordered chapters
+    pub trim: Vec<Range>,
+    /// Like `trim`, but the cuts may come from other files too
+    pub edl: Vec<EdlEntry>,
+    /// The timecode EDL sources start at, overriding their own timecode tags
+    pub edl_start: Option<Position>,
+}
+
+/// A filter applied to part of the output, e.g. to deband only the opening
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub range: ZoneRange,
+    pub filter: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum ZoneRange {
+    /// Chapters as they fall on the output
+    Chapter(ChapterMatcher),
+    /// Ranges of the output, in frames or time
+    Ranges(Vec<Range>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropMode {
+    /// Detect the crop for each file with ffmpeg's `cropdetect`
+    Auto,
+    Manual(Crop),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ResizeKernel {
+    Bicubic {
+        b: f64,
+        c: f64,
+    },
+    Lanczos {
+        taps: u32,
+    },
+    Spline16,
+    Spline36,
+    #[default]
+    Spline64,
+    Point,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Yuv420P8,
+    Yuv420P10,
+    Yuv420P12,
+    Yuv444P16,
+    Rgb24,
+}
+
+impl OutputFormat {
+    /// The YUV420 format that holds a source of the given bit depth
+    pub fn for_bit_depth(bit_depth: u8) -> OutputFormat {
+        match bit_depth {
+            0..=8 => OutputFormat::Yuv420P8,
+            9..=10 => OutputFormat::Yuv420P10,
+            11..=12 => OutputFormat::Yuv420P12,
+            _ => OutputFormat::Yuv444P16,
+        }
+    }
+
+    pub fn bit_depth(self) -> u8 {
+        match self {
+            OutputFormat::Yuv420P8 | OutputFormat::Rgb24 => 8,
+            OutputFormat::Yuv420P10 => 10,
+            OutputFormat::Yuv420P12 => 12,
+            OutputFormat::Yuv444P16 => 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DitherType {
+    #[default]
+    None,
+    Ordered,
+    ErrorDiffusion,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubtitleExtraction {
+    /// The first matching track, converted to `<name>.ass`
+    Single(StreamSelector),
+    /// Every matching track (or all of them) in its own format, with a manifest
+    All(Option<StreamSelector>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubtitleRenderer {
+    /// AviSynth only
+    VsFilter,
+    /// AviSynth only
+    XyVsFilter,
+    /// AviSynth only
+    VsFilterMod,
+    AssRender,
+    /// VapourSynth only
+    Sub,
+    /// VapourSynth only
+    XyVsf,
+}
+
+impl SubtitleRenderer {
+    pub fn supports_fontdir(self) -> bool {
+        matches!(self, SubtitleRenderer::AssRender | SubtitleRenderer::Sub)
+    }
+
+    /// Whether the matrix is passed to the renderer, rather than set in the subtitles
+    pub fn supports_matrix(self) -> bool {
+        matches!(self, SubtitleRenderer::AssRender | SubtitleRenderer::Sub)
+    }
+
+    /// The VSFilter family only takes a numeric GDI charset
+    pub fn numeric_charset(self) -> bool {
+        !matches!(self, SubtitleRenderer::AssRender | SubtitleRenderer::Sub)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubtitleMatrix {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioIntermediate {
+    Flac,
+    W64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IvtcPass {
+    /// Collects field matching and decimation metrics
+    First,
+    /// Reads the metrics back and decimates to VFR
+    Second,
+    /// Matches fields and decimates to CFR without any metrics files
+    Single,
+}
+
+/// Side files shared by the two IVTC passes, named after the output script
+#[derive(Debug, Clone)]
+pub struct IvtcFiles {
+    pub tfm_metrics: PathBuf,
+    pub tdecimate_metrics: PathBuf,
+    pub timecodes: PathBuf,
+}
+
+impl IvtcFiles {
+    pub fn new(out_file: &Path) -> Result<Self, String> {
+        Ok(IvtcFiles {
+            tfm_metrics: absolute_path(&out_file.with_extension("tfm.txt"))?,
+            tdecimate_metrics: absolute_path(&out_file.with_extension("tdec.txt"))?,
+            timecodes: absolute_path(&out_file.with_extension("ivtc.timecodes.txt"))?,
+        })
+    }
+}
+
+fn warn_hdr_clipping(path: &Path, reason: &str) {
+    eprintln!(
+        "Warning: {} is HDR, but {}, which will clip it",
+        path.to_str().unwrap(),
+        reason
+    );
+}
+
+fn probe_video_info(
+    path: &Path,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Result<VideoInfo, String> {
+    if let Some(info) = cache.get(path) {
+        return Ok(info.clone());
+    }
+    let info = match determine_input_type(path) {
+        Some(InputTypes::DgIndex) => get_d2v_video_info(path)?,
+        // A playlist has no video of its own, so go by its first clip
+        Some(InputTypes::Mpls) => match read_mpls(path)?.items.first() {
+            Some(item) => probe_video_info(&clip_path(path, &item.clip_name), cache)?,
+            None => return Err(format!("{} plays nothing", path.to_str().unwrap())),
+        },
+        _ => get_video_info(path)?,
+    };
+    cache.insert(path.to_owned(), info.clone());
+    Ok(info)
+}
+
+/// The frame rate of the spliced output, which is what frame numbers of the
+/// output refer to
+fn output_frame_rate(
+    in_file: &Path,
+    to_cfr: bool,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+    purpose: &str,
+) -> Result<f64, String> {
+    if to_cfr {
+        return Ok(120_000f64 / 1_001f64);
+    }
+    probe_video_info(in_file, cache)?.frame_rate.ok_or_else(|| {
+        format!(
+            "Could not read the frame rate of {} to {}",
+            in_file.to_str().unwrap(),
+            purpose
+        )
+    })
+}
+
+/// Finds the frames of the output which a zone covers
+fn resolve_zone(
+    zone: &Zone,
+    chapters: &[Chapter],
+    chapter_ranges: &[Option<(u64, u64)>],
+    fps: f64,
+    total_frames: u64,
+) -> Vec<(u64, u64)> {
+    match zone.range {
+        ZoneRange::Chapter(ref matcher) => {
+            let mut ranges: Vec<(u64, u64)> = chapters
+                .iter()
+                .zip(chapter_ranges)
+                .enumerate()
+                .filter(|(i, (chapter, _))| matcher.matches(*i, chapter))
+                .filter_map(|(_, (_, range))| *range)
+                .collect();
+            ranges.sort_unstable();
+            ranges
+        }
+        ZoneRange::Ranges(ref ranges) => ranges
+            .iter()
+            .filter_map(|range| range.to_frames(&FrameTiming::Constant(fps), total_frames))
+            .collect(),
+    }
+}
+
+/// Estimates the number of frames in a file from its duration
+fn source_frame_count(
+    in_file: &Path,
+    fps: f64,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Option<u64> {
+    let duration = probe_video_info(in_file, cache).ok()?.duration?;
+    Some((duration * fps).round() as u64)
+}
+
+/// Splits the play items of a Blu-ray playlist at its entry marks, so that
+/// every piece is a chapter of its clip, much like ordered chapters
+fn read_playlist_chapters(
+    in_file: &Path,
+    to_cfr: bool,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Result<Vec<Chapter>, String> {
+    let playlist = read_mpls(in_file)?;
+    let mut chapters = Vec::new();
+    for (i, item) in playlist.items.iter().enumerate() {
+        let clip = absolute_path(&clip_path(in_file, &item.clip_name))?;
+        let fps = output_frame_rate(&clip, to_cfr, cache, "place the clips of the playlist")?;
+        // Playlist times are on the clip's own timeline, which rarely starts at 0
+        let clip_start = get_stream_infos(&clip, "video")
+            .ok()
+            .and_then(|streams| streams.first()?.start_time)
+            .unwrap_or(0f64);
+        let to_frame = |ticks: u32| {
+            ((f64::from(ticks) / MPLS_CLOCK - clip_start).max(0f64) * fps).round() as u64
+        };
+        let mut bounds: Vec<u32> = playlist
+            .marks
+            .iter()
+            .filter(|mark| {
+                mark.is_entry
+                    && mark.play_item == i
+                    && mark.time > item.in_time
+                    && mark.time < item.out_time
+            })
+            .map(|mark| mark.time)
+            .collect();
+        bounds.push(item.in_time);
+        bounds.push(item.out_time);
+        bounds.sort_unstable();
+        bounds.dedup();
+        for piece in bounds.windows(2) {
+            let start_frame = to_frame(piece[0]);
+            let next_frame = to_frame(piece[1]);
+            if next_frame > start_frame {
+                chapters.push(Chapter {
+                    name: None,
+                    start_frame,
+                    end_frame: Some(next_frame - 1),
+                    foreign_uuid: None,
+                    foreign_file: Some(clip.clone()),
+                });
+            }
+        }
+    }
+    if chapters.is_empty() {
+        return Err(format!("{} plays nothing", in_file.to_str().unwrap()));
+    }
+    Ok(chapters)
+}
+
+/// Reads the chapters of a file, filling in the end of a last chapter which
+/// runs to the end of the file where the duration is known. The play items
+/// of a playlist are read as ordered chapters. `known` chapters are used
+/// instead of the file's own where given.
+fn read_chapters(
+    in_file: &Path,
+    known: &[Chapter],
+    to_cfr: bool,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Result<(Vec<Chapter>, bool), String> {
+    if !known.is_empty() {
+        return Ok((known.to_vec(), false));
+    }
+    if let Some(InputTypes::Mpls) = determine_input_type(in_file) {
+        return Ok((read_playlist_chapters(in_file, to_cfr, cache)?, true));
+    }
+    let (mut chapters, ordered) = get_chapters(in_file, to_cfr)?;
+    if chapters
+        .last()
+        .is_some_and(|last| last.end_frame.is_none() && last.foreign_uuid.is_none())
+    {
+        let total_frames = output_frame_rate(in_file, to_cfr, cache, "end the last chapter")
+            .ok()
+            .and_then(|fps| source_frame_count(in_file, fps, cache));
+        end_last_chapter(&mut chapters, total_frames);
+    }
+    Ok((chapters, ordered))
+}
+
+/// Works out which parts of which files make up the output. Without trims or
+/// skipped chapters, that is only ever set by ordered chapters.
+fn get_breakpoints(
+    in_file: &Path,
+    opts: &AvsOptions,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Result<Option<Vec<BreakPoint>>, String> {
+    if !opts.edl.is_empty() {
+        return cut_breakpoints(in_file, &opts.edl, opts, cache).map(Some);
+    }
+    let is_playlist = matches!(determine_input_type(in_file), Some(InputTypes::Mpls));
+    if !opts.trim.is_empty() {
+        if is_playlist {
+            return Err("Playlists cannot be trimmed, skip their chapters instead".to_owned());
+        }
+        let cuts: Vec<EdlEntry> = opts
+            .trim
+            .iter()
+            .map(|&range| EdlEntry {
+                file: in_file.to_owned(),
+                range,
+            })
+            .collect();
+        return cut_breakpoints(in_file, &cuts, opts, cache).map(Some);
+    }
+    let skip = &opts.skip_chapters;
+    let (chapters, ordered) = read_chapters(in_file, &opts.chapters, opts.to_cfr, cache)?;
+    let kept: Vec<Chapter> = chapters
+        .iter()
+        .enumerate()
+        .filter(|(i, chapter)| !is_skipped(skip, *i, chapter))
+        .map(|(_, chapter)| chapter.clone())
+        .collect();
+    if kept.len() == chapters.len() {
+        return Ok(if ordered {
+            Some(merge_chapters(&chapters)?)
+        } else {
+            None
+        });
+    }
+    if kept.is_empty() {
+        return Err(format!(
+            "Every chapter of {} is skipped",
+            in_file.to_str().unwrap()
+        ));
+    }
+    if kept.iter().any(|chapter| chapter.end_frame.is_none()) {
+        return Err(format!(
+            "Could not find where the last chapter of {} ends",
+            in_file.to_str().unwrap()
+        ));
+    }
+    Ok(Some(merge_chapters(&kept)?))
+}
+
+/// How frames of the source map to time, from its timecodes file if there is
+/// one. After conversion to CFR, that is always the fixed 120fps.
+fn source_timing(
+    in_file: &Path,
+    to_cfr: bool,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Result<FrameTiming, String> {
+    let timecodes_path = in_file.with_extension("timecodes.txt");
+    if !to_cfr && timecodes_path.exists() {
+        // An empty file is left behind by scripts which have not been run yet
+        match read_timecodes(&timecodes_path) {
+            Ok(starts) if !starts.is_empty() => return Ok(FrameTiming::Timecodes(starts)),
+            Ok(_) => (),
+            Err(e) => eprintln!("Warning: Ignoring timecodes, {}", e),
+        }
+    }
+    output_frame_rate(in_file, to_cfr, cache, "convert trim timestamps").map(FrameTiming::Constant)
+}
+
+/// How the frames of a cut's source map to time, and to SMPTE timecodes
+struct SourceTiming {
+    timing: FrameTiming,
+    total_frames: Option<u64>,
+    fps: f64,
+    start_timecode: Option<Position>,
+}
+
+/// Turns cuts of the input file or others into breakpoints, in the order given
+fn cut_breakpoints(
+    in_file: &Path,
+    cuts: &[EdlEntry],
+    opts: &AvsOptions,
+    cache: &mut HashMap<PathBuf, VideoInfo>,
+) -> Result<Vec<BreakPoint>, String> {
+    let in_file_path = absolute_path(in_file)?;
+    let mut timings: HashMap<PathBuf, SourceTiming> = HashMap::new();
+    let mut breakpoints = Vec::with_capacity(cuts.len());
+    for cut in cuts {
+        let file = absolute_path(&cut.file)?;
+        if !timings.contains_key(&file) {
+            let timing = source_timing(&file, opts.to_cfr, cache)?;
+            let total_frames = match timing {
+                FrameTiming::Timecodes(ref starts) => Some(starts.len() as u64),
+                FrameTiming::Constant(fps) => source_frame_count(&file, fps, cache),
+            };
+            // SMPTE timecodes count frames of the source as it is, before any conversion to CFR
+            let fps = probe_video_info(&file, cache)
+                .ok()
+                .and_then(|info| info.frame_rate)
+                .unwrap_or_else(|| timing.fps());
+            let start_timecode = match opts.edl_start {
+                Some(start) => Some(start),
+                None => match get_start_timecode(&file) {
+                    Some(timecode) => Some(parse_timecode(&timecode, false)?),
+                    None => None,
+                },
+            };
+            timings.insert(
+                file.clone(),
+                SourceTiming {
+                    timing,
+                    total_frames,
+                    fps,
+                    start_timecode,
+                },
+            );
+        }
+        let SourceTiming {
+            ref timing,
+            total_frames,
+            fps,
+            start_timecode,
+        } = timings[&file];
+        let total_frames = match (cut.range.end, total_frames) {
+            (_, Some(total_frames)) => total_frames,
+            (Some(_), None) => u64::MAX,
+            (None, None) => {
+                return Err(format!(
+                    "Could not find where {} ends to cut to its end",
+                    file.to_str().unwrap()
+                ))
+            }
+        };
+        match cut
+            .range
+            .timecodes_to_time(fps, start_timecode)
+            .to_frames(timing, total_frames)
+            .map(|(start, end)| (start, end.min(total_frames - 1)))
+            .filter(|(start, end)| start <= end)
+        {
+            Some((start_frame, end_frame)) => breakpoints.push(BreakPoint {
+                start_frame,
+                end_frame,
+                foreign_uuid: None,
+                foreign_file: Some(file.clone()).filter(|file| *file != in_file_path),
+            }),
+            None => eprintln!(
+                "Warning: Skipping a cut with no frames of {} in it",
+                file.to_str().unwrap()
+            ),
+        }
+    }
+    if breakpoints.is_empty() {
+        return Err(format!(
+            "Nothing of {} is left after cutting",
+            in_file.to_str().unwrap()
+        ));
+    }
+    Ok(breakpoints)
+}
+
+/// Writes the chapters as they fall on the output, leaving out skipped ones,
+/// to `<name>.chapters.txt` in the OGM format. `known` chapters are written
+/// instead of the file's own where given.
+pub fn export_chapters(
+    in_file: &Path,
+    known: &[Chapter],
+    to_cfr: bool,
+    skip: &[ChapterMatcher],
+) -> Result<PathBuf, String> {
+    let mut cache = HashMap::new();
+    let (chapters, ordered) = read_chapters(in_file, known, to_cfr, &mut cache)?;
+    if chapters.is_empty() {
+        return Err(format!("{} has no chapters", in_file.to_str().unwrap()));
+    }
+    let fps = output_frame_rate(in_file, to_cfr, &mut cache, "export chapters")?;
+    let ranges = chapter_output_ranges(&chapters, ordered, skip);
+    let path = in_file.with_extension("chapters.txt");
+    fs::write(&path, format_ogm_chapters(&chapters, &ranges, fps))
+        .map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    Ok(path)
+}
+
+/// Fills in whichever dimension is missing so that the display aspect ratio is kept
+fn resolve_resize_dimensions(
+    width: Option<u32>,
+    height: Option<u32>,
+    info: &VideoInfo,
+    crop: Crop,
+    modulus: u32,
+) -> Result<(u32, u32), String> {
+    let cropped_width = info.width.saturating_sub(crop.left + crop.right);
+    let cropped_height = info.height.saturating_sub(crop.top + crop.bottom);
+    if cropped_width == 0 || cropped_height == 0 {
+        return Err("Cannot resize a video which is cropped away entirely".to_owned());
+    }
+    if modulus == 0 {
+        return Err("Resize mod must be at least 1".to_owned());
+    }
+    let dar = info.display_aspect_ratio(cropped_width, cropped_height);
+    let round_to_mod = |value: f64| ((value / f64::from(modulus)).round() as u32).max(1) * modulus;
+
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        (Some(width), None) => Ok((width, round_to_mod(f64::from(width) / dar))),
+        (None, Some(height)) => Ok((round_to_mod(f64::from(height) * dar), height)),
+        (None, None) => Err("At most one resize dimension can be computed".to_owned()),
+    }
+}
+
+/// Like `canonicalize`, but also works for files which have not been written yet
+fn absolute_path(path: &Path) -> Result<PathBuf, String> {
+    if path.exists() {
+        return path.canonicalize().map_err(|e| e.to_string());
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    Ok(parent
+        .canonicalize()
+        .map_err(|e| e.to_string())?
+        .join(path.file_name().ok_or("Invalid file name")?))
+}
+
+/// Inserts `label` as the first argument of `filter`
+fn apply_filter(label: &str, filter: &str) -> String {
+    if filter.contains("()") {
+        filter.replacen("()", &format!("({})", label), 1)
+    } else {
+        filter.replacen("(", &format!("({}, ", label), 1)
+    }
+}
+
+/// Decodes an audio track to a lossless intermediate next to the source,
+/// unless an up to date one already exists
+pub fn extract_audio(
+    in_file: &Path,
+    track: Option<usize>,
+    delay: i64,
+    format: AudioIntermediate,
+) -> Result<PathBuf, String> {
+    let mut extension = match track {
+        Some(track) => format!("track{}", track),
+        None => "audio".to_owned(),
+    };
+    if delay != 0 {
+        extension.push_str(&format!(".delay{}ms", delay));
+    }
+    extension.push_str(match format {
+        AudioIntermediate::Flac => ".flac",
+        AudioIntermediate::W64 => ".w64",
+    });
+    let out_file = in_file.with_extension(extension);
+
+    let modified = |path: &Path| path.metadata().and_then(|metadata| metadata.modified());
+    if let (Ok(source), Ok(intermediate)) = (modified(in_file), modified(&out_file)) {
+        if intermediate >= source {
+            return Ok(out_file);
+        }
+    }
+
+    let map = match track {
+        Some(track) => format!("0:{}", track),
+        None => "0:a:0".to_owned(),
+    };
+    let mut args: Vec<String> = vec![
+        "-y".to_owned(),
+        "-i".to_owned(),
+        in_file.to_str().unwrap().to_owned(),
+        "-map".to_owned(),
+        map,
+    ];
+    if delay > 0 {
+        args.push("-af".to_owned());
+        args.push(format!("adelay={}:all=1", delay));
+    } else if delay < 0 {
+        args.push("-af".to_owned());
+        args.push(format!(
+            "atrim=start={:.3},asetpts=PTS-STARTPTS",
+            -delay as f64 / 1000f64
+        ));
+    }
+    match format {
+        AudioIntermediate::Flac => args.extend(
+            [
+                "-c:a",
+                "flac",
+                "-sample_fmt",
+                "s32",
+                "-bits_per_raw_sample",
+                "24",
+            ]
+            .iter()
+            .map(|arg| arg.to_string()),
+        ),
+        AudioIntermediate::W64 => args.extend(
+            ["-c:a", "pcm_f32le", "-f", "w64"]
+                .iter()
+                .map(|arg| arg.to_string()),
+        ),
+    }
+    args.push(out_file.to_str().unwrap().to_owned());
+
+    match Command::new("ffmpeg").args(&args).status() {
+        Ok(status) if status.success() => Ok(out_file),
+        Ok(_) => Err(format!(
+            "ffmpeg failed to decode audio from {}",
+            in_file.to_str().unwrap()
+        )),
+        Err(x) => Err(format!("{}", x)),
+    }
+}
+
+/// Writes the subtitles of every segment, retimed onto the spliced output,
+/// to `<name>.merged.ass`
+fn merge_segment_subtitles(
+    in_file: &Path,
+    parts: &[(PathBuf, BreakPoint, FrameTiming)],
+) -> Result<PathBuf, String> {
+    let mut files: Vec<&PathBuf> = Vec::new();
+    let mut scripts = Vec::new();
+    let mut retimed = Vec::with_capacity(parts.len());
+    let mut offset = 0;
+    for (file, breakpoint, timing) in parts {
+        let frame_to_centiseconds = |frame: u64| (timing.time_at(frame) * 100f64).round() as u64;
+        let script = match files.iter().position(|existing| *existing == file) {
+            Some(script) => script,
+            None => {
+                files.push(file);
+                scripts.push(AssScript::open(file)?);
+                scripts.len() - 1
+            }
+        };
+        let start = frame_to_centiseconds(breakpoint.start_frame);
+        let end = frame_to_centiseconds(breakpoint.end_frame + 1);
+        retimed.push(RetimedPart {
+            script,
+            start,
+            end,
+            offset,
+        });
+        offset += end - start;
+    }
+
+    let merged_file = in_file.with_extension("merged.ass");
+    let mut output = File::create(&merged_file).map_err(|e| e.to_string())?;
+    write!(output, "{}", merge_retimed_scripts(&scripts, &retimed)?).map_err(|e| e.to_string())?;
+    absolute_path(&merged_file)
+}
+
+/// The VSFilter family only learns the matrix from the `YCbCr Matrix` header,
+/// so that is set on a copy of the subtitles, e.g. `ep01.tv709.ass`
+fn tag_subtitle_matrix(ass_file: &Path, matrix: SubtitleMatrix) -> Result<PathBuf, String> {
+    let header = match matrix {
+        SubtitleMatrix::Bt601 => "TV.601",
+        SubtitleMatrix::Bt709 => "TV.709",
+        SubtitleMatrix::Bt2020 => {
+            eprintln!(
+                "Warning: VSFilter has no BT.2020 matrix, keeping the YCbCr Matrix of {}",
+                ass_file.to_str().unwrap()
+            );
+            return Ok(ass_file.to_owned());
+        }
+    };
+    let mut script = AssScript::open(ass_file)?;
+    if script.info("YCbCr Matrix") == Some(header) {
+        return Ok(ass_file.to_owned());
+    }
+    script.set_info("YCbCr Matrix", header);
+    let tagged_file = ass_file.with_extension(format!(
+        "{}.{}",
+        header.replace('.', "").to_lowercase(),
+        ass_file.extension().unwrap().to_str().unwrap()
+    ));
+    fs::write(&tagged_file, script.to_string())
+        .map_err(|e| format!("{}: {}", tagged_file.to_str().unwrap(), e))?;
+    Ok(tagged_file)
 }
 
-pub fn extract_subtitles(in_file: &Path, sub_track: u8) -> Result<(), String> {
-    match Command::new("ffmpeg")
-        .args(&[
-            "-i",
+/// Finds the file with the given Segment UID among the Matroska files next to
+/// the input, caching the UIDs of every file it has to read along the way
+pub fn find_linked_file(
+    in_file: &Path,
+    uuid: Uuid,
+    cached_uuids: &mut HashMap<Uuid, PathBuf>,
+) -> Result<PathBuf, String> {
+    if let Some(filename) = cached_uuids.get(&uuid) {
+        return Ok(filename.clone());
+    }
+    let dir = match in_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    for external in dir.read_dir().map_err(|e| e.to_string())? {
+        let path = external.map_err(|e| e.to_string())?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("mkv")
+            || path.file_name() == in_file.file_name()
+            || cached_uuids.values().any(|cached| *cached == path)
+        {
+            continue;
+        }
+        if let Ok(file_uuid) = get_file_uuid(&path) {
+            cached_uuids.insert(file_uuid, path.to_owned());
+            if file_uuid == uuid {
+                return Ok(path);
+            }
+        }
+    }
+    Err("Could not find file linked through ordered chapters.".to_owned())
+}
+
+/// Lists the input and every distinct file linked from its ordered chapters
+pub fn get_segment_files(in_file: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![in_file.to_owned()];
+    let mut cached_uuids = HashMap::new();
+    let (chapters, ordered) = get_chapters(in_file, false)?;
+    if !ordered {
+        return Ok(files);
+    }
+    for chapter in chapters {
+        if let Some(uuid) = chapter.foreign_uuid {
+            let file = find_linked_file(in_file, uuid, &mut cached_uuids)?;
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn extract_attachment(in_file: &Path, id: usize, out_file: &Path) -> Result<(), String> {
+    match Command::new("mkvextract")
+        .args([
+            "attachments",
             in_file.to_str().unwrap(),
-            "-map",
-            &format!("0:s:{}", sub_track),
-            "-map_chapters",
-            "-1",
-            in_file.with_extension("ass").to_str().unwrap(),
+            &format!("{}:{}", id, out_file.to_str().unwrap()),
         ])
         .status()
     {
-        Ok(_) => Ok(()),
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => {
+            fs::remove_file(out_file).ok();
+            Err(format!(
+                "mkvextract could not extract attachment {} ({})",
+                id, status
+            ))
+        }
         Err(x) => Err(format!("{}", x)),
     }
 }
 
-pub fn extract_fonts(in_file: &Path) -> Result<(), String> {
-    let fonts = match get_fonts_list(in_file) {
-        Ok(x) => x,
-        Err(x) => return Err(x.to_owned()),
+/// The attachment's name without directories, warning about names which have none
+fn attachment_base_name(in_file: &Path, attachment: &Attachment) -> Option<String> {
+    let name = attachment.base_name().map(|name| name.to_owned());
+    if name.is_none() {
+        eprintln!(
+            "Warning: Skipping attachment {} of {}, its file name {:?} is not usable",
+            attachment.id,
+            in_file.to_str().unwrap(),
+            attachment.file_name
+        );
+    }
+    name
+}
+
+/// Extracts the font attachments next to the video, returning their paths.
+///
+/// Attachments without a font MIME type or extension are only kept if their
+/// contents turn out to be a font, and fonts with odd names get the right
+/// extension so that renderers pick them up. A different font already
+/// extracted under the same name, e.g. from another segment, is kept as well.
+/// Fonts of the same name and size as the attachment count as extracted.
+pub fn extract_fonts(in_file: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut font_paths = Vec::new();
+    for attachment in get_attachments(in_file)? {
+        let kind = attachment.kind();
+        if kind == AttachmentKind::Other {
+            continue;
+        }
+        let name = match attachment_base_name(in_file, &attachment) {
+            Some(name) => name,
+            None => continue,
+        };
+        let mut font_path = in_file.with_file_name(&name);
+        let has_font_extension = matches!(
+            font_path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .as_deref(),
+            Some("ttf") | Some("otf") | Some("ttc") | Some("otc")
+        );
+        let mut candidates = vec![font_path.clone()];
+        if !has_font_extension {
+            candidates.extend(
+                ["ttf", "otf", "ttc"]
+                    .iter()
+                    .map(|ext| PathBuf::from(format!("{}.{}", font_path.to_str().unwrap(), ext))),
+            );
+        }
+        let extracted = candidates.into_iter().find(|path| {
+            fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.len() == attachment.size)
+        });
+        if let Some(extracted) = extracted {
+            font_paths.push(extracted);
+            continue;
+        }
+        let part_path = in_file.with_file_name(format!("{}.part", name));
+        extract_attachment(in_file, attachment.id, &part_path)?;
+        match sniff_font_file(&part_path) {
+            Ok(Some(extension)) if !has_font_extension => {
+                font_path = PathBuf::from(format!("{}.{}", font_path.to_str().unwrap(), extension));
+            }
+            Ok(None) if kind == AttachmentKind::Unknown => {
+                fs::remove_file(&part_path).map_err(|e| e.to_string())?;
+                continue;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                fs::remove_file(&part_path).ok();
+                return Err(e);
+            }
+        }
+        font_paths.push(place_extracted_font(&part_path, &font_path)?);
+    }
+
+    Ok(font_paths)
+}
+
+/// Moves a freshly extracted font to its name, unless that file has the same
+/// contents already. If it holds a different font, the name gets the hash.
+fn place_extracted_font(part_path: &Path, font_path: &Path) -> Result<PathBuf, String> {
+    let contents = fs::read(part_path).map_err(|e| e.to_string())?;
+    let target = match fs::read(font_path) {
+        Ok(existing) if existing != contents => {
+            font_path.with_file_name(hashed_file_name(font_path, fnv1a_64(&contents)))
+        }
+        _ => font_path.to_owned(),
     };
-    for (id, filename) in &fonts {
-        let font_path = in_file.with_file_name(filename);
-        if !font_path.exists() {
-            match Command::new("mkvextract")
-                .args(&[
-                    "attachments",
-                    in_file.to_str().unwrap(),
-                    &format!("{}:{}", id, font_path.to_str().unwrap()),
-                ])
-                .status()
-            {
-                Ok(_) => (),
-                Err(x) => return Err(format!("{}", x)),
-            };
+    if target.exists() {
+        fs::remove_file(part_path).map_err(|e| e.to_string())?;
+    } else {
+        fs::rename(part_path, &target).map_err(|e| e.to_string())?;
+    }
+    Ok(target)
+}
+
+/// Extracts the fonts of the input and of every file linked from its ordered
+/// chapters, leaving out fonts whose contents were already extracted
+pub fn extract_segment_fonts(in_file: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut hashes = HashSet::new();
+    let mut font_paths = Vec::new();
+    for file in get_segment_files(in_file)? {
+        for font in extract_fonts(&file)? {
+            let contents = fs::read(&font).map_err(|e| e.to_string())?;
+            if hashes.insert(fnv1a_64(&contents)) {
+                font_paths.push(font);
+            }
+        }
+    }
+    Ok(font_paths)
+}
+
+/// Extracts every attachment which is not recognizably a font, such as cover
+/// images, to `<name>.attachments/`
+pub fn extract_other_attachments(in_file: &Path) -> Result<Vec<PathBuf>, String> {
+    let dir = in_file.with_extension("attachments");
+    let mut paths = Vec::new();
+    for attachment in get_attachments(in_file)?
+        .into_iter()
+        .filter(|attachment| attachment.kind() != AttachmentKind::Font)
+    {
+        let name = match attachment_base_name(in_file, &attachment) {
+            Some(name) => name,
+            None => continue,
+        };
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let path = dir.join(name);
+        if !path.exists() {
+            extract_attachment(in_file, attachment.id, &path)?;
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::ffprobe::HdrMetadata;
+
+    fn anamorphic_dvd() -> VideoInfo {
+        VideoInfo {
+            width: 720,
+            height: 480,
+            pix_fmt: "yuv420p".to_owned(),
+            sar: (32, 27),
+            duration: None,
+            frame_rate: None,
+            color: ColorInfo::default(),
+            hdr_metadata: HdrMetadata::default(),
         }
     }
 
-    Ok(())
+    #[test]
+    fn fonts_with_clashing_names_are_kept() {
+        let dir = std::env::temp_dir().join(format!("avsser-extract-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let font = dir.join("font.ttf");
+        let part = dir.join("font.ttf.part");
+        fs::write(&part, b"font one").unwrap();
+        assert_eq!(place_extracted_font(&part, &font), Ok(font.clone()));
+        fs::write(&part, b"font one").unwrap();
+        assert_eq!(place_extracted_font(&part, &font), Ok(font.clone()));
+        fs::write(&part, b"font two").unwrap();
+        let hashed = dir.join(format!("font-{:016x}.ttf", fnv1a_64(b"font two")));
+        assert_eq!(place_extracted_font(&part, &font), Ok(hashed.clone()));
+        assert_eq!(fs::read(&font).unwrap(), b"font one");
+        assert_eq!(fs::read(&hashed).unwrap(), b"font two");
+        assert!(!part.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn downsample_keeps_source_bit_depth() {
+        let mut info = anamorphic_dvd();
+        assert_eq!(
+            OutputFormat::for_bit_depth(info.bit_depth()),
+            OutputFormat::Yuv420P8
+        );
+        info.pix_fmt = "yuv420p10le".to_owned();
+        assert_eq!(
+            OutputFormat::for_bit_depth(info.bit_depth()),
+            OutputFormat::Yuv420P10
+        );
+        info.pix_fmt = "yuv420p12le".to_owned();
+        assert_eq!(
+            OutputFormat::for_bit_depth(info.bit_depth()),
+            OutputFormat::Yuv420P12
+        );
+    }
+
+    #[test]
+    fn segments_resolve_per_script() {
+        let segments = vec![vec![
+            SegmentFilter::Video("FFVideoSource(\"ep01.mkv\")".to_owned()),
+            SegmentFilter::Ivtc,
+            SegmentFilter::Audio("core.bas.Source('ep01.mkv')".to_owned()),
+            SegmentFilter::Full("RemoveGrain(1)".to_owned()),
+            SegmentFilter::Full("Spline64Resize(1280, 720)".to_owned()),
+            SegmentFilter::Fast("BilinearResize(1280, 720)".to_owned()),
+        ]];
+        let ivtc = vec!["TFM()".to_owned(), "TDecimate()".to_owned()];
+        assert_eq!(
+            resolve_segments(&segments, &ivtc, false, false),
+            vec![ScriptSegment {
+                video: vec![
+                    "FFVideoSource(\"ep01.mkv\")".to_owned(),
+                    "TFM()".to_owned(),
+                    "TDecimate()".to_owned(),
+                    "RemoveGrain(1)".to_owned(),
+                    "Spline64Resize(1280, 720)".to_owned(),
+                ],
+                audio: vec!["core.bas.Source('ep01.mkv')".to_owned()],
+            }]
+        );
+        assert_eq!(
+            resolve_segments(&segments, &[], true, false),
+            vec![ScriptSegment {
+                video: vec![
+                    "FFVideoSource(\"ep01.mkv\")".to_owned(),
+                    "BilinearResize(1280, 720)".to_owned(),
+                ],
+                audio: vec![],
+            }]
+        );
+        let first_pass = resolve_segments(&segments, &ivtc, false, true);
+        assert_eq!(
+            (first_pass[0].video.len(), first_pass[0].audio.len()),
+            (3, 0)
+        );
+    }
+
+    #[test]
+    fn resize_computes_width_from_sar() {
+        assert_eq!(
+            resolve_resize_dimensions(None, Some(480), &anamorphic_dvd(), Crop::default(), 2),
+            Ok((854, 480))
+        );
+    }
+
+    #[test]
+    fn resize_computes_height_after_crop() {
+        let crop = Crop {
+            left: 8,
+            top: 0,
+            right: 8,
+            bottom: 0,
+        };
+        assert_eq!(
+            resolve_resize_dimensions(Some(1280), None, &anamorphic_dvd(), crop, 16),
+            Ok((1280, 736))
+        );
+    }
+
+    #[test]
+    fn resize_requires_one_dimension() {
+        assert!(
+            resolve_resize_dimensions(None, None, &anamorphic_dvd(), Crop::default(), 2).is_err()
+        );
+    }
 }
@@ -4,12 +4,11 @@ use std::path::Path;
 
 pub struct VapoursynthWriter {
     opts: AvsOptions,
-    audio_filename: Option<PathBuf>,
 }
 
 impl ScriptFormat for VapoursynthWriter {
     fn build_video_filter_string(&self, current_filename: &Path, is_preload: bool) -> String {
-        let video_filter = self.get_video_filter_full_name(&current_filename);
+        let video_filter = self.get_video_filter_full_name(current_filename);
         let timecodes_path = current_filename.with_extension("timecodes.txt");
         if self.opts.to_cfr && !timecodes_path.exists() {
             File::create(&timecodes_path).ok();
@@ -23,12 +22,9 @@ impl ScriptFormat for VapoursynthWriter {
         }
 
         format!(
-            "{}({}{})",
+            "{}(source='{}'{})",
             video_filter,
-            format!(
-                "source='{}'",
-                escape_python_string(current_filename.canonicalize().unwrap().to_str().unwrap())
-            ),
+            escape_python_string(current_filename.canonicalize().unwrap().to_str().unwrap()),
             filter_opts
         )
     }
@@ -40,8 +36,58 @@ impl ScriptFormat for VapoursynthWriter {
         )
     }
 
-    fn build_downsample_string(&self) -> Option<String> {
-        Some("core.resize.Spline36(format = vs.YUV420P8)".to_string())
+    fn build_color_props_string(&self, color: &ColorInfo) -> Option<String> {
+        let mut props = Vec::new();
+        if let Some(matrix) = color.matrix_code() {
+            props.push(format!("_Matrix={}", matrix));
+        }
+        if let Some(range) = color.range.as_deref() {
+            props.push(format!("_ColorRange={}", if range == "pc" { 0 } else { 1 }));
+        }
+        if let Some(primaries) = color.primaries_code() {
+            props.push(format!("_Primaries={}", primaries));
+        }
+        if let Some(transfer) = color.transfer_code() {
+            props.push(format!("_Transfer={}", transfer));
+        }
+        if props.is_empty() {
+            return None;
+        }
+        Some(format!("core.std.SetFrameProps({})", props.join(", ")))
+    }
+
+    fn build_downsample_string(&self, format: OutputFormat, color: &ColorInfo) -> Option<String> {
+        let mut filter_opts = format!(
+            "format=vs.{}",
+            match format {
+                OutputFormat::Yuv420P8 => "YUV420P8",
+                OutputFormat::Yuv420P10 => "YUV420P10",
+                OutputFormat::Yuv420P12 => "YUV420P12",
+                OutputFormat::Yuv444P16 => "YUV444P16",
+                OutputFormat::Rgb24 => "RGB24",
+            }
+        );
+        if format == OutputFormat::Rgb24 {
+            let matrix = match color.matrix.as_deref() {
+                Some("bt709") => Some("709"),
+                Some("bt470bg") => Some("470bg"),
+                Some("smpte170m") => Some("170m"),
+                Some("smpte240m") => Some("240m"),
+                Some("bt2020nc") => Some("2020ncl"),
+                Some("bt2020c") => Some("2020cl"),
+                Some("fcc") => Some("fcc"),
+                _ => None,
+            };
+            if let Some(matrix) = matrix {
+                filter_opts.push_str(&format!(", matrix_in_s=\"{}\"", matrix));
+            }
+        }
+        match self.opts.dither {
+            DitherType::None => (),
+            DitherType::Ordered => filter_opts.push_str(", dither_type=\"ordered\""),
+            DitherType::ErrorDiffusion => filter_opts.push_str(", dither_type=\"error_diffusion\""),
+        }
+        Some(self.build_resizer_call(&filter_opts))
     }
 
     #[inline(always)]
@@ -54,22 +100,108 @@ impl ScriptFormat for VapoursynthWriter {
         "vpy"
     }
 
-    fn build_audio_dub_string(&mut self, audio_filename: &Path) -> String {
-        format!(
-            "core.damb.Read(\'{}\')",
-            escape_python_string(audio_filename.to_str().unwrap())
-        )
+    fn build_audio_dub_string(
+        &mut self,
+        audio_filename: &Path,
+        track: Option<usize>,
+        delay: i64,
+    ) -> Result<SegmentFilter, String> {
+        let source = format!(
+            "core.bas.Source(\'{}\'{})",
+            escape_python_string(audio_filename.to_str().unwrap()),
+            track
+                .map(|track| format!(", track={}", track))
+                .unwrap_or_default()
+        );
+        Ok(SegmentFilter::Audio(if delay != 0 {
+            format!("delay_audio({}, {:.3})", source, delay as f64 / 1000f64)
+        } else {
+            source
+        }))
+    }
+
+    fn build_audio_trim_string(&self, breakpoint: &BreakPoint, segment: usize) -> Option<String> {
+        Some(format!(
+            "trim_audio(video{}, {}, {})",
+            segment, breakpoint.start_frame, breakpoint.end_frame
+        ))
     }
 
     fn build_subtitle_string(&self, subtitle_filename: &Path) -> String {
+        let filename = escape_python_string(subtitle_filename.to_str().unwrap());
+        if let Some("sup") | Some("idx") =
+            subtitle_filename.extension().and_then(|ext| ext.to_str())
+        {
+            return format!("core.sub.ImageFile(\'{}\')", filename);
+        }
+
+        let renderer = self.subtitle_renderer();
+        let mut args = vec![format!("\'{}\'", filename)];
+        if let Some(ref charset) = self.opts.sub_charset {
+            args.push(if renderer.numeric_charset() {
+                format!("charset={}", charset)
+            } else {
+                format!("charset=\'{}\'", escape_python_string(charset))
+            });
+        }
+        if renderer.supports_fontdir() {
+            if let Some(ref fontdir) = self.opts.sub_fontdir {
+                args.push(format!(
+                    "fontdir=\'{}\'",
+                    escape_python_string(fontdir.to_str().unwrap())
+                ));
+            }
+        }
+        if let Some(matrix) = self.opts.sub_matrix {
+            match renderer {
+                SubtitleRenderer::AssRender => args.push(format!(
+                    "colorspace=\'{}\'",
+                    match matrix {
+                        SubtitleMatrix::Bt601 => "BT.601",
+                        SubtitleMatrix::Bt709 => "BT.709",
+                        SubtitleMatrix::Bt2020 => "BT.2020",
+                    }
+                )),
+                SubtitleRenderer::Sub => args.push(format!(
+                    "matrix_s=\'{}\'",
+                    match matrix {
+                        SubtitleMatrix::Bt601 => "170m",
+                        SubtitleMatrix::Bt709 => "709",
+                        SubtitleMatrix::Bt2020 => "2020ncl",
+                    }
+                )),
+                _ => (),
+            }
+        }
+        let filter = match renderer {
+            SubtitleRenderer::AssRender => "assrender.TextSub",
+            SubtitleRenderer::XyVsf => "xyvsf.TextSub",
+            _ => "sub.TextFile",
+        };
+        format!("core.{}({})", filter, args.join(", "))
+    }
+
+    fn subtitle_renderer(&self) -> SubtitleRenderer {
+        self.opts.sub_renderer.unwrap_or(SubtitleRenderer::Sub)
+    }
+
+    fn build_crop_string(&self, crop: Crop) -> String {
         format!(
-            "core.sub.TextFile(\'{}\')",
-            escape_python_string(subtitle_filename.to_str().unwrap())
+            "core.std.Crop(left={}, right={}, top={}, bottom={})",
+            crop.left, crop.right, crop.top, crop.bottom
         )
     }
 
     fn build_resize_string(&self, width: u32, height: u32) -> String {
-        format!("core.resize.Spline36({}, {})", width, height)
+        self.build_resizer_call(&format!("{}, {}", width, height))
+    }
+
+    fn build_fast_resize_string(&self, width: u32, height: u32) -> String {
+        format!("core.resize.Bilinear({}, {})", width, height)
+    }
+
+    fn build_frame_skip_string(&self, cycle: u32) -> String {
+        format!("core.std.SelectEvery(cycle={}, offsets=0)", cycle)
     }
 
     fn build_trim_string(&self, breakpoint: BreakPoint) -> String {
@@ -79,25 +211,77 @@ impl ScriptFormat for VapoursynthWriter {
         )
     }
 
+    fn build_zone_string(&self, filter: &str, ranges: &[(u64, u64)]) -> String {
+        format!(
+            "rfs({}, [{}])",
+            apply_filter("video", filter),
+            ranges
+                .iter()
+                .map(|(start, end)| format!("({}, {})", start, end))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+
     fn write_script_header(&self, script: &mut File) -> Result<(), String> {
         writeln!(script, "import vapoursynth as vs").map_err(|e| e.to_string())?;
         writeln!(script, "core = vs.get_core()").map_err(|e| e.to_string())?;
         writeln!(script).map_err(|e| e.to_string())?;
+        if !self.opts.zones.is_empty() {
+            writeln!(script, "{}", RFS_HELPER).map_err(|e| e.to_string())?;
+        }
+        if self.opts.audio != (false, None) {
+            writeln!(script, "{}", DELAY_AUDIO_HELPER).map_err(|e| e.to_string())?;
+            writeln!(script, "{}", TRIM_AUDIO_HELPER).map_err(|e| e.to_string())?;
+        }
         Ok(())
     }
 
-    fn write_segments(&self, segments: &[Vec<String>], script: &mut File) -> Result<(), String> {
+    fn build_ivtc_strings(&self, pass: IvtcPass, files: &IvtcFiles) -> Vec<String> {
+        let tfm_metrics = escape_python_string(files.tfm_metrics.to_str().unwrap());
+        let tdecimate_metrics = escape_python_string(files.tdecimate_metrics.to_str().unwrap());
+        match pass {
+            IvtcPass::First => vec![
+                format!("core.tivtc.TFM(output='{}')", tfm_metrics),
+                format!(
+                    "core.tivtc.TDecimate(mode=4, output='{}')",
+                    tdecimate_metrics
+                ),
+            ],
+            IvtcPass::Second => vec![
+                format!("core.tivtc.TFM(input='{}')", tfm_metrics),
+                format!(
+                    "core.tivtc.TDecimate(mode=5, hybrid=2, dupThresh=1.0, input='{}', tfmIn='{}', mkvOut='{}')",
+                    tdecimate_metrics,
+                    tfm_metrics,
+                    escape_python_string(files.timecodes.to_str().unwrap())
+                ),
+            ],
+            IvtcPass::Single => vec![
+                "core.vivtc.VFM(order=1)".to_owned(),
+                "core.vivtc.VDecimate()".to_owned(),
+            ],
+        }
+    }
+
+    fn write_segments(
+        &self,
+        segments: &[ScriptSegment],
+        post_filters: &[String],
+        _fast: bool,
+        script: &mut File,
+    ) -> Result<(), String> {
         for (i, segment) in segments.iter().enumerate() {
-            let video_label = format!("video{}", i + 1);
-            for (j, mut filter) in segment.clone().into_iter().enumerate() {
-                if j > 0 {
-                    filter = if filter.contains("()") {
-                        filter.replacen("()", &format!("({})", video_label), 1)
-                    } else {
-                        filter.replacen("(", &&format!("({}, ", video_label), 1)
-                    };
+            for (label, filters) in [
+                (format!("video{}", i + 1), &segment.video),
+                (format!("audio{}", i + 1), &segment.audio),
+            ] {
+                for (j, mut filter) in filters.clone().into_iter().enumerate() {
+                    if j > 0 {
+                        filter = apply_filter(&label, &filter);
+                    }
+                    writeln!(script, "{} = {}", label, filter).map_err(|e| e.to_string())?;
                 }
-                writeln!(script, "{} = {}", video_label, filter).map_err(|e| e.to_string())?;
             }
             writeln!(script).map_err(|e| e.to_string())?;
         }
@@ -110,53 +294,102 @@ impl ScriptFormat for VapoursynthWriter {
                 .join(" + ")
         )
         .map_err(|e| e.to_string())?;
-        writeln!(script).map_err(|e| e.to_string())?;
-        if let Some(ref audio_filename) = self.audio_filename {
+        for filter in post_filters {
+            writeln!(script, "video = {}", apply_filter("video", filter))
+                .map_err(|e| e.to_string())?;
+        }
+        // The fast script never has audio
+        let has_audio =
+            !segments.is_empty() && segments.iter().all(|segment| !segment.audio.is_empty());
+        if has_audio {
             writeln!(
                 script,
-                "core.damb.Write(video, \'{}\').set_output()",
-                escape_python_string(audio_filename.with_extension("flac").to_str().unwrap())
+                "audio = {}",
+                (0..segments.len())
+                    .map(|i| format!("audio{}", i + 1))
+                    .collect::<Vec<String>>()
+                    .join(" + ")
             )
             .map_err(|e| e.to_string())?;
-        } else {
-            writeln!(script, "video.set_output()").map_err(|e| e.to_string())?;
+        }
+        writeln!(script).map_err(|e| e.to_string())?;
+        writeln!(script, "video.set_output()").map_err(|e| e.to_string())?;
+        if has_audio {
+            writeln!(script, "audio.set_output(1)").map_err(|e| e.to_string())?;
         }
 
         Ok(())
     }
 }
 
+/// Replaces the given inclusive frame ranges of `clip` with `replacement`
+const RFS_HELPER: &str = "def rfs(clip, replacement, ranges):
+    for start, end in ranges:
+        parts = [clip[:start]] if start > 0 else []
+        parts.append(replacement[start:end + 1])
+        if end + 1 < clip.num_frames:
+            parts.append(clip[end + 1:])
+        clip = core.std.Splice(parts)
+    return clip
+";
+
+/// Pads the start of `audio` with silence, or cuts it off for a negative delay
+const DELAY_AUDIO_HELPER: &str = "def delay_audio(audio, seconds):
+    samples = round(audio.sample_rate * seconds)
+    if samples > 0:
+        return core.std.BlankAudio(audio, length=samples) + audio
+    return audio[-samples:]
+";
+
+/// Cuts `audio` at the samples where the given inclusive frame range of `clip` starts and ends
+const TRIM_AUDIO_HELPER: &str = "def trim_audio(audio, clip, first, last):
+    samples_per_frame = audio.sample_rate * clip.fps.denominator / clip.fps.numerator
+    return audio[round(first * samples_per_frame):round((last + 1) * samples_per_frame)]
+";
+
 fn escape_python_string(input: &str) -> String {
     input.replace(r"\", r"\\").replace(r"'", r"\'")
 }
 
 impl VapoursynthWriter {
-    pub fn new(
-        mut opts: AvsOptions,
-        apply_default_filters: bool,
-        audio_filename: Option<PathBuf>,
-    ) -> Self {
+    pub fn new(mut opts: AvsOptions, apply_default_filters: bool) -> Self {
         let default_filters: &[String] = &["core.rgvs.RemoveGrain(1)".to_string()];
         if apply_default_filters {
-            opts.filters.extend_from_slice(default_filters);
-        }
-        VapoursynthWriter {
-            opts,
-            audio_filename,
+            opts.heavy_filters.extend_from_slice(default_filters);
         }
+        VapoursynthWriter { opts }
     }
 
     fn determine_video_source_filter(&self, path: &Path) -> &'static str {
         match determine_input_type(path) {
             Some(InputTypes::DgIndex) => "core.d2v.Source",
             Some(InputTypes::DgAvc) => unimplemented!(),
+            // ffms2 can't seek in transport streams reliably
+            Some(InputTypes::M2ts) | Some(InputTypes::Vob) => "core.lsmas.LWLibavSource",
             Some(_) => "core.ffms2.Source",
             None => panic!("Invalid input type"),
         }
     }
 
     fn get_video_filter_full_name(&self, current_filename: &Path) -> &'static str {
-        self.determine_video_source_filter(&current_filename)
+        self.determine_video_source_filter(current_filename)
+    }
+
+    /// Calls the resizer for the selected kernel, with `args` before the kernel parameters
+    fn build_resizer_call(&self, args: &str) -> String {
+        match self.opts.resize_kernel {
+            ResizeKernel::Bicubic { b, c } => format!(
+                "core.resize.Bicubic({}, filter_param_a={}, filter_param_b={})",
+                args, b, c
+            ),
+            ResizeKernel::Lanczos { taps } => {
+                format!("core.resize.Lanczos({}, filter_param_a={})", args, taps)
+            }
+            ResizeKernel::Spline16 => format!("core.resize.Spline16({})", args),
+            ResizeKernel::Spline36 => format!("core.resize.Spline36({})", args),
+            ResizeKernel::Spline64 => format!("core.resize.Spline64({})", args),
+            ResizeKernel::Point => format!("core.resize.Point({})", args),
+        }
     }
 }
 
@@ -181,15 +414,7 @@ mod tests {
         let in_file = Path::new("files/example.mkv");
         let out_file = Path::new("files/vps_basic.vpy");
         let expected = Path::new("files/vps_basic.vpy.expected");
-        let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
-            to_cfr: false,
-            downsample: false,
-        };
+        let opts = AvsOptions::default();
         let mut writer = VapoursynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
         assert_eq!(&read_file(out_file), &read_file(expected));
@@ -201,13 +426,8 @@ mod tests {
         let out_file = Path::new("files/vps_audio.vpy");
         let expected = Path::new("files/vps_audio.vpy.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
             audio: (true, None),
-            resize: None,
-            to_cfr: false,
-            downsample: false,
+            ..Default::default()
         };
         let mut writer = VapoursynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -220,13 +440,8 @@ mod tests {
         let out_file = Path::new("files/vps_downsample.vpy");
         let expected = Path::new("files/vps_downsample.vpy.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
-            to_cfr: false,
             downsample: true,
+            ..Default::default()
         };
         let mut writer = VapoursynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -239,13 +454,8 @@ mod tests {
         let out_file = Path::new("files/vps_cfr.vpy");
         let expected = Path::new("files/vps_cfr.vpy.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
             to_cfr: true,
-            downsample: false,
+            ..Default::default()
         };
         let mut writer = VapoursynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -258,13 +468,8 @@ mod tests {
         let out_file = Path::new("files/vps_resize.vpy");
         let expected = Path::new("files/vps_resize.vpy.expected");
         let opts = AvsOptions {
-            filters: vec![],
-            ass: false,
-            ass_extract: None,
-            audio: (false, None),
-            resize: Some((640, 480)),
-            to_cfr: false,
-            downsample: false,
+            resize: Some((Some(640), Some(480))),
+            ..Default::default()
         };
         let mut writer = VapoursynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
@@ -277,16 +482,185 @@ mod tests {
         let out_file = Path::new("files/vps_ass.vpy");
         let expected = Path::new("files/vps_ass.vpy.expected");
         let opts = AvsOptions {
-            filters: vec![],
             ass: true,
-            ass_extract: None,
-            audio: (false, None),
-            resize: None,
-            to_cfr: false,
-            downsample: false,
+            ..Default::default()
+        };
+        let mut writer = VapoursynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(out_file), &read_file(expected));
+    }
+
+    #[test]
+    fn create_script_vps_ivtc() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/vps_ivtc.vpy");
+        let expected = Path::new("files/vps_ivtc.vpy.expected");
+        let first_pass_file = Path::new("files/vps_ivtc.pass1.vpy");
+        let first_pass_expected = Path::new("files/vps_ivtc.pass1.vpy.expected");
+        let opts = AvsOptions {
+            ivtc: true,
+            ..Default::default()
+        };
+        let mut writer = VapoursynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(out_file), &read_file(expected));
+        assert_eq!(&read_file(first_pass_file), &read_file(first_pass_expected));
+    }
+
+    #[test]
+    fn create_script_vps_fast_fp() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/vps_fast_fp.vpy");
+        let fast_file = Path::new("files/vps_fast_fp.fp.vpy");
+        let expected = Path::new("files/vps_fast_fp.fp.vpy.expected");
+        let opts = AvsOptions {
+            ass: true,
+            resize: Some((Some(640), Some(480))),
+            fast_fp: true,
+            fast_fp_skip: Some(4),
+            ..Default::default()
+        };
+        let mut writer = VapoursynthWriter::new(opts, true);
+        writer.create_script(in_file, out_file).unwrap();
+        assert_eq!(&read_file(fast_file), &read_file(expected));
+    }
+
+    #[test]
+    fn create_script_vps_crop() {
+        let in_file = Path::new("files/example.mkv");
+        let out_file = Path::new("files/vps_crop.vpy");
+        let expected = Path::new("files/vps_crop.vpy.expected");
+        let opts = AvsOptions {
+            crop: Some(CropMode::Manual(Crop {
+                left: 0,
+                top: 140,
+                right: 0,
+                bottom: 140,
+            })),
+            resize: Some((Some(640), Some(480))),
+            ..Default::default()
         };
         let mut writer = VapoursynthWriter::new(opts, true);
         writer.create_script(in_file, out_file).unwrap();
         assert_eq!(&read_file(out_file), &read_file(expected));
     }
+
+    #[test]
+    fn subtitle_filter_by_format() {
+        let writer = VapoursynthWriter::new(AvsOptions::default(), false);
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "core.sub.TextFile('ep01.ass')"
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.sup")),
+            "core.sub.ImageFile('ep01.sup')"
+        );
+    }
+
+    #[test]
+    fn subtitle_renderer_options() {
+        let writer = VapoursynthWriter::new(
+            AvsOptions {
+                sub_matrix: Some(SubtitleMatrix::Bt709),
+                sub_fontdir: Some(PathBuf::from("fonts")),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "core.sub.TextFile('ep01.ass', fontdir='fonts', matrix_s='709')"
+        );
+        let writer = VapoursynthWriter::new(
+            AvsOptions {
+                sub_renderer: Some(SubtitleRenderer::XyVsf),
+                sub_matrix: Some(SubtitleMatrix::Bt709),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            writer.build_subtitle_string(Path::new("ep01.ass")),
+            "core.xyvsf.TextSub('ep01.ass')"
+        );
+    }
+
+    #[test]
+    fn zone_replaces_frames() {
+        let writer = VapoursynthWriter::new(AvsOptions::default(), false);
+        assert_eq!(
+            writer.build_zone_string("core.f3kdb.Deband(y=64)", &[(0, 2157), (30000, 32157)]),
+            "rfs(core.f3kdb.Deband(video, y=64), [(0, 2157), (30000, 32157)])"
+        );
+    }
+
+    #[test]
+    fn color_props_only_tag_known_values() {
+        let writer = VapoursynthWriter::new(AvsOptions::default(), false);
+        assert_eq!(writer.build_color_props_string(&ColorInfo::default()), None);
+        let color = ColorInfo {
+            matrix: Some("bt709".to_owned()),
+            range: Some("tv".to_owned()),
+            primaries: None,
+            transfer: Some("smpte2084".to_owned()),
+        };
+        assert_eq!(
+            writer.build_color_props_string(&color),
+            Some("core.std.SetFrameProps(_Matrix=1, _ColorRange=1, _Transfer=16)".to_owned())
+        );
+    }
+
+    #[test]
+    fn audio_follows_segment_trims() {
+        let mut writer = VapoursynthWriter::new(
+            AvsOptions {
+                audio: (true, None),
+                ..Default::default()
+            },
+            false,
+        );
+        let breakpoint = BreakPoint {
+            start_frame: 24,
+            end_frame: 47,
+            foreign_uuid: None,
+            foreign_file: None,
+        };
+        assert_eq!(
+            writer.build_audio_dub_string(Path::new("ep01.mkv"), Some(2), -42),
+            Ok(SegmentFilter::Audio(
+                "delay_audio(core.bas.Source('ep01.mkv', track=2), -0.042)".to_owned()
+            ))
+        );
+        assert_eq!(
+            writer.build_audio_trim_string(&breakpoint, 2),
+            Some("trim_audio(video2, 24, 47)".to_owned())
+        );
+        let segment = |n: usize, first: u64, last: u64| ScriptSegment {
+            video: vec![
+                "core.ffms2.Source(source='ep01.mkv')".to_owned(),
+                format!("core.std.Trim({}, {})", first, last),
+            ],
+            audio: vec![
+                "core.bas.Source('ep01.mkv', track=2)".to_owned(),
+                format!("trim_audio(video{}, {}, {})", n, first, last),
+            ],
+        };
+        let out_file = std::env::temp_dir().join("avsser_audio_follows_segment_trims.vpy");
+        writer
+            .write_script(
+                &out_file,
+                &[],
+                &[segment(1, 0, 23), segment(2, 48, 71)],
+                &[],
+                false,
+            )
+            .unwrap();
+        let script = read_file(&out_file);
+        assert!(script.contains(
+            "audio1 = core.bas.Source('ep01.mkv', track=2)\naudio1 = trim_audio(audio1, video1, 0, 23)\n"
+        ));
+        assert!(script
+            .ends_with("audio = audio1 + audio2\n\nvideo.set_output()\naudio.set_output(1)\n"));
+    }
 }
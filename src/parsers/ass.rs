@@ -0,0 +1,397 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// An ASS/SSA script, split into the sections that avsser needs to touch.
+/// Everything else is kept as raw lines so it can be written back unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AssScript {
+    pub script_info: Vec<String>,
+    /// `[V4+ Styles]` for ASS, `[V4 Styles]` for SSA
+    pub style_section: String,
+    pub style_format: Vec<String>,
+    /// Field values of each `Style:` line, in `style_format` order
+    pub styles: Vec<Vec<String>>,
+    pub event_format: Vec<String>,
+    pub events: Vec<AssEvent>,
+    pub other_sections: Vec<(String, Vec<String>)>,
+}
+
+/// A font face which some text in the script is rendered with
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FontUsage {
+    pub family: String,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssEvent {
+    /// `Dialogue`, `Comment`, ...
+    pub kind: String,
+    /// Field values in `event_format` order, the last one being the text
+    pub fields: Vec<String>,
+}
+
+impl AssScript {
+    pub fn open(path: &Path) -> Result<AssScript, String> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+        AssScript::parse(&contents).map_err(|e| format!("{} in {}", e, path.to_str().unwrap()))
+    }
+
+    pub fn parse(contents: &str) -> Result<AssScript, String> {
+        let mut script = AssScript {
+            style_section: "[V4+ Styles]".to_owned(),
+            ..Default::default()
+        };
+        let mut section = String::new();
+        for line in contents.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim_end();
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line.to_owned();
+                match section.to_lowercase().as_str() {
+                    "[script info]" | "[events]" => (),
+                    "[v4+ styles]" | "[v4 styles]" => script.style_section = section.clone(),
+                    _ => script.other_sections.push((section.clone(), Vec::new())),
+                }
+                continue;
+            }
+            match section.to_lowercase().as_str() {
+                "[script info]" => {
+                    if !line.is_empty() {
+                        script.script_info.push(line.to_owned());
+                    }
+                }
+                "[v4+ styles]" | "[v4 styles]" => {
+                    if let Some(format) = line.strip_prefix("Format:") {
+                        script.style_format = split_format(format);
+                    } else if let Some(style) = line.strip_prefix("Style:") {
+                        if script.style_format.is_empty() {
+                            return Err("Style found before its format line".to_owned());
+                        }
+                        script
+                            .styles
+                            .push(split_fields(style, script.style_format.len()));
+                    }
+                }
+                "[events]" => {
+                    if let Some(format) = line.strip_prefix("Format:") {
+                        script.event_format = split_format(format);
+                    } else if let Some((kind, event)) = line.split_once(':') {
+                        if script.event_format.is_empty() {
+                            return Err("Event found before its format line".to_owned());
+                        }
+                        script.events.push(AssEvent {
+                            kind: kind.to_owned(),
+                            fields: split_fields(event, script.event_format.len()),
+                        });
+                    }
+                }
+                _ if line.is_empty() => (),
+                _ => {
+                    if let Some((_, lines)) = script.other_sections.last_mut() {
+                        lines.push(line.to_owned());
+                    }
+                }
+            }
+        }
+        if script.event_format.is_empty() {
+            return Err("No [Events] section found".to_owned());
+        }
+        Ok(script)
+    }
+
+    pub fn style_field(&self, name: &str) -> Option<usize> {
+        field_index(&self.style_format, name)
+    }
+
+    pub fn event_field(&self, name: &str) -> Option<usize> {
+        field_index(&self.event_format, name)
+    }
+
+    /// Lists the fonts used by the styles and `\fn`/`\b`/`\i`/`\r` overrides
+    /// of every dialogue line, without duplicates
+    pub fn used_fonts(&self) -> Vec<FontUsage> {
+        let name = self.style_field("Name");
+        let font = self.style_field("Fontname");
+        let bold = self.style_field("Bold");
+        let italic = self.style_field("Italic");
+        let style_usage = |style: &Vec<String>| FontUsage {
+            family: font
+                .map(|i| style[i].trim().trim_start_matches('@').to_owned())
+                .unwrap_or_default(),
+            bold: bold.map(|i| is_enabled(&style[i])).unwrap_or(false),
+            italic: italic.map(|i| is_enabled(&style[i])).unwrap_or(false),
+        };
+        let find_style = |style_name: &str| {
+            self.styles
+                .iter()
+                .find(|style| name.map(|i| style[i] == style_name).unwrap_or(false))
+                .or_else(|| self.styles.first())
+                .map(style_usage)
+        };
+
+        let mut used = Vec::new();
+        let style_field = self.event_field("Style");
+        for event in self.events.iter().filter(|event| event.kind == "Dialogue") {
+            let base = match style_field {
+                Some(i) => find_style(event.fields[i].trim().trim_start_matches('*')),
+                None => self.styles.first().map(style_usage),
+            }
+            .unwrap_or(FontUsage {
+                family: String::new(),
+                bold: false,
+                italic: false,
+            });
+            let mut current = base.clone();
+            let mut recorded = false;
+            let mut chars = event.fields.last().unwrap().chars().peekable();
+            while let Some(c) = chars.next() {
+                if c == '{' {
+                    let block: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    for tag in block.split('\\').map(str::trim) {
+                        if let Some(family) = tag.strip_prefix("fn") {
+                            current.family = match family.trim().trim_start_matches('@') {
+                                "" => base.family.clone(),
+                                family => family.to_owned(),
+                            };
+                        } else if let Some(value) = numeric_tag(tag, 'b') {
+                            current.bold = match value {
+                                Some(weight) => weight == 1 || weight >= 600,
+                                None => base.bold,
+                            };
+                        } else if let Some(value) = numeric_tag(tag, 'i') {
+                            current.italic = value.map(|value| value == 1).unwrap_or(base.italic);
+                        } else if let Some(style_name) = tag.strip_prefix('r') {
+                            current = match style_name.trim() {
+                                "" => base.clone(),
+                                style_name => {
+                                    find_style(style_name).unwrap_or_else(|| base.clone())
+                                }
+                            };
+                        } else {
+                            continue;
+                        }
+                        recorded = false;
+                    }
+                } else if !recorded && !c.is_whitespace() {
+                    if !current.family.is_empty() && !used.contains(&current) {
+                        used.push(current.clone());
+                    }
+                    recorded = true;
+                }
+            }
+        }
+        used
+    }
+
+    /// Replaces a `[Script Info]` value, or adds it if the script has none
+    pub fn set_info(&mut self, key: &str, value: &str) {
+        let line = format!("{}: {}", key, value);
+        let existing = self.script_info.iter_mut().find(|line| {
+            line.split_once(':')
+                .map(|(line_key, _)| line_key.trim().eq_ignore_ascii_case(key))
+                .unwrap_or(false)
+        });
+        match existing {
+            Some(existing) => *existing = line,
+            None => self.script_info.push(line),
+        }
+    }
+
+    /// Looks up a `[Script Info]` value such as `PlayResX`
+    pub fn info(&self, key: &str) -> Option<&str> {
+        self.script_info.iter().find_map(|line| {
+            let (line_key, value) = line.split_once(':')?;
+            if line_key.trim().eq_ignore_ascii_case(key) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl fmt::Display for AssScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[Script Info]")?;
+        for line in &self.script_info {
+            writeln!(f, "{}", line)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", self.style_section)?;
+        writeln!(f, "Format: {}", self.style_format.join(", "))?;
+        for style in &self.styles {
+            writeln!(f, "Style: {}", style.join(","))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "[Events]")?;
+        writeln!(f, "Format: {}", self.event_format.join(", "))?;
+        for event in &self.events {
+            writeln!(f, "{}: {}", event.kind, event.fields.join(","))?;
+        }
+        for (section, lines) in &self.other_sections {
+            writeln!(f)?;
+            writeln!(f, "{}", section)?;
+            for line in lines {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Style flags are `-1` for on, but some tools write `1`
+fn is_enabled(value: &str) -> bool {
+    matches!(value.trim(), "-1" | "1")
+}
+
+/// Matches tags like `\b1` or `\i`, but not longer ones like `\blur`
+fn numeric_tag(tag: &str, name: char) -> Option<Option<u32>> {
+    let value = tag.strip_prefix(name)?;
+    if value.chars().all(|c| c.is_ascii_digit()) {
+        Some(value.parse().ok())
+    } else {
+        None
+    }
+}
+
+fn split_format(format: &str) -> Vec<String> {
+    format
+        .split(',')
+        .map(|field| field.trim().to_owned())
+        .collect()
+}
+
+/// Splits a line into at most `count` fields, so commas in the text survive
+fn split_fields(line: &str, count: usize) -> Vec<String> {
+    let mut fields: Vec<String> = line
+        .trim_start()
+        .splitn(count, ',')
+        .map(str::to_owned)
+        .collect();
+    fields.resize(count, String::new());
+    fields
+}
+
+fn field_index(format: &[String], name: &str) -> Option<usize> {
+    format
+        .iter()
+        .position(|field| field.eq_ignore_ascii_case(name))
+}
+
+/// Parses an `H:MM:SS.cc` timestamp into centiseconds
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let mut parts = timestamp.trim().splitn(3, ':');
+    let hours: u64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let (seconds, fraction) = parts.next()?.split_once('.')?;
+    let seconds: u64 = seconds.parse().ok()?;
+    // Some tools write more or fewer than two fractional digits
+    let centiseconds = match fraction.len() {
+        0 => 0,
+        1 => fraction.parse::<u64>().ok()? * 10,
+        _ => fraction[..2].parse().ok()?,
+    };
+    Some(((hours * 60 + minutes) * 60 + seconds) * 100 + centiseconds)
+}
+
+pub fn format_timestamp(centiseconds: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centiseconds / 360_000,
+        centiseconds / 6_000 % 60,
+        centiseconds / 100 % 60,
+        centiseconds % 100
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str =
+        "\u{feff}[Script Info]\nTitle: Episode 1\nPlayResX: 1920\nPlayResY: 1080\n\n\
+        [V4+ Styles]\nFormat: Name, Fontname, Fontsize, Bold\nStyle: Default,Open Sans,72,0\n\n\
+        [Events]\nFormat: Layer, Start, End, Style, Text\n\
+        Dialogue: 0,0:00:01.50,0:00:03.00,Default,Hello, world\n\
+        Comment: 0,0:00:04.00,0:00:05.00,Default,note\n\n\
+        [Fonts]\nfontname: OpenSans.ttf\n";
+
+    #[test]
+    fn parse_and_write_ass() {
+        let script = AssScript::parse(SCRIPT).unwrap();
+        assert_eq!(script.info("playresx"), Some("1920"));
+        assert_eq!(script.style_field("Fontname"), Some(1));
+        assert_eq!(script.styles[0][1], "Open Sans");
+        assert_eq!(script.events.len(), 2);
+        assert_eq!(script.events[0].fields[4], "Hello, world");
+        assert_eq!(script.events[1].kind, "Comment");
+        assert_eq!(
+            script.other_sections,
+            vec![(
+                "[Fonts]".to_owned(),
+                vec!["fontname: OpenSans.ttf".to_owned()]
+            )]
+        );
+        assert_eq!(AssScript::parse(&script.to_string()).unwrap(), script);
+    }
+
+    #[test]
+    fn set_script_info() {
+        let mut script = AssScript::parse(SCRIPT).unwrap();
+        script.set_info("YCbCr Matrix", "TV.601");
+        script.set_info("ycbcr matrix", "TV.709");
+        script.set_info("PlayResX", "1280");
+        assert_eq!(script.info("YCbCr Matrix"), Some("TV.709"));
+        assert_eq!(script.info("PlayResX"), Some("1280"));
+        assert_eq!(script.script_info.len(), 4);
+    }
+
+    #[test]
+    fn list_used_fonts() {
+        let script = AssScript::parse(
+            "[V4+ Styles]\nFormat: Name, Fontname, Bold, Italic\n\
+             Style: Default,Open Sans,0,0\nStyle: Sign,@Kaushan Script,-1,0\n\n\
+             [Events]\nFormat: Layer, Start, End, Style, Text\n\
+             Dialogue: 0,0:00:00.00,0:00:01.00,Default,Plain {\\i1}italic{\\i0}\n\
+             Dialogue: 0,0:00:00.00,0:00:01.00,Sign,{\\blur2\\bord1}Sign {\\fnComic Neue\\b0}text{\\rDefault\\b700}reset\n\
+             Dialogue: 0,0:00:00.00,0:00:01.00,Default,{\\fnUnused}\n\
+             Comment: 0,0:00:00.00,0:00:01.00,Default,{\\fnCommented}text\n",
+        )
+        .unwrap();
+        let usage = |family: &str, bold, italic| FontUsage {
+            family: family.to_owned(),
+            bold,
+            italic,
+        };
+        assert_eq!(
+            script.used_fonts(),
+            vec![
+                usage("Open Sans", false, false),
+                usage("Open Sans", false, true),
+                usage("Kaushan Script", true, false),
+                usage("Comic Neue", false, false),
+                usage("Open Sans", true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ass_without_events() {
+        assert!(AssScript::parse("[Script Info]\nTitle: x\n").is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("0:00:01.50"), Some(150));
+        assert_eq!(parse_timestamp("1:02:03.4"), Some(372_340));
+        assert_eq!(parse_timestamp("0:00:01.505"), Some(150));
+        assert_eq!(parse_timestamp("garbage"), None);
+        assert_eq!(format_timestamp(372_340), "1:02:03.40");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::parsers::ffprobe::VideoInfo;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref CROPDETECT_PATTERN: Regex = Regex::new(r"crop=(\d+):(\d+):(\d+):(\d+)").unwrap();
+}
+
+const SAMPLE_POINTS: u32 = 10;
+const FRAMES_PER_SAMPLE: u32 = 5;
+
+/// Pixels to remove from each edge of the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Crop {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl Crop {
+    pub fn is_empty(&self) -> bool {
+        self.left == 0 && self.top == 0 && self.right == 0 && self.bottom == 0
+    }
+}
+
+/// Runs ffmpeg's `cropdetect` on frames sampled across the whole video,
+/// and picks the most common result
+pub fn detect_crop(path: &Path, info: &VideoInfo) -> Result<Crop, String> {
+    let duration = info.duration.unwrap_or(0f64);
+    let mut detected: Vec<(u32, u32, u32, u32)> = Vec::new();
+    for i in 0..SAMPLE_POINTS {
+        let position = duration * f64::from(2 * i + 1) / f64::from(2 * SAMPLE_POINTS);
+        let output = match Command::new("ffmpeg")
+            .args([
+                "-hide_banner",
+                "-ss",
+                &format!("{:.3}", position),
+                "-i",
+                path.to_str().unwrap(),
+                "-map",
+                "0:v:0",
+                "-vf",
+                "cropdetect=round=1:reset=1",
+                "-frames:v",
+                &FRAMES_PER_SAMPLE.to_string(),
+                "-f",
+                "null",
+                "-",
+            ])
+            .output()
+        {
+            Ok(x) => x,
+            Err(x) => return Err(format!("{}", x)),
+        };
+        detected.extend(parse_cropdetect_output(&String::from_utf8_lossy(
+            &output.stderr,
+        )));
+        if duration <= 0f64 {
+            // We can't seek without knowing where to, so the first frames will have to do
+            break;
+        }
+    }
+
+    pick_stable_crop(&detected, info).ok_or_else(|| {
+        format!(
+            "cropdetect returned no results for {}",
+            path.to_str().unwrap()
+        )
+    })
+}
+
+/// Returns the `w:h:x:y` values of every `crop=` line in ffmpeg's output
+fn parse_cropdetect_output(output: &str) -> Vec<(u32, u32, u32, u32)> {
+    output
+        .lines()
+        .filter_map(|line| CROPDETECT_PATTERN.captures(line))
+        .map(|captures| {
+            (
+                captures[1].parse().unwrap(),
+                captures[2].parse().unwrap(),
+                captures[3].parse().unwrap(),
+                captures[4].parse().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// Picks the most common detected area, preferring the largest on ties,
+/// and rounds each edge inwards to the chroma subsampling mod
+fn pick_stable_crop(detected: &[(u32, u32, u32, u32)], info: &VideoInfo) -> Option<Crop> {
+    let mut counts: HashMap<(u32, u32, u32, u32), usize> = HashMap::new();
+    for area in detected {
+        *counts.entry(*area).or_insert(0) += 1;
+    }
+    let (width, height, x, y) = counts
+        .into_iter()
+        .max_by_key(|&((w, h, _, _), count)| (count, u64::from(w) * u64::from(h)))
+        .map(|(area, _)| area)?;
+
+    let (mod_w, mod_h) = info.chroma_mod();
+    let round_up = |value: u32, modulus: u32| value.div_ceil(modulus) * modulus;
+    Some(Crop {
+        left: round_up(x, mod_w),
+        top: round_up(y, mod_h),
+        right: round_up(info.width.saturating_sub(width + x), mod_w),
+        bottom: round_up(info.height.saturating_sub(height + y), mod_h),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::ffprobe::ColorInfo;
+    use crate::parsers::ffprobe::HdrMetadata;
+
+    fn info() -> VideoInfo {
+        VideoInfo {
+            width: 1920,
+            height: 1080,
+            pix_fmt: "yuv420p".to_owned(),
+            sar: (1, 1),
+            duration: None,
+            frame_rate: None,
+            color: ColorInfo::default(),
+            hdr_metadata: HdrMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn parse_cropdetect_lines() {
+        let output = "[Parsed_cropdetect_0 @ 0x55d0] x1:0 x2:1919 y1:138 y2:941 w:1920 h:804 x:0 y:138 pts:0 t:0.000000 limit:0.094118 crop=1920:804:0:138\n\
+                      frame=    5 fps=0.0 q=-0.0 Lsize=N/A time=00:00:00.20\n";
+        assert_eq!(parse_cropdetect_output(output), vec![(1920, 804, 0, 138)]);
+    }
+
+    #[test]
+    fn pick_most_common_crop() {
+        let detected = [
+            (1920, 804, 0, 138),
+            (1920, 1080, 0, 0),
+            (1920, 804, 0, 138),
+            (1916, 804, 2, 138),
+        ];
+        assert_eq!(
+            pick_stable_crop(&detected, &info()),
+            Some(Crop {
+                left: 0,
+                top: 138,
+                right: 0,
+                bottom: 138,
+            })
+        );
+    }
+
+    #[test]
+    fn round_crop_to_chroma_mod() {
+        let detected = [(1918, 801, 1, 139)];
+        assert_eq!(
+            pick_stable_crop(&detected, &info()),
+            Some(Crop {
+                left: 2,
+                top: 140,
+                right: 2,
+                bottom: 140,
+            })
+        );
+    }
+}
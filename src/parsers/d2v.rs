@@ -0,0 +1,102 @@
+use crate::parsers::ffprobe::ColorInfo;
+use crate::parsers::ffprobe::HdrMetadata;
+use crate::parsers::ffprobe::VideoInfo;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref PICTURE_SIZE_PATTERN: Regex = Regex::new(r"^Picture_Size=(\d+)x(\d+)").unwrap();
+    static ref ASPECT_RATIO_PATTERN: Regex =
+        Regex::new(r"^Aspect_Ratio=(\d+(?:\.\d+)?):(\d+(?:\.\d+)?)").unwrap();
+    static ref FRAME_RATE_PATTERN: Regex = Regex::new(r"^Frame_Rate=\d+ \((\d+)/(\d+)\)").unwrap();
+}
+
+/// Reads the picture size and aspect ratio flag from a DGIndex project file
+pub fn get_d2v_video_info(path: &Path) -> Result<VideoInfo, String> {
+    let contents = read_to_string(path).map_err(|e| e.to_string())?;
+    parse_d2v_header(&contents).map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))
+}
+
+fn parse_d2v_header(contents: &str) -> Result<VideoInfo, String> {
+    let mut size: Option<(u32, u32)> = None;
+    let mut aspect: Option<(f64, f64)> = None;
+    let mut frame_rate: Option<f64> = None;
+    for line in contents.lines() {
+        let invalid = || format!("Invalid line in d2v header: {}", line);
+        if let Some(captures) = PICTURE_SIZE_PATTERN.captures(line) {
+            size = Some((
+                captures[1].parse().map_err(|_| invalid())?,
+                captures[2].parse().map_err(|_| invalid())?,
+            ))
+            .filter(|&(width, height)| width > 0 && height > 0);
+            if size.is_none() {
+                return Err(invalid());
+            }
+        } else if let Some(captures) = ASPECT_RATIO_PATTERN.captures(line) {
+            let dar_num: f64 = captures[1].parse().map_err(|_| invalid())?;
+            let dar_den: f64 = captures[2].parse().map_err(|_| invalid())?;
+            if dar_num <= 0f64 || dar_den <= 0f64 {
+                return Err(invalid());
+            }
+            aspect = Some((dar_num, dar_den));
+        } else if let Some(captures) = FRAME_RATE_PATTERN.captures(line) {
+            let num: f64 = captures[1].parse().map_err(|_| invalid())?;
+            let den: f64 = captures[2].parse().map_err(|_| invalid())?;
+            frame_rate = Some(num / den).filter(|fps| fps.is_finite() && *fps > 0f64);
+        }
+    }
+    let (width, height) = size.ok_or("No picture size in d2v header")?;
+
+    // d2v only knows the display aspect ratio, so work backwards to the SAR
+    let sar = match aspect {
+        Some((dar_num, dar_den)) => {
+            let sar = (dar_num * f64::from(height)) / (dar_den * f64::from(width));
+            ((sar * 10_000f64).round() as u32, 10_000)
+        }
+        None => (1, 1),
+    };
+    Ok(VideoInfo {
+        width,
+        height,
+        pix_fmt: "yuv420p".to_owned(),
+        sar,
+        duration: None,
+        frame_rate,
+        color: ColorInfo::default(),
+        hdr_metadata: HdrMetadata::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_anamorphic_d2v() {
+        let header = "DGIndexProjectFile16\n1\nD:\\VTS_01_1.VOB\n\nStream_Type=1\nMPEG_Type=2\niDCT_Algorithm=5 (1:MMX 2:SSEMMX 3:SSE2MMX 4:FPU 5:REF 6:Skal 7:Simple)\nYUVRGB_Scale=1\nLuminance_Filter=0,0\nClipping=0,0,0,0\nAspect_Ratio=16:9\nPicture_Size=720x480\nField_Operation=0\nFrame_Rate=29970 (30000/1001)\n";
+        let info = parse_d2v_header(header).expect("valid header");
+        assert_eq!((info.width, info.height), (720, 480));
+        assert_eq!(info.sar, (11_852, 10_000));
+        assert_eq!(info.frame_rate, Some(30_000f64 / 1_001f64));
+    }
+
+    #[test]
+    fn parse_d2v_without_picture_size() {
+        assert!(parse_d2v_header("DGIndexProjectFile16\nAspect_Ratio=4:3\n").is_err());
+    }
+
+    #[test]
+    fn parse_malformed_d2v() {
+        assert!(parse_d2v_header("Picture_Size=99999999999x480\n").is_err());
+        assert!(parse_d2v_header("Picture_Size=0x480\n").is_err());
+        assert!(parse_d2v_header("Picture_Size=720x480\nAspect_Ratio=16:0\n").is_err());
+        assert!(
+            parse_d2v_header("Picture_Size=720x480\nFrame_Rate=0 (99999999999999999999/0)\n")
+                .map(|info| info.frame_rate)
+                == Ok(None)
+        );
+    }
+}
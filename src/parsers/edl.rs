@@ -0,0 +1,269 @@
+use crate::ranges::Position;
+use crate::ranges::Range;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::path::PathBuf;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref CMX_EVENT_PATTERN: Regex = Regex::new(
+        r"^(\d{3,6})\s+(\S+)\s+(\S+)\s+(\S+)(?:\s+\d{3})?\s+(\d{2}:\d{2}:\d{2}[:;.,]\d{2})\s+(\d{2}:\d{2}:\d{2}[:;.,]\d{2})\s+\S+\s+\S+\s*$"
+    )
+    .unwrap();
+    static ref CMX_CLIP_NAME_PATTERN: Regex =
+        Regex::new(r"^\*\s*(?:FROM CLIP NAME|SOURCE FILE):\s*(.+?)\s*$").unwrap();
+    static ref TIMECODE_PATTERN: Regex =
+        Regex::new(r"^(\d{2}):(\d{2}):(\d{2})([:;.,])(\d{2})$").unwrap();
+}
+
+/// One cut of an edit decision list, in the time of its own source file
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdlEntry {
+    /// Resolved against the directory of the EDL
+    pub file: PathBuf,
+    pub range: Range,
+}
+
+/// Reads a CMX3600 or mpv EDL, telling them apart by mpv's header
+pub fn read_edl(path: &Path) -> Result<Vec<EdlEntry>, String> {
+    let contents =
+        read_to_string(path).map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let entries = if contents.starts_with("# mpv EDL v0") {
+        parse_mpv_edl(&contents, base)?
+    } else {
+        parse_cmx3600(&contents, base)?
+    };
+    if entries.is_empty() {
+        return Err(format!("{} has no video cuts", path.to_str().unwrap()));
+    }
+    Ok(entries)
+}
+
+/// Parses an mpv EDL, i.e. `file,start,length` entries in seconds, separated
+/// by lines or semicolons. A missing length plays to the end of the file.
+fn parse_mpv_edl(contents: &str, base: &Path) -> Result<Vec<EdlEntry>, String> {
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        for entry in line.split(';').map(str::trim) {
+            // Headers like `!no_chapters` change how mpv plays the EDL, not what it contains
+            if entry.is_empty() || entry.starts_with('!') {
+                continue;
+            }
+            entries.push(parse_mpv_entry(entry, base)?);
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_mpv_entry(entry: &str, base: &Path) -> Result<EdlEntry, String> {
+    let (file, rest) = split_mpv_file_name(entry)?;
+    let mut start = 0f64;
+    let mut length = None;
+    for (i, param) in rest
+        .split(',')
+        .filter(|param| !param.is_empty())
+        .enumerate()
+    {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name, value),
+            None if i == 0 => ("start", param),
+            None if i == 1 => ("length", param),
+            None => return Err(format!("Unexpected parameter in mpv EDL: {}", param)),
+        };
+        let seconds = || {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|seconds| *seconds >= 0f64)
+                .ok_or_else(|| format!("Invalid {} in mpv EDL: {}", name, value))
+        };
+        match name {
+            "start" => start = seconds()?,
+            "length" => length = Some(seconds()?),
+            _ => (),
+        }
+    }
+    Ok(EdlEntry {
+        file: base.join(file),
+        range: Range {
+            start: Position::Time(start),
+            end: length.map(|length| Position::Time(start + length)),
+        },
+    })
+}
+
+/// Splits off the file name, which may be written as `%LENGTH%NAME` so that
+/// it can contain commas
+fn split_mpv_file_name(entry: &str) -> Result<(&str, &str), String> {
+    let invalid = || format!("Invalid file name in mpv EDL: {}", entry);
+    if let Some(rest) = entry.strip_prefix('%') {
+        let (length, rest) = rest.split_once('%').ok_or_else(invalid)?;
+        let length: usize = length.parse().map_err(|_| invalid())?;
+        if length > rest.len() || !rest.is_char_boundary(length) {
+            return Err(invalid());
+        }
+        let (file, rest) = rest.split_at(length);
+        return Ok((file, rest.strip_prefix(',').unwrap_or(rest)));
+    }
+    Ok(entry.split_once(',').unwrap_or((entry, "")))
+}
+
+/// Parses the video events of a CMX3600 EDL. Each event's source is taken
+/// from its `FROM CLIP NAME` or `SOURCE FILE` comment, falling back to the
+/// reel name.
+fn parse_cmx3600(contents: &str, base: &Path) -> Result<Vec<EdlEntry>, String> {
+    let mut drop_frame = false;
+    let mut entries = Vec::new();
+    // Comments naming the clip follow their event
+    let mut current: Option<(String, Range)> = None;
+    let mut flush = |current: &mut Option<(String, Range)>| {
+        if let Some((file, range)) = current.take() {
+            entries.push(EdlEntry {
+                file: base.join(file),
+                range,
+            });
+        }
+    };
+    for line in contents.lines().map(str::trim) {
+        if let Some(mode) = line.strip_prefix("FCM:") {
+            drop_frame = mode.trim() == "DROP FRAME";
+            continue;
+        }
+        if let Some(captures) = CMX_CLIP_NAME_PATTERN.captures(line) {
+            if let Some((ref mut file, _)) = current {
+                *file = captures[1].to_owned();
+            }
+            continue;
+        }
+        let captures = match CMX_EVENT_PATTERN.captures(line) {
+            Some(captures) => captures,
+            None => continue,
+        };
+        flush(&mut current);
+        let reel = &captures[2];
+        let track = &captures[3];
+        // Black and colour bars have no source, and audio events don't cut the video
+        if matches!(reel, "BL" | "BLK" | "BLACK") || !(track.contains('V') || track == "B") {
+            continue;
+        }
+        let start = parse_timecode(&captures[5], drop_frame)?;
+        let end = parse_timecode(&captures[6], drop_frame)?;
+        // The outgoing side of a dissolve has the same in and out point
+        if start == end {
+            continue;
+        }
+        current = Some((
+            reel.to_owned(),
+            Range {
+                start,
+                end: Some(end),
+            },
+        ));
+    }
+    flush(&mut current);
+    Ok(entries)
+}
+
+/// Parses a SMPTE timecode like `01:00:00:00`, or `01:00:00;00` for drop-frame
+pub fn parse_timecode(timecode: &str, drop_frame: bool) -> Result<Position, String> {
+    let captures = TIMECODE_PATTERN
+        .captures(timecode)
+        .ok_or_else(|| format!("Invalid timecode: {}", timecode))?;
+    let field = |i: usize| captures[i].parse::<u64>().unwrap();
+    Ok(Position::Timecode {
+        seconds: field(1) * 3600 + field(2) * 60 + field(3),
+        frames: field(5),
+        drop_frame: drop_frame || matches!(&captures[4], ";" | ","),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mpv_entries() {
+        let entries = parse_mpv_edl(
+            "# mpv EDL v0\n\
+             episode01.mkv,10,20\n\
+             !no_chapters\n\
+             %14%episode,02.mkv,start=5.5;episode03.mkv\n",
+            Path::new("cuts"),
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                EdlEntry {
+                    file: PathBuf::from("cuts/episode01.mkv"),
+                    range: Range {
+                        start: Position::Time(10.0),
+                        end: Some(Position::Time(30.0)),
+                    },
+                },
+                EdlEntry {
+                    file: PathBuf::from("cuts/episode,02.mkv"),
+                    range: Range {
+                        start: Position::Time(5.5),
+                        end: None,
+                    },
+                },
+                EdlEntry {
+                    file: PathBuf::from("cuts/episode03.mkv"),
+                    range: Range {
+                        start: Position::Time(0.0),
+                        end: None,
+                    },
+                },
+            ]
+        );
+        assert!(parse_mpv_edl("# mpv EDL v0\n%40%short.mkv\n", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn parse_cmx3600_events() {
+        let entries = parse_cmx3600(
+            "TITLE: RECAP\n\
+             FCM: NON-DROP FRAME\n\
+             \n\
+             001  AX       V     C        00:01:30:12 00:02:00:00 01:00:00:00 01:00:29:12\n\
+             * FROM CLIP NAME: episode01.mkv\n\
+             002  AX       A     C        00:01:30:12 00:02:00:00 01:00:00:00 01:00:29:12\n\
+             003  BL       V     C        00:00:00:00 00:00:01:00 01:00:29:12 01:00:30:12\n\
+             004  EP02     V     C        00:10:00:00 00:10:00:00 01:00:30:12 01:00:30:12\n\
+             004  EP02     V     D    024 00:10:00:00 00:10:30:00 01:00:30:12 01:01:00:12\n",
+            Path::new(""),
+        )
+        .unwrap();
+        let timecode = |seconds, frames| Position::Timecode {
+            seconds,
+            frames,
+            drop_frame: false,
+        };
+        assert_eq!(
+            entries,
+            vec![
+                EdlEntry {
+                    file: PathBuf::from("episode01.mkv"),
+                    range: Range {
+                        start: timecode(90, 12),
+                        end: Some(timecode(120, 0)),
+                    },
+                },
+                EdlEntry {
+                    file: PathBuf::from("EP02"),
+                    range: Range {
+                        start: timecode(600, 0),
+                        end: Some(timecode(630, 0)),
+                    },
+                },
+            ]
+        );
+    }
+}
@@ -2,9 +2,16 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::process::Command;
 
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref BIT_DEPTH_PATTERN: Regex = Regex::new(r"p(9|10|12|14|16)(?:le|be)?$").unwrap();
+}
+
 pub fn get_streams_list(path: &Path) -> Result<Vec<HashMap<String, String>>, String> {
     let output = match Command::new("ffprobe")
-        .args(&["-show_streams", path.to_str().unwrap()])
+        .args(["-show_streams", path.to_str().unwrap()])
         .output()
     {
         Ok(x) => x,
@@ -22,11 +29,373 @@ pub fn get_streams_list(path: &Path) -> Result<Vec<HashMap<String, String>>, Str
             continue;
         } else if line == "[/STREAM]" {
             streams.push(current.clone());
-        } else {
-            let key_pair = line.splitn(2, '=').collect::<Vec<&str>>();
-            current.insert(key_pair[0].to_owned(), key_pair[1].to_owned());
+        } else if let Some((key, value)) = line.split_once('=') {
+            current.insert(key.to_owned(), value.to_owned());
         }
     }
 
     Ok(streams)
 }
+
+#[derive(Debug, Clone)]
+pub struct VideoInfo {
+    pub width: u32,
+    pub height: u32,
+    pub pix_fmt: String,
+    /// Sample aspect ratio as `(num, den)`
+    pub sar: (u32, u32),
+    /// Length of the whole file in seconds, if known
+    pub duration: Option<f64>,
+    /// Frames per second, if known
+    pub frame_rate: Option<f64>,
+    pub color: ColorInfo,
+    /// Static HDR metadata, only probed for HDR sources
+    pub hdr_metadata: HdrMetadata,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HdrMetadata {
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+}
+
+/// SMPTE ST 2086 mastering display color volume, with CIE 1931 xy chromaticities
+/// and luminance in cd/m²
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MasteringDisplay {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white_point: (f64, f64),
+    pub min_luminance: f64,
+    pub max_luminance: f64,
+}
+
+/// MaxCLL and MaxFALL in cd/m²
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ContentLightLevel {
+    pub max_content: u32,
+    pub max_average: u32,
+}
+
+/// Color tags using ffprobe's names, `None` when missing or unknown
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColorInfo {
+    pub matrix: Option<String>,
+    pub range: Option<String>,
+    pub primaries: Option<String>,
+    pub transfer: Option<String>,
+}
+
+impl ColorInfo {
+    pub fn is_empty(&self) -> bool {
+        self.matrix.is_none()
+            && self.range.is_none()
+            && self.primaries.is_none()
+            && self.transfer.is_none()
+    }
+
+    /// PQ and HLG sources both need more than 8 bits to survive
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.transfer.as_deref(),
+            Some("smpte2084") | Some("arib-std-b67")
+        )
+    }
+
+    pub fn is_full_range(&self) -> bool {
+        self.range.as_deref() == Some("pc")
+    }
+
+    /// Fills in an untagged matrix the same way players do, based on the frame height
+    pub fn with_guessed_matrix(&self, height: u32) -> ColorInfo {
+        let mut color = self.clone();
+        if color.matrix.is_none() {
+            color.matrix = Some(if height >= 720 { "bt709" } else { "smpte170m" }.to_owned());
+        }
+        color
+    }
+
+    /// Matrix coefficients as defined by ITU-T H.273
+    pub fn matrix_code(&self) -> Option<u8> {
+        match self.matrix.as_deref()? {
+            "gbr" => Some(0),
+            "bt709" => Some(1),
+            "fcc" => Some(4),
+            "bt470bg" => Some(5),
+            "smpte170m" => Some(6),
+            "smpte240m" => Some(7),
+            "ycgco" => Some(8),
+            "bt2020nc" => Some(9),
+            "bt2020c" => Some(10),
+            _ => None,
+        }
+    }
+
+    /// Colour primaries as defined by ITU-T H.273
+    pub fn primaries_code(&self) -> Option<u8> {
+        match self.primaries.as_deref()? {
+            "bt709" => Some(1),
+            "bt470m" => Some(4),
+            "bt470bg" => Some(5),
+            "smpte170m" => Some(6),
+            "smpte240m" => Some(7),
+            "film" => Some(8),
+            "bt2020" => Some(9),
+            "smpte428" => Some(10),
+            "smpte431" => Some(11),
+            "smpte432" => Some(12),
+            _ => None,
+        }
+    }
+
+    /// Transfer characteristics as defined by ITU-T H.273
+    pub fn transfer_code(&self) -> Option<u8> {
+        match self.transfer.as_deref()? {
+            "bt709" => Some(1),
+            "bt470m" => Some(4),
+            "bt470bg" => Some(5),
+            "smpte170m" => Some(6),
+            "smpte240m" => Some(7),
+            "linear" => Some(8),
+            "iec61966-2-1" => Some(13),
+            "bt2020-10" => Some(14),
+            "bt2020-12" => Some(15),
+            "smpte2084" => Some(16),
+            "arib-std-b67" => Some(18),
+            _ => None,
+        }
+    }
+}
+
+impl VideoInfo {
+    pub fn bit_depth(&self) -> u8 {
+        BIT_DEPTH_PATTERN
+            .captures(&self.pix_fmt)
+            .map(|captures| captures[1].parse().unwrap())
+            .unwrap_or(8)
+    }
+
+    /// Display aspect ratio of the frame after cropping
+    pub fn display_aspect_ratio(&self, cropped_width: u32, cropped_height: u32) -> f64 {
+        (f64::from(cropped_width) * f64::from(self.sar.0))
+            / (f64::from(cropped_height) * f64::from(self.sar.1))
+    }
+
+    /// The horizontal and vertical mod required by the chroma subsampling
+    pub fn chroma_mod(&self) -> (u32, u32) {
+        if self.pix_fmt.starts_with("yuv420") || self.pix_fmt.starts_with("yuvj420") {
+            (2, 2)
+        } else if self.pix_fmt.starts_with("yuv422") || self.pix_fmt.starts_with("yuvj422") {
+            (2, 1)
+        } else if self.pix_fmt.starts_with("yuv411") {
+            (4, 1)
+        } else if self.pix_fmt.starts_with("yuv410") {
+            (4, 4)
+        } else {
+            (1, 1)
+        }
+    }
+}
+
+pub fn get_video_info(path: &Path) -> Result<VideoInfo, String> {
+    let streams = get_streams_list(path)?;
+    let stream = streams
+        .iter()
+        .find(|stream| stream.get("codec_type").map(String::as_str) == Some("video"))
+        .ok_or_else(|| format!("No video stream found in {}", path.to_str().unwrap()))?;
+    let dimension = |key: &str| -> Result<u32, String> {
+        stream
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| format!("Could not read {} of {}", key, path.to_str().unwrap()))
+    };
+
+    let color = ColorInfo {
+        matrix: get_color_tag(stream, "color_space"),
+        range: get_color_tag(stream, "color_range"),
+        primaries: get_color_tag(stream, "color_primaries"),
+        transfer: get_color_tag(stream, "color_transfer"),
+    };
+    let hdr_metadata = if color.is_hdr() {
+        get_hdr_metadata(path)?
+    } else {
+        HdrMetadata::default()
+    };
+
+    Ok(VideoInfo {
+        width: dimension("width")?,
+        height: dimension("height")?,
+        pix_fmt: stream.get("pix_fmt").cloned().unwrap_or_default(),
+        sar: stream
+            .get("sample_aspect_ratio")
+            .and_then(|sar| parse_ratio(sar))
+            .unwrap_or((1, 1)),
+        duration: stream
+            .get("duration")
+            .and_then(|value| value.parse().ok())
+            .or_else(|| get_format_duration(path)),
+        frame_rate: stream
+            .get("r_frame_rate")
+            .and_then(|rate| parse_rational(rate))
+            .filter(|fps| *fps > 0f64),
+        color,
+        hdr_metadata,
+    })
+}
+
+/// Reads the HDR side data attached to the first video frame
+pub fn get_hdr_metadata(path: &Path) -> Result<HdrMetadata, String> {
+    let output = match Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-read_intervals",
+            "%+#1",
+            "-show_frames",
+            path.to_str().unwrap(),
+        ])
+        .output()
+    {
+        Ok(x) => x,
+        Err(x) => return Err(format!("{}", x)),
+    };
+
+    Ok(parse_hdr_side_data(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+fn parse_hdr_side_data(output: &str) -> HdrMetadata {
+    let mut metadata = HdrMetadata::default();
+    let mut side_data: HashMap<&str, &str> = HashMap::new();
+    for line in output.lines() {
+        if line == "[SIDE_DATA]" {
+            side_data.clear();
+        } else if line == "[/SIDE_DATA]" {
+            let value = |key: &str| side_data.get(key).and_then(|value| parse_rational(value));
+            match side_data.get("side_data_type").copied() {
+                Some("Mastering display metadata") => {
+                    let point = |x: &str, y: &str| Some((value(x)?, value(y)?));
+                    metadata.mastering_display = (|| {
+                        Some(MasteringDisplay {
+                            red: point("red_x", "red_y")?,
+                            green: point("green_x", "green_y")?,
+                            blue: point("blue_x", "blue_y")?,
+                            white_point: point("white_point_x", "white_point_y")?,
+                            min_luminance: value("min_luminance")?,
+                            max_luminance: value("max_luminance")?,
+                        })
+                    })();
+                }
+                Some("Content light level metadata") => {
+                    metadata.content_light_level = (|| {
+                        Some(ContentLightLevel {
+                            max_content: value("max_content")? as u32,
+                            max_average: value("max_average")? as u32,
+                        })
+                    })();
+                }
+                _ => (),
+            }
+        } else if let Some((key, value)) = line.split_once('=') {
+            side_data.insert(key, value);
+        }
+    }
+    metadata
+}
+
+/// Parses ffprobe's `num/den` rationals as well as plain numbers
+fn parse_rational(value: &str) -> Option<f64> {
+    match value.split_once('/') {
+        Some((num, den)) => {
+            let den: f64 = den.parse().ok()?;
+            if den == 0f64 {
+                return None;
+            }
+            Some(num.parse::<f64>().ok()? / den)
+        }
+        None => value.parse().ok(),
+    }
+}
+
+fn get_color_tag(stream: &HashMap<String, String>, key: &str) -> Option<String> {
+    stream
+        .get(key)
+        .filter(|value| !["unknown", "unspecified", "reserved"].contains(&value.as_str()))
+        .cloned()
+}
+
+/// The SMPTE timecode of the first frame, as tagged by e.g. MOV and MXF files
+pub fn get_start_timecode(path: &Path) -> Option<String> {
+    get_streams_list(path)
+        .ok()?
+        .into_iter()
+        .find_map(|mut stream| stream.remove("TAG:timecode"))
+}
+
+fn get_format_duration(path: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            path.to_str().unwrap(),
+        ])
+        .output()
+        .ok()?;
+    String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+}
+
+/// Parses ffprobe's `num:den` ratios, treating unknown (`0:1`, `N/A`) as `None`
+fn parse_ratio(ratio: &str) -> Option<(u32, u32)> {
+    let (num, den) = ratio.split_once(':')?;
+    let num: u32 = num.parse().ok()?;
+    let den: u32 = den.parse().ok()?;
+    if num == 0 || den == 0 {
+        return None;
+    }
+    Some((num, den))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hdr10_side_data() {
+        let output = "[FRAME]\nmedia_type=video\n[SIDE_DATA]\nside_data_type=Mastering display metadata\nred_x=34000/50000\nred_y=16000/50000\ngreen_x=13250/50000\ngreen_y=34500/50000\nblue_x=7500/50000\nblue_y=3000/50000\nwhite_point_x=15635/50000\nwhite_point_y=16450/50000\nmin_luminance=50/10000\nmax_luminance=10000000/10000\n[/SIDE_DATA]\n[SIDE_DATA]\nside_data_type=Content light level metadata\nmax_content=1000\nmax_average=400\n[/SIDE_DATA]\n[/FRAME]\n";
+        let metadata = parse_hdr_side_data(output);
+        assert_eq!(
+            metadata.mastering_display,
+            Some(MasteringDisplay {
+                red: (0.68, 0.32),
+                green: (0.265, 0.69),
+                blue: (0.15, 0.06),
+                white_point: (0.3127, 0.329),
+                min_luminance: 0.005,
+                max_luminance: 1000f64,
+            })
+        );
+        assert_eq!(
+            metadata.content_light_level,
+            Some(ContentLightLevel {
+                max_content: 1000,
+                max_average: 400,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_sdr_side_data() {
+        assert_eq!(
+            parse_hdr_side_data("[FRAME]\nmedia_type=video\n[/FRAME]\n"),
+            HdrMetadata::default()
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Size of a DVD sector, which IFO addresses count in
+pub const SECTOR_SIZE: u64 = 2048;
+
+/// A title as listed by `VIDEO_TS.IFO`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DvdTitle {
+    /// Starting at 1, as players number them
+    pub number: usize,
+    pub angles: u8,
+    pub chapter_count: u16,
+    /// The title set holding the title, i.e. `VTS_<vts>_*`
+    pub vts: u8,
+    /// The number of the title within its title set
+    pub vts_title: u8,
+}
+
+/// A cell of a title's program chain, addressed in sectors of the title set's VOBs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DvdCell {
+    pub first_sector: u32,
+    /// Inclusive
+    pub last_sector: u32,
+    pub frames: u64,
+}
+
+/// The cells a title plays, in order, and where its chapters start
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TitleLayout {
+    pub cells: Vec<DvdCell>,
+    /// The index of each chapter's first cell
+    pub chapter_cells: Vec<usize>,
+    /// 30000/1001 for NTSC, 25 for PAL
+    pub frame_rate: f64,
+}
+
+impl TitleLayout {
+    pub fn frame_count(&self) -> u64 {
+        self.cells.iter().map(|cell| cell.frames).sum()
+    }
+
+    /// The first frame of each chapter
+    pub fn chapter_frames(&self) -> Vec<u64> {
+        self.chapter_cells
+            .iter()
+            .map(|&cell| self.cells[..cell].iter().map(|cell| cell.frames).sum())
+            .collect()
+    }
+}
+
+fn read_ifo(path: &Path) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    Ok(data)
+}
+
+/// Lists the titles of a disc from `VIDEO_TS.IFO`
+pub fn read_titles(video_ts: &Path) -> Result<Vec<DvdTitle>, String> {
+    let path = video_ts.join("VIDEO_TS.IFO");
+    parse_titles(&read_ifo(&path)?)
+        .ok_or_else(|| format!("{} is not a valid video manager", path.to_str().unwrap()))
+}
+
+/// Reads the cells and chapters of a title from its title set's IFO
+pub fn read_title_layout(video_ts: &Path, title: &DvdTitle) -> Result<TitleLayout, String> {
+    let path = video_ts.join(format!("VTS_{:02}_0.IFO", title.vts));
+    parse_title_layout(&read_ifo(&path)?, title.vts_title).ok_or_else(|| {
+        format!(
+            "{} does not describe title {}",
+            path.to_str().unwrap(),
+            title.number
+        )
+    })
+}
+
+pub fn parse_titles(data: &[u8]) -> Option<Vec<DvdTitle>> {
+    if data.get(0..12)? != b"DVDVIDEO-VMG" {
+        return None;
+    }
+    let table = read_u32(data, 0xC4)? as usize * SECTOR_SIZE as usize;
+    let count = read_u16(data, table)? as usize;
+    (0..count)
+        .map(|i| {
+            let entry = table + 8 + i * 12;
+            Some(DvdTitle {
+                number: i + 1,
+                angles: *data.get(entry + 1)?,
+                chapter_count: read_u16(data, entry + 2)?,
+                vts: *data.get(entry + 6)?,
+                vts_title: *data.get(entry + 7)?,
+            })
+        })
+        .collect()
+}
+
+pub fn parse_title_layout(data: &[u8], vts_title: u8) -> Option<TitleLayout> {
+    if data.get(0..12)? != b"DVDVIDEO-VTS" {
+        return None;
+    }
+    let ptt_table = read_u32(data, 0xC8)? as usize * SECTOR_SIZE as usize;
+    let pgc_table = read_u32(data, 0xCC)? as usize * SECTOR_SIZE as usize;
+
+    // Each part of title (chapter) points at a program of a program chain
+    let title_count = read_u16(data, ptt_table)? as usize;
+    let title_index = (vts_title as usize).checked_sub(1)?;
+    if title_index >= title_count {
+        return None;
+    }
+    let ptt_start = ptt_table + read_u32(data, ptt_table + 8 + title_index * 4)? as usize;
+    let ptt_end = if title_index + 1 < title_count {
+        ptt_table + read_u32(data, ptt_table + 8 + (title_index + 1) * 4)? as usize
+    } else {
+        ptt_table + read_u32(data, ptt_table + 4)? as usize + 1
+    };
+    let parts: Vec<(usize, usize)> = (ptt_start..ptt_end)
+        .step_by(4)
+        .map(|part| {
+            Some((
+                read_u16(data, part)? as usize,
+                read_u16(data, part + 2)? as usize,
+            ))
+        })
+        .collect::<Option<_>>()?;
+
+    let mut layout = TitleLayout::default();
+    // The index into the layout of each chain's cells, by chain number
+    let mut chains: Vec<(usize, Vec<usize>)> = Vec::new();
+    for (pgc_number, program) in parts {
+        let srp = pgc_table + 8 + pgc_number.checked_sub(1)? * 8;
+        let pgc = pgc_table + read_u32(data, srp + 4)? as usize;
+        // Titles spanning several program chains play them in order
+        if !chains.iter().any(|(number, _)| *number == pgc_number) {
+            let (frame_rate, cell_indices) = read_pgc_cells(data, pgc, &mut layout.cells)?;
+            layout.frame_rate = frame_rate;
+            chains.push((pgc_number, cell_indices));
+        }
+        let cell_indices = &chains.iter().find(|(number, _)| *number == pgc_number)?.1;
+        let program_map = pgc + read_u16(data, pgc + 0xE6)? as usize;
+        let entry_cell = *data.get(program_map + program.checked_sub(1)?)? as usize;
+        layout
+            .chapter_cells
+            .push(*cell_indices.get(entry_cell.checked_sub(1)?)?);
+    }
+    if layout.cells.is_empty() {
+        return None;
+    }
+    Some(layout)
+}
+
+/// Appends the cells of a program chain, leaving out all but the first angle.
+/// Returns the frame rate and where each of the chain's cells ended up, or
+/// `None` if a cell ends before it starts.
+fn read_pgc_cells(data: &[u8], pgc: usize, cells: &mut Vec<DvdCell>) -> Option<(f64, Vec<usize>)> {
+    let cell_count = *data.get(pgc + 3)? as usize;
+    let (_, frame_rate) = read_dvd_time(data, pgc + 4)?;
+    let playback = pgc + read_u16(data, pgc + 0xE8)? as usize;
+    let mut cell_indices = Vec::with_capacity(cell_count);
+    for i in 0..cell_count {
+        let cell = playback + i * 24;
+        let flags = *data.get(cell)?;
+        let block_mode = flags >> 6;
+        let block_type = (flags >> 4) & 0b11;
+        cell_indices.push(cells.len());
+        // Angle blocks hold one cell per angle, of which only the first is played
+        if block_type == 1 && block_mode != 1 {
+            continue;
+        }
+        let first_sector = read_u32(data, cell + 8)?;
+        let last_sector = read_u32(data, cell + 20)?;
+        if last_sector < first_sector {
+            return None;
+        }
+        cells.push(DvdCell {
+            first_sector,
+            last_sector,
+            frames: read_dvd_time(data, cell + 4)?.0,
+        });
+    }
+    Some((frame_rate, cell_indices))
+}
+
+/// Reads a BCD playback time, returning the number of frames and the frame rate
+fn read_dvd_time(data: &[u8], offset: usize) -> Option<(u64, f64)> {
+    let bytes = data.get(offset..offset + 4)?;
+    let bcd = |byte: u8| u64::from(byte >> 4) * 10 + u64::from(byte & 0x0f);
+    let (nominal, frame_rate) = match bytes[3] >> 6 {
+        0b01 => (25, 25f64),
+        _ => (30, 30_000f64 / 1_001f64),
+    };
+    let seconds = bcd(bytes[0]) * 3600 + bcd(bytes[1]) * 60 + bcd(bytes[2]);
+    Some((seconds * nominal + bcd(bytes[3] & 0x3f), frame_rate))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn build_vmg() -> Vec<u8> {
+        let mut data = vec![0u8; 2 * SECTOR_SIZE as usize];
+        data[0..12].copy_from_slice(b"DVDVIDEO-VMG");
+        put_u32(&mut data, 0xC4, 1);
+        let table = SECTOR_SIZE as usize;
+        put_u16(&mut data, table, 2);
+        for (i, (angles, chapters, vts, vts_title)) in
+            [(1u8, 3u16, 1u8, 1u8), (2, 1, 2, 1)].iter().enumerate()
+        {
+            let entry = table + 8 + i * 12;
+            data[entry + 1] = *angles;
+            put_u16(&mut data, entry + 2, *chapters);
+            data[entry + 6] = *vts;
+            data[entry + 7] = *vts_title;
+        }
+        data
+    }
+
+    /// One title of three chapters over four cells, the third being the
+    /// second angle of an angle block
+    fn build_vts() -> Vec<u8> {
+        let mut data = vec![0u8; 3 * SECTOR_SIZE as usize];
+        data[0..12].copy_from_slice(b"DVDVIDEO-VTS");
+        put_u32(&mut data, 0xC8, 1);
+        put_u32(&mut data, 0xCC, 2);
+
+        let ptt = SECTOR_SIZE as usize;
+        put_u16(&mut data, ptt, 1);
+        put_u32(&mut data, ptt + 4, 12 + 3 * 4 - 1);
+        put_u32(&mut data, ptt + 8, 12);
+        for (i, program) in [1u16, 2, 3].iter().enumerate() {
+            put_u16(&mut data, ptt + 12 + i * 4, 1);
+            put_u16(&mut data, ptt + 12 + i * 4 + 2, *program);
+        }
+
+        let pgci = 2 * SECTOR_SIZE as usize;
+        put_u16(&mut data, pgci, 1);
+        put_u32(&mut data, pgci + 12, 16);
+        let pgc = pgci + 16;
+        data[pgc + 2] = 3;
+        data[pgc + 3] = 5;
+        data[pgc + 4..pgc + 8].copy_from_slice(&[0x00, 0x01, 0x00, 0xc0]);
+        put_u16(&mut data, pgc + 0xE6, 0xEC);
+        put_u16(&mut data, pgc + 0xE8, 0xF0);
+        data[pgc + 0xEC..pgc + 0xEF].copy_from_slice(&[1, 2, 5]);
+        let cells = [
+            (0x00u8, [0x00, 0x00, 0x10, 0xc0], 0u32, 499u32),
+            (0x00, [0x00, 0x00, 0x20, 0xc0], 500, 1499),
+            (0x50, [0x00, 0x00, 0x05, 0xc0], 1500, 1599),
+            (0xd0, [0x00, 0x00, 0x05, 0xc0], 1600, 1699),
+            (0x00, [0x00, 0x00, 0x29, 0xd5], 1700, 2999),
+        ];
+        for (i, (flags, time, first, last)) in cells.iter().enumerate() {
+            let cell = pgc + 0xF0 + i * 24;
+            data[cell] = *flags;
+            data[cell + 4..cell + 8].copy_from_slice(time);
+            put_u32(&mut data, cell + 8, *first);
+            put_u32(&mut data, cell + 20, *last);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_video_manager() {
+        let titles = parse_titles(&build_vmg()).unwrap();
+        assert_eq!(titles.len(), 2);
+        assert_eq!(
+            titles[1],
+            DvdTitle {
+                number: 2,
+                angles: 2,
+                chapter_count: 1,
+                vts: 2,
+                vts_title: 1,
+            }
+        );
+        assert!(parse_titles(&build_vts()).is_none());
+    }
+
+    #[test]
+    fn parse_title_set() {
+        let layout = parse_title_layout(&build_vts(), 1).unwrap();
+        assert_eq!(
+            layout
+                .cells
+                .iter()
+                .map(|cell| cell.frames)
+                .collect::<Vec<u64>>(),
+            vec![300, 600, 150, 885]
+        );
+        assert_eq!(layout.cells[2].first_sector, 1500);
+        assert_eq!(layout.cells[3].last_sector, 2999);
+        assert_eq!(layout.chapter_cells, vec![0, 1, 3]);
+        assert_eq!(layout.chapter_frames(), vec![0, 300, 1050]);
+        assert_eq!(layout.frame_count(), 1935);
+        assert_eq!(layout.frame_rate, 30_000f64 / 1_001f64);
+        assert!(parse_title_layout(&build_vts(), 2).is_none());
+
+        let mut inverted = build_vts();
+        put_u32(
+            &mut inverted,
+            2 * SECTOR_SIZE as usize + 16 + 0xF0 + 24 + 20,
+            400,
+        );
+        assert!(parse_title_layout(&inverted, 1).is_none());
+    }
+}
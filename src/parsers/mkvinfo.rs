@@ -1,6 +1,5 @@
-use std::collections::HashMap;
-use std::error::Error;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
 
 use lazy_static::lazy_static;
@@ -8,7 +7,8 @@ use regex::Regex;
 use uuid::Uuid;
 
 lazy_static! {
-    static ref ATTACHMENT_PATTERN: Regex = Regex::new(r"Attachment ID (\d+): .* file name '(.+)'").unwrap();
+    static ref ATTACHMENT_PATTERN: Regex =
+        Regex::new(r"^Attachment ID (\d+): type '([^']*)', size (\d+) bytes,.* file name '(.*)'$").unwrap();
     static ref SEGMENT_UUID_REGEX: Regex = Regex::new(
         r"Segment UID: 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2}) 0x([0-9a-f]{2})"
     ).unwrap();
@@ -16,6 +16,7 @@ lazy_static! {
         .unwrap();
     static ref EDITION_FLAG_ORDERED_REGEX: Regex = Regex::new(r"Edition ?[fF]lag ?[oO]rdered: 1").unwrap();
     static ref CHAPTER_ATOM_REGEX: Regex = Regex::new(r"Chapter ?[aA]tom").unwrap();
+    static ref CHAPTER_STRING_REGEX: Regex = Regex::new(r"Chapter ?[sS]tring: (.*)$").unwrap();
     static ref TIME_START_REGEX: Regex = Regex::new(r"Chapter ?[tT]ime ?[sS]tart: (\d{2}):(\d{2}):(\d{2}).(\d{9})")
         .unwrap();
     static ref TIME_END_REGEX: Regex = Regex::new(r"Chapter ?[tT]ime ?[eE]nd: (\d{2}):(\d{2}):(\d{2}).(\d{9})").unwrap();
@@ -25,38 +26,100 @@ lazy_static! {
     static ref EBML_VOID_REGEX: Regex = Regex::new(r"(?:Ebml|EBML) ?[vV]oid").unwrap();
 }
 
-pub fn get_fonts_list(path: &Path) -> Result<HashMap<usize, String>, String> {
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub id: usize,
+    pub mime_type: String,
+    pub size: u64,
+    pub file_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachmentKind {
+    Font,
+    Other,
+    /// Generic MIME type and no telling extension, only the contents can tell
+    Unknown,
+}
+
+impl Attachment {
+    /// The file name without any directories, so that it cannot point outside
+    /// of where it is extracted to. `None` if nothing usable is left.
+    pub fn base_name(&self) -> Option<&str> {
+        match self.file_name.rsplit(['/', '\\']).next()? {
+            "" | "." | ".." => None,
+            name => Some(name),
+        }
+    }
+
+    /// Classifies the attachment by its MIME type, then by its extension
+    pub fn kind(&self) -> AttachmentKind {
+        let mime_type = self.mime_type.to_lowercase();
+        if mime_type.starts_with("font/")
+            || matches!(
+                mime_type.as_str(),
+                "application/x-truetype-font"
+                    | "application/x-font-ttf"
+                    | "application/x-font-otf"
+                    | "application/x-font-opentype"
+                    | "application/x-font"
+                    | "application/font-sfnt"
+                    | "application/vnd.ms-opentype"
+            )
+        {
+            return AttachmentKind::Font;
+        }
+        let extension = Path::new(&self.file_name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("ttf") | Some("otf") | Some("ttc") | Some("otc") => AttachmentKind::Font,
+            _ if mime_type.is_empty() || mime_type == "application/octet-stream" => {
+                AttachmentKind::Unknown
+            }
+            _ => AttachmentKind::Other,
+        }
+    }
+}
+
+pub fn get_attachments(path: &Path) -> Result<Vec<Attachment>, String> {
     let output = match Command::new("mkvmerge")
-        .args(&["-i", path.to_str().unwrap()])
+        .args(["-i", path.to_str().unwrap()])
         .output()
     {
         Ok(x) => x,
         Err(x) => return Err(format!("{}", x)),
     };
 
-    let mut attachments: HashMap<usize, String> = HashMap::new();
-    for line in String::from_utf8(output.stdout).unwrap().lines() {
-        if line.starts_with("Attachment")
-            && (line.to_lowercase().contains(".ttf") || line.to_lowercase().contains(".otf"))
-        {
-            let captures = ATTACHMENT_PATTERN.captures(line).unwrap();
-            attachments.insert(
-                captures[1].parse::<usize>().unwrap(),
-                captures[2].to_owned(),
-            );
-        }
-    }
+    Ok(parse_attachments(&String::from_utf8_lossy(&output.stdout)))
+}
 
-    Ok(attachments)
+fn parse_attachments(output: &str) -> Vec<Attachment> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("Attachment"))
+        .filter_map(|line| match ATTACHMENT_PATTERN.captures(line) {
+            Some(captures) => Some(Attachment {
+                id: captures[1].parse().ok()?,
+                mime_type: captures[2].to_owned(),
+                size: captures[3].parse().ok()?,
+                file_name: captures[4].to_owned(),
+            }),
+            None => {
+                eprintln!("Warning: could not parse attachment: {}", line);
+                None
+            }
+        })
+        .collect()
 }
 
 pub fn get_file_uuid(path: &Path) -> Result<Uuid, String> {
     let output = match Command::new("mkvinfo")
-        .args(&[path.to_str().unwrap()])
+        .args([path.to_str().unwrap()])
         .output()
     {
         Ok(x) => x,
-        Err(x) => return Err(x.description().to_owned()),
+        Err(x) => return Err(x.to_string()),
     };
 
     let output = String::from_utf8(output.stdout).unwrap();
@@ -87,34 +150,52 @@ enum SectionType {
     Chapters,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct BreakPoint {
     pub start_frame: u64,
     pub end_frame: u64,
     pub foreign_uuid: Option<Uuid>,
+    /// Another file referenced by its path rather than its Segment UID, as by EDLs
+    pub foreign_file: Option<PathBuf>,
 }
 
-pub fn get_ordered_chapters_list(
-    path: &Path,
-    force_120_fps: bool,
-) -> Result<Option<Vec<BreakPoint>>, String> {
+/// A chapter of the first edition, with times converted to frames
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chapter {
+    pub name: Option<String>,
+    pub start_frame: u64,
+    /// Inclusive, `None` for a last chapter that runs to the end of the file
+    pub end_frame: Option<u64>,
+    pub foreign_uuid: Option<Uuid>,
+    /// Another file referenced by its path, as by Blu-ray playlists
+    pub foreign_file: Option<PathBuf>,
+}
+
+/// Reads the chapters of a file, and whether they are ordered
+pub fn get_chapters(path: &Path, force_120_fps: bool) -> Result<(Vec<Chapter>, bool), String> {
     let output = match Command::new("mkvinfo")
-        .args(&[path.to_str().unwrap()])
+        .args([path.to_str().unwrap()])
         .output()
     {
         Ok(x) => x,
-        Err(x) => return Err(x.description().to_owned()),
+        Err(x) => return Err(x.to_string()),
     };
 
-    let output = String::from_utf8(output.stdout).unwrap();
-    let mut chapters: Vec<BreakPoint> = Vec::new();
+    Ok(parse_chapters(
+        &String::from_utf8(output.stdout).unwrap(),
+        force_120_fps,
+    ))
+}
+
+fn parse_chapters(output: &str, force_120_fps: bool) -> (Vec<Chapter>, bool) {
+    let mut chapters: Vec<Chapter> = Vec::new();
     let mut video_fps: Option<f64> = if force_120_fps {
         Some(120_000. / 1_001.)
     } else {
         None
     };
     let mut current_section: Option<SectionType> = None;
-    let mut current_chapter: Option<BreakPoint> = None;
+    let mut current_chapter: Option<Chapter> = None;
     let mut ordered_chapters = false;
     for line in output.lines() {
         // Find video_fps
@@ -134,19 +215,21 @@ pub fn get_ordered_chapters_list(
                 continue;
             }
             if CHAPTER_ATOM_REGEX.is_match(line) {
-                if let Some(chapter) = current_chapter {
+                if let Some(chapter) = current_chapter.take() {
                     chapters.push(chapter);
                 }
-                current_chapter = Some(BreakPoint {
+                current_chapter = Some(Chapter {
+                    name: None,
                     start_frame: 0,
-                    end_frame: 0,
+                    end_frame: None,
                     foreign_uuid: None,
+                    foreign_file: None,
                 });
                 continue;
             }
-            if current_chapter.is_some() {
+            if let Some(chapter) = current_chapter.as_mut() {
                 if let Some(captures) = TIME_START_REGEX.captures(line) {
-                    current_chapter.as_mut().unwrap().start_frame = timestamp_to_frame_number(
+                    chapter.start_frame = timestamp_to_frame_number(
                         captures[1].parse::<u64>().unwrap(),
                         captures[2].parse::<u64>().unwrap(),
                         captures[3].parse::<f64>().unwrap()
@@ -156,17 +239,27 @@ pub fn get_ordered_chapters_list(
                     continue;
                 }
                 if let Some(captures) = TIME_END_REGEX.captures(line) {
-                    current_chapter.as_mut().unwrap().end_frame = timestamp_to_frame_number(
-                        captures[1].parse::<u64>().unwrap(),
-                        captures[2].parse::<u64>().unwrap(),
-                        captures[3].parse::<f64>().unwrap()
-                            + captures[4].parse::<f64>().unwrap() / 1_000_000_000f64,
-                        video_fps.unwrap(),
-                    ) - 1;
+                    chapter.end_frame = Some(
+                        timestamp_to_frame_number(
+                            captures[1].parse::<u64>().unwrap(),
+                            captures[2].parse::<u64>().unwrap(),
+                            captures[3].parse::<f64>().unwrap()
+                                + captures[4].parse::<f64>().unwrap() / 1_000_000_000f64,
+                            video_fps.unwrap(),
+                        )
+                        .saturating_sub(1),
+                    );
+                    continue;
+                }
+                if let Some(captures) = CHAPTER_STRING_REGEX.captures(line) {
+                    // Only the first language is kept
+                    if chapter.name.is_none() {
+                        chapter.name = Some(captures[1].to_owned());
+                    }
                     continue;
                 }
                 if let Some(captures) = FOREIGN_UUID_REGEX.captures(line) {
-                    current_chapter.as_mut().unwrap().foreign_uuid = Some(
+                    chapter.foreign_uuid = Some(
                         Uuid::parse_str(
                             &captures
                                 .iter()
@@ -182,7 +275,7 @@ pub fn get_ordered_chapters_list(
                 }
             }
             if EBML_VOID_REGEX.is_match(line) {
-                if let Some(chapter) = current_chapter {
+                if let Some(chapter) = current_chapter.take() {
                     chapters.push(chapter);
                 }
                 break;
@@ -192,48 +285,244 @@ pub fn get_ordered_chapters_list(
             continue;
         }
     }
+    if let Some(chapter) = current_chapter {
+        chapters.push(chapter);
+    }
 
-    if !ordered_chapters {
-        return Ok(None);
+    // Ordinary chapters often only have a start time
+    for i in 1..chapters.len() {
+        if chapters[i - 1].end_frame.is_none() && chapters[i - 1].foreign_uuid.is_none() {
+            chapters[i - 1].end_frame = Some(chapters[i].start_frame.saturating_sub(1));
+        }
     }
 
-    // Merge chapters
+    (chapters, ordered_chapters)
+}
+
+/// Ends a last chapter which runs to the end of the file, given the number
+/// of frames in the file
+pub fn end_last_chapter(chapters: &mut [Chapter], total_frames: Option<u64>) {
+    if let Some(last) = chapters
+        .last_mut()
+        .filter(|last| last.end_frame.is_none() && last.foreign_uuid.is_none())
+    {
+        last.end_frame = total_frames
+            .and_then(|total_frames| total_frames.checked_sub(1))
+            .filter(|&end_frame| end_frame >= last.start_frame);
+    }
+}
+
+/// Joins consecutive chapters of the same file into one breakpoint each.
+/// Chapters of the file itself need an end, see `end_last_chapter`.
+pub fn merge_chapters(chapters: &[Chapter]) -> Result<Vec<BreakPoint>, String> {
     let mut breakpoints: Vec<BreakPoint> = Vec::new();
-    let mut iter = chapters.iter().peekable();
-    let mut merging = BreakPoint {
-        start_frame: 0,
-        end_frame: 0,
-        foreign_uuid: None,
-    };
-    while let Some(chapter) = iter.next() {
+    let mut merging: Option<BreakPoint> = None;
+    for chapter in chapters {
         if chapter.foreign_uuid.is_some() {
-            breakpoints.push(chapter.clone());
+            breakpoints.extend(merging.take());
+            breakpoints.push(BreakPoint {
+                start_frame: chapter.start_frame,
+                // Trimming to frame 0 plays to the end of the linked file
+                end_frame: chapter.end_frame.unwrap_or(0),
+                foreign_uuid: chapter.foreign_uuid,
+                foreign_file: None,
+            });
             continue;
         }
-        if merging.end_frame == 0 {
-            merging.start_frame = chapter.start_frame;
-        }
-        merging.end_frame = chapter.end_frame;
-        if let Some(next_chapter) = iter.peek() {
-            if next_chapter.foreign_uuid.is_some() && merging.end_frame > 0 {
-                breakpoints.push(merging);
-                merging = BreakPoint {
-                    start_frame: 0,
-                    end_frame: 0,
-                    foreign_uuid: None,
-                };
+        let end_frame = chapter.end_frame.ok_or_else(|| {
+            format!(
+                "Could not find where the chapter at frame {} ends",
+                chapter.start_frame
+            )
+        })?;
+        match merging {
+            // Chapters only join up if they follow on from each other in the same file
+            Some(ref mut last)
+                if last.foreign_file == chapter.foreign_file
+                    && last.end_frame + 1 == chapter.start_frame =>
+            {
+                last.end_frame = end_frame;
             }
-        } else {
-            if merging.end_frame > 0 {
-                breakpoints.push(merging);
+            _ => {
+                breakpoints.extend(merging.take());
+                merging = Some(BreakPoint {
+                    start_frame: chapter.start_frame,
+                    end_frame,
+                    foreign_uuid: None,
+                    foreign_file: chapter.foreign_file.clone(),
+                });
             }
-            break;
         }
     }
+    breakpoints.extend(merging);
 
-    Ok(Some(breakpoints))
+    Ok(breakpoints)
 }
 
 fn timestamp_to_frame_number(hours: u64, minutes: u64, seconds: f64, fps: f64) -> u64 {
     ((seconds + 60f64 * minutes as f64 + 3600f64 * hours as f64) * fps).floor() as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_attachments() {
+        let output = "File 'ep01.mkv': container: Matroska\n\
+            Track ID 0: video (AVC/H.264/MPEG-4p10)\n\
+            Attachment ID 1: type 'application/x-truetype-font', size 57684 bytes, file name 'FONT1'\n\
+            Attachment ID 2: type 'font/collection', size 1024 bytes, file name 'NotoSansCJK.ttc'\n\
+            Attachment ID 3: type 'application/octet-stream', size 2048 bytes, description 'font, it's odd', file name 'fnt.bin'\n\
+            Attachment ID 4: type 'image/jpeg', size 4096 bytes, file name 'cover.jpg'\n\
+            Attachment ID 5: type 'application/octet-stream', size 512 bytes, file name 'Some Font.OTF'\n\
+            Attachment ID garbage\n";
+        let kinds: Vec<(usize, AttachmentKind)> = parse_attachments(output)
+            .iter()
+            .map(|attachment| (attachment.id, attachment.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, AttachmentKind::Font),
+                (2, AttachmentKind::Font),
+                (3, AttachmentKind::Unknown),
+                (4, AttachmentKind::Other),
+                (5, AttachmentKind::Font),
+            ]
+        );
+    }
+
+    #[test]
+    fn attachment_base_names() {
+        let output = "Attachment ID 1: type 'font/ttf', size 10 bytes, file name '../../.bashrc'\n\
+            Attachment ID 2: type 'font/ttf', size 20 bytes, file name 'C:\\Fonts\\arial.ttf'\n\
+            Attachment ID 3: type 'font/ttf', size 30 bytes, file name 'fonts/..'\n";
+        let attachments = parse_attachments(output);
+        assert_eq!(
+            attachments
+                .iter()
+                .map(|attachment| (attachment.size, attachment.base_name()))
+                .collect::<Vec<_>>(),
+            vec![(10, Some(".bashrc")), (20, Some("arial.ttf")), (30, None)]
+        );
+    }
+
+    const ORDERED_CHAPTERS: &str = "|+ Segment information\n\
+        |  + Track type: video\n\
+        |  + Default duration: 00:00:00.041708333 (23.976 frames/fields per second for a video track)\n\
+        |+ Chapters\n\
+        | + Edition entry\n\
+        |  + Edition flag ordered: 1\n\
+        |  + Chapter atom\n\
+        |   + Chapter time start: 00:00:00.000000000\n\
+        |   + Chapter time end: 00:00:10.011000000\n\
+        |   + Chapter display\n\
+        |    + Chapter string: Prologue\n\
+        |  + Chapter atom\n\
+        |   + Chapter time start: 00:00:00.000000000\n\
+        |   + Chapter time end: 00:01:30.048000000\n\
+        |   + Chapter segment UID: length 16, data: 0x00 0x11 0x22 0x33 0x44 0x55 0x66 0x77 0x88 0x99 0xaa 0xbb 0xcc 0xdd 0xee 0xff\n\
+        |   + Chapter display\n\
+        |    + Chapter string: Opening\n\
+        |  + Chapter atom\n\
+        |   + Chapter time start: 00:00:10.011000000\n\
+        |   + Chapter time end: 00:00:20.021000000\n\
+        |   + Chapter display\n\
+        |    + Chapter string: Part A\n\
+        |  + Chapter atom\n\
+        |   + Chapter time start: 00:00:20.021000000\n\
+        |   + Chapter time end: 00:00:30.031000000\n\
+        |   + Chapter display\n\
+        |    + Chapter string: Part B\n\
+        |+ EBML void: size 100\n";
+
+    #[test]
+    fn parse_ordered_chapters() {
+        let (chapters, ordered) = parse_chapters(ORDERED_CHAPTERS, false);
+        assert!(ordered);
+        let names: Vec<Option<&str>> = chapters
+            .iter()
+            .map(|chapter| chapter.name.as_deref())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                Some("Prologue"),
+                Some("Opening"),
+                Some("Part A"),
+                Some("Part B")
+            ]
+        );
+        assert_eq!(chapters[2].start_frame, 240);
+        assert_eq!(chapters[2].end_frame, Some(479));
+
+        let breakpoints: Vec<(u64, u64, bool)> = merge_chapters(&chapters)
+            .unwrap()
+            .iter()
+            .map(|breakpoint| {
+                (
+                    breakpoint.start_frame,
+                    breakpoint.end_frame,
+                    breakpoint.foreign_uuid.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            breakpoints,
+            vec![(0, 239, false), (0, 2157, true), (240, 719, false)]
+        );
+    }
+
+    #[test]
+    fn fill_in_missing_chapter_ends() {
+        let output = ORDERED_CHAPTERS
+            .replace("|  + Edition flag ordered: 1\n", "")
+            .lines()
+            .filter(|line| !line.contains("time end") && !line.contains("segment UID"))
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let (chapters, ordered) = parse_chapters(&output, false);
+        assert!(!ordered);
+        let ends: Vec<Option<u64>> = chapters.iter().map(|chapter| chapter.end_frame).collect();
+        assert_eq!(ends, vec![Some(0), Some(239), Some(479), None]);
+    }
+
+    #[test]
+    fn merge_only_chapters_which_follow_on() {
+        let chapter = |start_frame, end_frame| Chapter {
+            name: None,
+            start_frame,
+            end_frame: Some(end_frame),
+            foreign_uuid: None,
+            foreign_file: None,
+        };
+        let breakpoints: Vec<(u64, u64)> =
+            merge_chapters(&[chapter(0, 239), chapter(240, 479), chapter(960, 1199)])
+                .unwrap()
+                .iter()
+                .map(|breakpoint| (breakpoint.start_frame, breakpoint.end_frame))
+                .collect();
+        assert_eq!(breakpoints, vec![(0, 479), (960, 1199)]);
+    }
+
+    #[test]
+    fn merge_last_chapter_to_end_of_file() {
+        let chapter = |start_frame, end_frame| Chapter {
+            name: None,
+            start_frame,
+            end_frame,
+            foreign_uuid: None,
+            foreign_file: None,
+        };
+        let mut chapters = vec![chapter(0, Some(239)), chapter(240, None)];
+        assert!(merge_chapters(&chapters).is_err());
+        end_last_chapter(&mut chapters, Some(1200));
+        let breakpoints: Vec<(u64, u64)> = merge_chapters(&chapters)
+            .unwrap()
+            .iter()
+            .map(|breakpoint| (breakpoint.start_frame, breakpoint.end_frame))
+            .collect();
+        assert_eq!(breakpoints, vec![(0, 1199)]);
+    }
+}
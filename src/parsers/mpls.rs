@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Ticks per second of MPLS timestamps
+pub const MPLS_CLOCK: f64 = 45_000f64;
+
+/// A Blu-ray playlist, which plays parts of clips one after another
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Playlist {
+    pub items: Vec<PlayItem>,
+    pub marks: Vec<PlaylistMark>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayItem {
+    /// The five digit name of the clip, e.g. `00001` for `STREAM/00001.m2ts`
+    pub clip_name: String,
+    /// In 45kHz ticks on the clip's own timeline
+    pub in_time: u32,
+    /// In 45kHz ticks on the clip's own timeline, exclusive
+    pub out_time: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistMark {
+    /// Entry marks are chapters, link points only matter to players
+    pub is_entry: bool,
+    /// Index into the play items
+    pub play_item: usize,
+    /// In 45kHz ticks on the play item's clip timeline
+    pub time: u32,
+}
+
+pub fn read_mpls(path: &Path) -> Result<Playlist, String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    parse_mpls(&data).ok_or_else(|| format!("{} is not a valid playlist", path.to_str().unwrap()))
+}
+
+/// Playlists live in `BDMV/PLAYLIST`, next to the clips in `BDMV/STREAM`
+pub fn clip_path(playlist_path: &Path, clip_name: &str) -> PathBuf {
+    let bdmv = playlist_path
+        .parent()
+        .and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""));
+    bdmv.join("STREAM").join(format!("{}.m2ts", clip_name))
+}
+
+pub fn parse_mpls(data: &[u8]) -> Option<Playlist> {
+    if data.get(0..4)? != b"MPLS" {
+        return None;
+    }
+    let playlist_start = read_u32(data, 8)? as usize;
+    let marks_start = read_u32(data, 12)? as usize;
+
+    let item_count = read_u16(data, playlist_start + 6)? as usize;
+    let mut items = Vec::with_capacity(item_count);
+    let mut offset = playlist_start + 10;
+    for _ in 0..item_count {
+        let length = read_u16(data, offset)? as usize;
+        let clip_name = String::from_utf8(data.get(offset + 2..offset + 7)?.to_vec()).ok()?;
+        items.push(PlayItem {
+            clip_name,
+            in_time: read_u32(data, offset + 14)?,
+            out_time: read_u32(data, offset + 18)?,
+        });
+        offset += 2 + length;
+    }
+
+    let mark_count = read_u16(data, marks_start + 4)? as usize;
+    let marks = (0..mark_count)
+        .map(|i| {
+            let mark = marks_start + 6 + i * 14;
+            Some(PlaylistMark {
+                is_entry: *data.get(mark + 1)? == 1,
+                play_item: read_u16(data, mark + 2)? as usize,
+                time: read_u32(data, mark + 4)?,
+            })
+        })
+        .collect::<Option<Vec<PlaylistMark>>>()?;
+    Some(Playlist { items, marks })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a playlist with the given `(clip, in, out)` items and
+    /// `(type, item, time)` marks
+    fn build_mpls(items: &[(&str, u32, u32)], marks: &[(u8, u16, u32)]) -> Vec<u8> {
+        let mut playlist = Vec::new();
+        playlist.extend_from_slice(&[0, 0]);
+        playlist.extend_from_slice(&(items.len() as u16).to_be_bytes());
+        playlist.extend_from_slice(&0u16.to_be_bytes());
+        for (clip, in_time, out_time) in items {
+            let mut item = Vec::new();
+            item.extend_from_slice(clip.as_bytes());
+            item.extend_from_slice(b"M2TS");
+            item.extend_from_slice(&[0, 1, 0]);
+            item.extend_from_slice(&in_time.to_be_bytes());
+            item.extend_from_slice(&out_time.to_be_bytes());
+            // UO mask, random access flag, still mode and the stream table
+            item.extend_from_slice(&[0; 12]);
+            playlist.extend_from_slice(&(item.len() as u16).to_be_bytes());
+            playlist.extend_from_slice(&item);
+        }
+        let mut mark_table = Vec::new();
+        mark_table.extend_from_slice(&(marks.len() as u16).to_be_bytes());
+        for (mark_type, item, time) in marks {
+            mark_table.push(0);
+            mark_table.push(*mark_type);
+            mark_table.extend_from_slice(&item.to_be_bytes());
+            mark_table.extend_from_slice(&time.to_be_bytes());
+            mark_table.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+        }
+
+        let playlist_start = 40u32;
+        let marks_start = playlist_start + 4 + playlist.len() as u32;
+        let mut data = Vec::new();
+        data.extend_from_slice(b"MPLS0200");
+        data.extend_from_slice(&playlist_start.to_be_bytes());
+        data.extend_from_slice(&marks_start.to_be_bytes());
+        data.resize(playlist_start as usize, 0);
+        data.extend_from_slice(&(playlist.len() as u32).to_be_bytes());
+        data.extend_from_slice(&playlist);
+        data.extend_from_slice(&(mark_table.len() as u32).to_be_bytes());
+        data.extend_from_slice(&mark_table);
+        data
+    }
+
+    #[test]
+    fn parse_playlist() {
+        let data = build_mpls(
+            &[("00001", 27_000, 1_377_000), ("00002", 27_000, 4_077_000)],
+            &[
+                (1, 0, 27_000),
+                (2, 0, 500_000),
+                (1, 1, 27_000),
+                (1, 1, 2_727_000),
+            ],
+        );
+        let playlist = parse_mpls(&data).unwrap();
+        assert_eq!(
+            playlist.items,
+            vec![
+                PlayItem {
+                    clip_name: "00001".to_owned(),
+                    in_time: 27_000,
+                    out_time: 1_377_000,
+                },
+                PlayItem {
+                    clip_name: "00002".to_owned(),
+                    in_time: 27_000,
+                    out_time: 4_077_000,
+                },
+            ]
+        );
+        assert_eq!(playlist.marks.len(), 4);
+        assert!(!playlist.marks[1].is_entry);
+        assert_eq!(playlist.marks[3].play_item, 1);
+        assert_eq!(playlist.marks[3].time, 2_727_000);
+        assert!(parse_mpls(b"MPLS0200").is_none());
+        assert_eq!(
+            clip_path(Path::new("disc/BDMV/PLAYLIST/00000.mpls"), "00001"),
+            PathBuf::from("disc/BDMV/STREAM/00001.m2ts")
+        );
+    }
+}
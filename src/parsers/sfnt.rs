@@ -0,0 +1,244 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The names and style of one face in a TrueType/OpenType file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FontFace {
+    /// Family, full and typographic family names, in every language present
+    pub names: Vec<String>,
+    /// `usWeightClass` from the OS/2 table, 400 being regular and 700 bold
+    pub weight: u16,
+    pub italic: bool,
+}
+
+impl FontFace {
+    pub fn is_bold(&self) -> bool {
+        self.weight >= 600
+    }
+
+    pub fn has_name(&self, family: &str) -> bool {
+        let family = family.to_lowercase();
+        self.names.iter().any(|name| name.to_lowercase() == family)
+    }
+}
+
+/// Reads every face of a font file or collection
+pub fn read_font_faces(path: &Path) -> Result<Vec<FontFace>, String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    parse_font_faces(&data).ok_or_else(|| format!("{} is not a valid font", path.to_str().unwrap()))
+}
+
+/// Tells fonts apart from other files by their magic bytes, returning the
+/// extension the font should have
+pub fn sniff_font_type(header: &[u8]) -> Option<&'static str> {
+    match header.get(0..4)? {
+        b"ttcf" => Some("ttc"),
+        b"OTTO" => Some("otf"),
+        [0, 1, 0, 0] | b"true" => Some("ttf"),
+        _ => None,
+    }
+}
+
+pub fn sniff_font_file(path: &Path) -> Result<Option<&'static str>, String> {
+    let mut header = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .map(|_| sniff_font_type(&header))
+        .or_else(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(format!("{}: {}", path.to_str().unwrap(), e)),
+        })
+}
+
+pub fn parse_font_faces(data: &[u8]) -> Option<Vec<FontFace>> {
+    if data.get(0..4)? == b"ttcf" {
+        let count = read_u32(data, 8)? as usize;
+        (0..count)
+            .map(|i| parse_face(data, read_u32(data, 12 + i * 4)? as usize))
+            .collect()
+    } else {
+        Some(vec![parse_face(data, 0)?])
+    }
+}
+
+fn parse_face(data: &[u8], offset: usize) -> Option<FontFace> {
+    match data.get(offset..offset + 4)? {
+        [0, 1, 0, 0] | b"OTTO" | b"true" => (),
+        _ => return None,
+    }
+    let table_count = read_u16(data, offset + 4)? as usize;
+    let mut name_table = None;
+    let mut os2_table = None;
+    for i in 0..table_count {
+        let record = offset + 12 + i * 16;
+        let tag = data.get(record..record + 4)?;
+        let table_offset = read_u32(data, record + 8)? as usize;
+        match tag {
+            b"name" => name_table = Some(table_offset),
+            b"OS/2" => os2_table = Some(table_offset),
+            _ => (),
+        }
+    }
+
+    let mut face = FontFace {
+        names: parse_names(data, name_table?)?,
+        weight: 400,
+        italic: false,
+    };
+    if let Some(os2) = os2_table {
+        face.weight = read_u16(data, os2 + 4).unwrap_or(400);
+        let selection = read_u16(data, os2 + 62).unwrap_or(0);
+        face.italic = selection & 1 != 0;
+        if selection & (1 << 5) != 0 && face.weight < 600 {
+            face.weight = 700;
+        }
+    }
+    Some(face)
+}
+
+fn parse_names(data: &[u8], table: usize) -> Option<Vec<String>> {
+    let count = read_u16(data, table + 2)? as usize;
+    let storage = table + read_u16(data, table + 4)? as usize;
+    let mut names: Vec<String> = Vec::new();
+    for i in 0..count {
+        let record = table + 6 + i * 12;
+        let platform = read_u16(data, record)?;
+        let encoding = read_u16(data, record + 2)?;
+        let name_id = read_u16(data, record + 6)?;
+        let length = read_u16(data, record + 8)? as usize;
+        let start = storage + read_u16(data, record + 10)? as usize;
+        // Family, full name and typographic family are what renderers match against
+        if !matches!(name_id, 1 | 4 | 16) {
+            continue;
+        }
+        let bytes = match data.get(start..start + length) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let name = match (platform, encoding) {
+            (0, _) | (3, 0) | (3, 1) | (3, 10) => {
+                let units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            // Mac Roman, which matches Latin-1 for the ASCII names that matter
+            (1, 0) => bytes.iter().map(|&byte| char::from(byte)).collect(),
+            _ => continue,
+        };
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Some(names)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a minimal font with a name table and an OS/2 table
+    fn build_font(family: &str, weight: u16, italic: bool) -> Vec<u8> {
+        let utf16: Vec<u8> = family
+            .encode_utf16()
+            .flat_map(|unit| unit.to_be_bytes())
+            .collect();
+        let mut name = Vec::new();
+        name.extend_from_slice(&0u16.to_be_bytes());
+        name.extend_from_slice(&2u16.to_be_bytes());
+        name.extend_from_slice(&(6u16 + 2 * 12).to_be_bytes());
+        for (platform, encoding, name_id, offset, length) in [
+            (3u16, 1u16, 1u16, 0u16, utf16.len() as u16),
+            (1, 0, 4, utf16.len() as u16, family.len() as u16 + 5),
+        ] {
+            for value in [platform, encoding, 0x409, name_id, length, offset] {
+                name.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        name.extend_from_slice(&utf16);
+        name.extend_from_slice(format!("{} Bold", family).as_bytes());
+
+        let mut os2 = vec![0u8; 64];
+        os2[4..6].copy_from_slice(&weight.to_be_bytes());
+        os2[62..64].copy_from_slice(&(if italic { 1u16 } else { 0 }).to_be_bytes());
+
+        let mut font = Vec::new();
+        font.extend_from_slice(&[0, 1, 0, 0]);
+        font.extend_from_slice(&2u16.to_be_bytes());
+        font.extend_from_slice(&[0; 6]);
+        let name_offset = 12 + 2 * 16;
+        let os2_offset = name_offset + name.len();
+        for (tag, offset, length) in [
+            (b"OS/2", os2_offset, os2.len()),
+            (b"name", name_offset, name.len()),
+        ] {
+            font.extend_from_slice(tag);
+            font.extend_from_slice(&[0; 4]);
+            font.extend_from_slice(&(offset as u32).to_be_bytes());
+            font.extend_from_slice(&(length as u32).to_be_bytes());
+        }
+        font.extend_from_slice(&name);
+        font.extend_from_slice(&os2);
+        font
+    }
+
+    #[test]
+    fn parse_single_font() {
+        let faces = parse_font_faces(&build_font("Open Sans", 700, true)).unwrap();
+        assert_eq!(
+            faces,
+            vec![FontFace {
+                names: vec!["Open Sans".to_owned(), "Open Sans Bold".to_owned()],
+                weight: 700,
+                italic: true,
+            }]
+        );
+        assert!(faces[0].is_bold());
+        assert!(faces[0].has_name("open sans"));
+    }
+
+    #[test]
+    fn parse_font_collection() {
+        let first = build_font("Noto Sans CJK JP", 400, false);
+        let second = build_font("Noto Sans CJK KR", 400, false);
+        let mut collection = Vec::new();
+        collection.extend_from_slice(b"ttcf");
+        collection.extend_from_slice(&[0, 1, 0, 0]);
+        collection.extend_from_slice(&2u32.to_be_bytes());
+        collection.extend_from_slice(&20u32.to_be_bytes());
+        collection.extend_from_slice(&(20 + first.len() as u32).to_be_bytes());
+        // Table offsets in a collection are relative to the whole file
+        for (font, base) in [(&first, 20usize), (&second, 20 + first.len())] {
+            let mut font = font.clone();
+            for i in 0..2 {
+                let record = 12 + i * 16 + 8;
+                let offset = read_u32(&font, record).unwrap() + base as u32;
+                font[record..record + 4].copy_from_slice(&offset.to_be_bytes());
+            }
+            collection.extend_from_slice(&font);
+        }
+        let faces = parse_font_faces(&collection).unwrap();
+        assert_eq!(faces.len(), 2);
+        assert!(faces[1].has_name("Noto Sans CJK KR"));
+        assert!(parse_font_faces(b"not a font").is_none());
+        assert_eq!(sniff_font_type(&collection), Some("ttc"));
+        assert_eq!(sniff_font_type(&first), Some("ttf"));
+        assert_eq!(sniff_font_type(b"OTTO\0\x0a"), Some("otf"));
+        assert_eq!(sniff_font_type(b"\xff\xd8\xff\xe0"), None);
+    }
+}
@@ -0,0 +1,44 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Reads a v2 (or v4) timecodes file, as written by ffms2 and mkvextract,
+/// returning the start of every frame in seconds
+pub fn read_timecodes(path: &Path) -> Result<Vec<f64>, String> {
+    let contents =
+        read_to_string(path).map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))?;
+    parse_timecodes(&contents).map_err(|e| format!("{}: {}", path.to_str().unwrap(), e))
+}
+
+fn parse_timecodes(contents: &str) -> Result<Vec<f64>, String> {
+    let mut lines = contents.lines().map(str::trim);
+    match lines.next() {
+        Some(header) if header.starts_with("# timecode format v2") => (),
+        Some(header) if header.starts_with("# timestamp format v4") => (),
+        Some(header) => return Err(format!("Unsupported timecodes format: {}", header)),
+        // Left empty by scripts which have not been run yet
+        None => return Ok(Vec::new()),
+    }
+    lines
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse::<f64>()
+                .map(|millis| millis / 1000f64)
+                .map_err(|_| format!("Invalid timecode: {}", line))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_v2_timecodes() {
+        assert_eq!(
+            parse_timecodes("# timecode format v2\n0\n41.708\n# comment\n83.417\n").unwrap(),
+            vec![0.0, 41.708 / 1000f64, 83.417 / 1000f64]
+        );
+        assert!(parse_timecodes("# timecode format v1\nAssume 23.976\n").is_err());
+        assert_eq!(parse_timecodes(""), Ok(Vec::new()));
+    }
+}
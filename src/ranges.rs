@@ -0,0 +1,281 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref TIMESTAMP_REGEX: Regex =
+        Regex::new(r"^(?:(?:(\d+):)?(\d{1,2}):)?(\d{1,2}(?:\.\d+)?)$").unwrap();
+}
+
+/// How the frames of a video map to time
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameTiming {
+    Constant(f64),
+    /// The start of every frame in seconds, from a timecodes file
+    Timecodes(Vec<f64>),
+}
+
+impl FrameTiming {
+    /// The first frame shown at or after the given time
+    pub fn frame_at(&self, seconds: f64) -> u64 {
+        match *self {
+            // Rounded, since timestamps are usually truncated to milliseconds
+            FrameTiming::Constant(fps) => (seconds * fps).round() as u64,
+            // Timecodes are only accurate to the millisecond
+            FrameTiming::Timecodes(ref starts) => {
+                starts.partition_point(|&start| start < seconds - 0.000_5) as u64
+            }
+        }
+    }
+
+    /// When the given frame starts in seconds, which for the frame after the
+    /// last one is where the video ends
+    pub fn time_at(&self, frame: u64) -> f64 {
+        match *self {
+            FrameTiming::Constant(fps) => frame as f64 / fps,
+            FrameTiming::Timecodes(ref starts) => match starts.get(frame as usize) {
+                Some(&start) => start,
+                // Timecodes don't say how long the last frame lasts, so assume the average
+                None => match (starts.first(), starts.last()) {
+                    (Some(&first), Some(&last)) if starts.len() > 1 => {
+                        let duration = (last - first) / (starts.len() - 1) as f64;
+                        last + (frame + 1 - starts.len() as u64) as f64 * duration
+                    }
+                    _ => 0f64,
+                },
+            },
+        }
+    }
+
+    /// The average number of frames per second
+    pub fn fps(&self) -> f64 {
+        match *self {
+            FrameTiming::Constant(fps) => fps,
+            FrameTiming::Timecodes(ref starts) => match starts.last() {
+                Some(&last) if last > 0f64 => (starts.len() - 1) as f64 / last,
+                _ => 1f64,
+            },
+        }
+    }
+}
+
+/// A point in a video, either a frame number or a time in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Frame(u64),
+    Time(f64),
+    /// A SMPTE timecode, which counts frames rather than time
+    Timecode {
+        seconds: u64,
+        frames: u64,
+        drop_frame: bool,
+    },
+}
+
+impl Position {
+    /// Plain numbers are frames, anything with a colon or an `s` suffix is a
+    /// timestamp, e.g. `1:30.5`, `0:01:30.5` or `90.5s`
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        if let Ok(frame) = input.parse::<u64>() {
+            return Ok(Position::Frame(frame));
+        }
+        if let Some(seconds) = input.strip_suffix('s') {
+            return seconds
+                .parse::<f64>()
+                .ok()
+                .filter(|seconds| *seconds >= 0f64)
+                .map(Position::Time)
+                .ok_or_else(|| format!("Invalid timestamp: {}", input));
+        }
+        let captures = TIMESTAMP_REGEX
+            .captures(input)
+            .filter(|_| input.contains(':'))
+            .ok_or_else(|| format!("Invalid frame number or timestamp: {}", input))?;
+        let hours = captures
+            .get(1)
+            .map(|hours| hours.as_str().parse::<f64>().unwrap())
+            .unwrap_or(0f64);
+        let minutes = captures
+            .get(2)
+            .map(|minutes| minutes.as_str().parse::<f64>().unwrap())
+            .unwrap_or(0f64);
+        let seconds = captures[3].parse::<f64>().unwrap();
+        Ok(Position::Time(hours * 3600f64 + minutes * 60f64 + seconds))
+    }
+
+    /// The first frame shown at or after this position
+    pub fn to_frame(self, timing: &FrameTiming) -> u64 {
+        match self {
+            Position::Frame(frame) => frame,
+            Position::Time(seconds) => timing.frame_at(seconds),
+            // Without the rate of the source, assume that of the timing
+            Position::Timecode { .. } => self.timecode_to_time(timing.fps(), None).to_frame(timing),
+        }
+    }
+
+    /// Turns a SMPTE timecode into the time since `start`, the timecode of the
+    /// source's first frame, for a source at the given frame rate. Timecodes
+    /// count frames at the nearest whole rate, e.g. 30 for 29.97fps.
+    pub fn timecode_to_time(self, fps: f64, start: Option<Position>) -> Position {
+        let timebase = (fps.round() as u64).max(1);
+        match self.timecode_frames(timebase) {
+            Some(frames) => {
+                let start = start
+                    .and_then(|start| start.timecode_frames(timebase))
+                    .unwrap_or(0);
+                Position::Time(frames.saturating_sub(start) as f64 / fps)
+            }
+            None => self,
+        }
+    }
+
+    /// How many frames a SMPTE timecode counts, `None` for other positions
+    fn timecode_frames(self, timebase: u64) -> Option<u64> {
+        match self {
+            Position::Timecode {
+                seconds,
+                frames,
+                drop_frame,
+            } => {
+                let nominal = seconds * timebase + frames;
+                if !drop_frame {
+                    return Some(nominal);
+                }
+                // Drop-frame timecodes skip the first frame numbers of every
+                // minute, except every tenth one
+                let dropped_per_minute = 2 * (timebase / 30).max(1);
+                let minutes = seconds / 60;
+                Some(nominal - dropped_per_minute * (minutes - minutes / 10))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A span of a video, with an end of `None` running to the end of the video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: Position,
+    /// A frame end is inclusive, a time end is where the next frame would start
+    pub end: Option<Position>,
+}
+
+impl Range {
+    /// Parses `START-END`, where a missing end runs to the end of the video
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let (start, end) = input
+            .split_once('-')
+            .ok_or_else(|| format!("Expected a range like START-END: {}", input))?;
+        let start = Position::parse(start)?;
+        let end = match end.trim() {
+            "" => None,
+            end => Some(Position::parse(end)?),
+        };
+        Ok(Range { start, end })
+    }
+
+    /// Parses a comma separated list of ranges
+    pub fn parse_list(input: &str) -> Result<Vec<Self>, String> {
+        input.split(',').map(Range::parse).collect()
+    }
+
+    /// Turns SMPTE timecodes into times, see `Position::timecode_to_time`
+    pub fn timecodes_to_time(self, fps: f64, start: Option<Position>) -> Range {
+        Range {
+            start: self.start.timecode_to_time(fps, start),
+            end: self.end.map(|end| end.timecode_to_time(fps, start)),
+        }
+    }
+
+    /// Converts to an inclusive frame range, `total_frames` being the length of
+    /// the video for open ends. Returns `None` for an empty range.
+    pub fn to_frames(self, timing: &FrameTiming, total_frames: u64) -> Option<(u64, u64)> {
+        let start = self.start.to_frame(timing);
+        let end = match self.end {
+            Some(Position::Frame(frame)) => frame,
+            Some(end) => end.to_frame(timing).checked_sub(1)?,
+            None => total_frames.checked_sub(1)?,
+        };
+        if start > end {
+            return None;
+        }
+        Some((start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_positions() {
+        assert_eq!(Position::parse("1234"), Ok(Position::Frame(1234)));
+        assert_eq!(Position::parse("90.5s"), Ok(Position::Time(90.5)));
+        assert_eq!(Position::parse("1:30.5"), Ok(Position::Time(90.5)));
+        assert_eq!(Position::parse("1:01:30"), Ok(Position::Time(3690.0)));
+        assert!(Position::parse("12.5").is_err());
+        assert!(Position::parse("abc").is_err());
+    }
+
+    #[test]
+    fn ranges_to_frames() {
+        let timing = FrameTiming::Constant(24_000f64 / 1_001f64);
+        let ranges = Range::parse_list("0-99,1:30-1:31.001,2000-").unwrap();
+        assert_eq!(ranges[0].to_frames(&timing, 3000), Some((0, 99)));
+        assert_eq!(ranges[1].to_frames(&timing, 3000), Some((2158, 2181)));
+        assert_eq!(ranges[2].to_frames(&timing, 3000), Some((2000, 2999)));
+        assert_eq!(
+            Range::parse("50-10").unwrap().to_frames(&timing, 3000),
+            None
+        );
+        assert!(Range::parse("100").is_err());
+    }
+
+    #[test]
+    fn smpte_timecodes() {
+        let timecode = |seconds, frames, drop_frame| Position::Timecode {
+            seconds,
+            frames,
+            drop_frame,
+        };
+        let film = FrameTiming::Constant(24_000f64 / 1_001f64);
+        assert_eq!(timecode(90, 12, false).to_frame(&film), 2172);
+        let ntsc = FrameTiming::Constant(30_000f64 / 1_001f64);
+        assert_eq!(timecode(60, 2, true).to_frame(&ntsc), 1800);
+        assert_eq!(timecode(600, 0, true).to_frame(&ntsc), 17982);
+        // Counted at the source's own rate, not at the rate after conversion to CFR
+        let cfr = FrameTiming::Constant(120_000f64 / 1_001f64);
+        assert_eq!(
+            timecode(90, 12, false)
+                .timecode_to_time(24_000f64 / 1_001f64, None)
+                .to_frame(&cfr),
+            10860
+        );
+        assert_eq!(
+            timecode(3690, 12, false)
+                .timecode_to_time(24_000f64 / 1_001f64, Some(timecode(3600, 0, false)))
+                .to_frame(&film),
+            2172
+        );
+    }
+
+    #[test]
+    fn ranges_with_timecodes() {
+        // 24fps for two seconds, then 30fps
+        let mut starts: Vec<f64> = (0..48).map(|frame| frame as f64 / 24f64).collect();
+        starts.extend((0..60).map(|frame| 2f64 + frame as f64 / 30f64));
+        let starts: Vec<f64> = starts
+            .into_iter()
+            .map(|start| (start * 1000f64).floor() / 1000f64)
+            .collect();
+        let timing = FrameTiming::Timecodes(starts);
+        assert_eq!(timing.time_at(48), 2f64);
+        assert!((timing.time_at(108) - 4.003).abs() < 0.001);
+        let range = Range::parse("0:01.5-0:03.0").unwrap();
+        assert_eq!(range.to_frames(&timing, 108), Some((36, 77)));
+        assert_eq!(
+            Range::parse("0:03-").unwrap().to_frames(&timing, 108),
+            Some((78, 107))
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use crate::parsers::ffprobe::get_streams_list;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Picks a stream by its metadata, so that the same choice can be applied to
+/// files with different track orders
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamSelector {
+    /// 0-indexed among streams of the same type
+    Index(usize),
+    /// ISO 639 language code
+    Language(String),
+    /// A bare two or three letter code, which selects by language if any
+    /// stream has that language and by title otherwise, e.g. `ja` or `SDH`
+    Code(String),
+    /// Case-insensitive substring of the track title
+    Title(String),
+    /// Codec name as reported by ffprobe, e.g. `ass` or `hdmv_pgs_subtitle`
+    Codec(String),
+    /// Tracks flagged as forced
+    Forced,
+    /// Tracks matching every one of the selectors
+    All(Vec<StreamSelector>),
+}
+
+impl StreamSelector {
+    /// Accepts `lang=eng`, `title~Signs`, `codec=ass`, `forced` and `index=N`,
+    /// joined with commas to require all of them. Bare numbers select by index,
+    /// two or three letter codes by language or else by title, and anything
+    /// else by title
+    pub fn parse(selector: &str) -> StreamSelector {
+        let selector = selector.trim();
+        if selector.contains(',') {
+            return StreamSelector::All(selector.split(',').map(StreamSelector::parse).collect());
+        }
+        if selector.eq_ignore_ascii_case("forced") {
+            return StreamSelector::Forced;
+        }
+        if let Some((key, value)) = selector.split_once(['=', '~']) {
+            let value = value.trim().to_lowercase();
+            match key.trim().to_lowercase().as_str() {
+                "lang" | "language" => return StreamSelector::Language(value),
+                "title" => return StreamSelector::Title(value),
+                "codec" => return StreamSelector::Codec(value),
+                "index" => {
+                    if let Ok(index) = value.parse() {
+                        return StreamSelector::Index(index);
+                    }
+                }
+                _ => (),
+            }
+        }
+        if let Ok(index) = selector.parse() {
+            StreamSelector::Index(index)
+        } else if (2..=3).contains(&selector.len())
+            && selector.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            StreamSelector::Code(selector.to_lowercase())
+        } else {
+            StreamSelector::Title(selector.to_lowercase())
+        }
+    }
+
+    /// Settles bare codes on a language if any of the streams has it, or
+    /// on a title otherwise
+    fn resolve(&self, streams: &[StreamInfo]) -> StreamSelector {
+        match *self {
+            StreamSelector::Code(ref code) => {
+                let language = StreamSelector::Language(code.clone());
+                if streams
+                    .iter()
+                    .any(|stream| language.matches(stream, stream.type_index))
+                {
+                    language
+                } else {
+                    StreamSelector::Title(code.clone())
+                }
+            }
+            StreamSelector::All(ref selectors) => StreamSelector::All(
+                selectors
+                    .iter()
+                    .map(|selector| selector.resolve(streams))
+                    .collect(),
+            ),
+            ref selector => selector.clone(),
+        }
+    }
+
+    fn matches(&self, stream: &StreamInfo, type_index: usize) -> bool {
+        match *self {
+            StreamSelector::Index(index) => index == type_index,
+            StreamSelector::Language(ref language) => stream
+                .language
+                .as_deref()
+                .is_some_and(|stream_language| same_language(stream_language, language)),
+            // Only reached unresolved when there are no streams to settle it
+            StreamSelector::Code(_) => false,
+            StreamSelector::Title(ref title) => stream
+                .title
+                .as_deref()
+                .map(|stream_title| stream_title.to_lowercase().contains(title.as_str()))
+                .unwrap_or(false),
+            StreamSelector::Codec(ref codec) => stream.codec_name.eq_ignore_ascii_case(codec),
+            StreamSelector::Forced => stream.forced,
+            StreamSelector::All(ref selectors) => selectors
+                .iter()
+                .all(|selector| selector.matches(stream, type_index)),
+        }
+    }
+}
+
+/// ISO 639-1 codes with their ISO 639-2 equivalents, bibliographic first
+const LANGUAGE_CODES: &[&[&str]] = &[
+    &["ar", "ara"],
+    &["bg", "bul"],
+    &["ca", "cat"],
+    &["cs", "cze", "ces"],
+    &["da", "dan"],
+    &["de", "ger", "deu"],
+    &["el", "gre", "ell"],
+    &["en", "eng"],
+    &["es", "spa"],
+    &["fa", "per", "fas"],
+    &["fi", "fin"],
+    &["fr", "fre", "fra"],
+    &["he", "heb"],
+    &["hi", "hin"],
+    &["hr", "hrv"],
+    &["hu", "hun"],
+    &["id", "ind"],
+    &["it", "ita"],
+    &["ja", "jpn"],
+    &["ko", "kor"],
+    &["ms", "may", "msa"],
+    &["nl", "dut", "nld"],
+    &["no", "nor"],
+    &["pl", "pol"],
+    &["pt", "por"],
+    &["ro", "rum", "ron"],
+    &["ru", "rus"],
+    &["sk", "slo", "slk"],
+    &["sr", "srp"],
+    &["sv", "swe"],
+    &["th", "tha"],
+    &["tr", "tur"],
+    &["uk", "ukr"],
+    &["vi", "vie"],
+    &["zh", "chi", "zho"],
+];
+
+/// Compares language codes case-insensitively, taking ISO 639-1 and both
+/// kinds of ISO 639-2 codes for the same language as equal
+fn same_language(a: &str, b: &str) -> bool {
+    let canonical = |code: &str| {
+        let code = code.to_lowercase();
+        LANGUAGE_CODES
+            .iter()
+            .find(|codes| codes.contains(&code.as_str()))
+            .map(|codes| codes[0].to_owned())
+            .unwrap_or(code)
+    };
+    canonical(a) == canonical(b)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    /// Index among all streams in the file
+    pub index: usize,
+    /// Index among streams of the same type
+    pub type_index: usize,
+    pub codec_name: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub forced: bool,
+    /// Presentation time of the first packet in seconds
+    pub start_time: Option<f64>,
+}
+
+/// Lists the streams of the given type (`audio`, `subtitle`, ...) in a file
+pub fn get_stream_infos(path: &Path, codec_type: &str) -> Result<Vec<StreamInfo>, String> {
+    Ok(parse_stream_infos(&get_streams_list(path)?, codec_type))
+}
+
+fn parse_stream_infos(streams: &[HashMap<String, String>], codec_type: &str) -> Vec<StreamInfo> {
+    streams
+        .iter()
+        .filter(|stream| stream.get("codec_type").map(String::as_str) == Some(codec_type))
+        .enumerate()
+        .filter_map(|(type_index, stream)| {
+            Some(StreamInfo {
+                index: stream.get("index")?.parse().ok()?,
+                type_index,
+                codec_name: stream.get("codec_name").cloned().unwrap_or_default(),
+                language: stream
+                    .get("TAG:language")
+                    .filter(|language| language.as_str() != "und")
+                    .cloned(),
+                title: stream.get("TAG:title").cloned(),
+                forced: stream.get("DISPOSITION:forced").map(String::as_str) == Some("1"),
+                start_time: stream
+                    .get("start_time")
+                    .and_then(|start_time| start_time.parse().ok()),
+            })
+        })
+        .collect()
+}
+
+/// Returns the first stream matching the selector
+pub fn select_stream<'a>(
+    streams: &'a [StreamInfo],
+    selector: &StreamSelector,
+) -> Option<&'a StreamInfo> {
+    let selector = selector.resolve(streams);
+    streams
+        .iter()
+        .find(|stream| selector.matches(stream, stream.type_index))
+}
+
+/// Returns every stream matching the selector
+pub fn select_streams(streams: &[StreamInfo], selector: &StreamSelector) -> Vec<StreamInfo> {
+    let selector = selector.resolve(streams);
+    streams
+        .iter()
+        .filter(|stream| selector.matches(stream, stream.type_index))
+        .cloned()
+        .collect()
+}
+
+/// Resolves the selector against a file, failing if nothing matches
+pub fn resolve_stream(
+    path: &Path,
+    codec_type: &str,
+    selector: &StreamSelector,
+) -> Result<StreamInfo, String> {
+    let streams = get_stream_infos(path, codec_type)?;
+    select_stream(&streams, selector).cloned().ok_or_else(|| {
+        format!(
+            "No {} track matching {:?} found in {}",
+            codec_type,
+            selector,
+            path.to_str().unwrap()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    fn dual_audio() -> Vec<StreamInfo> {
+        parse_stream_infos(
+            &[
+                stream(&[("index", "0"), ("codec_type", "video")]),
+                stream(&[
+                    ("index", "1"),
+                    ("codec_type", "audio"),
+                    ("codec_name", "aac"),
+                    ("TAG:language", "eng"),
+                    ("TAG:title", "English Dub"),
+                ]),
+                stream(&[
+                    ("index", "2"),
+                    ("codec_type", "audio"),
+                    ("codec_name", "flac"),
+                    ("TAG:language", "jpn"),
+                    ("TAG:title", "Japanese 2.0"),
+                ]),
+            ],
+            "audio",
+        )
+    }
+
+    #[test]
+    fn parse_selectors() {
+        assert_eq!(StreamSelector::parse("1"), StreamSelector::Index(1));
+        assert_eq!(
+            StreamSelector::parse("JPN"),
+            StreamSelector::Code("jpn".to_owned())
+        );
+        assert_eq!(
+            StreamSelector::parse("Commentary"),
+            StreamSelector::Title("commentary".to_owned())
+        );
+    }
+
+    #[test]
+    fn select_by_index_language_and_title() {
+        let streams = dual_audio();
+        let select = |selector: &str| {
+            select_stream(&streams, &StreamSelector::parse(selector)).map(|stream| stream.index)
+        };
+        assert_eq!(select("1"), Some(2));
+        assert_eq!(select("jpn"), Some(2));
+        assert_eq!(select("english"), Some(1));
+        assert_eq!(select("ger"), None);
+        assert_eq!(select("ja"), Some(2));
+        assert_eq!(select("lang=en"), Some(1));
+    }
+
+    #[test]
+    fn select_bare_codes_by_title_without_a_language_match() {
+        let streams = parse_stream_infos(
+            &[
+                stream(&[
+                    ("index", "2"),
+                    ("codec_type", "subtitle"),
+                    ("TAG:language", "eng"),
+                    ("TAG:title", "Full"),
+                ]),
+                stream(&[
+                    ("index", "3"),
+                    ("codec_type", "subtitle"),
+                    ("TAG:language", "eng"),
+                    ("TAG:title", "SDH"),
+                ]),
+            ],
+            "subtitle",
+        );
+        let select = |selector: &str| {
+            select_stream(&streams, &StreamSelector::parse(selector)).map(|stream| stream.index)
+        };
+        assert_eq!(select("SDH"), Some(3));
+        assert_eq!(select("en"), Some(2));
+        assert_eq!(select("lang=sdh"), None);
+    }
+
+    #[test]
+    fn select_subtitles_by_keyed_selectors() {
+        let streams = parse_stream_infos(
+            &[
+                stream(&[("index", "0"), ("codec_type", "video")]),
+                stream(&[
+                    ("index", "2"),
+                    ("codec_type", "subtitle"),
+                    ("codec_name", "ass"),
+                    ("TAG:language", "eng"),
+                    ("TAG:title", "Full Subtitles"),
+                    ("DISPOSITION:forced", "0"),
+                ]),
+                stream(&[
+                    ("index", "3"),
+                    ("codec_type", "subtitle"),
+                    ("codec_name", "ass"),
+                    ("TAG:language", "eng"),
+                    ("TAG:title", "Signs & Songs"),
+                    ("DISPOSITION:forced", "1"),
+                ]),
+                stream(&[
+                    ("index", "4"),
+                    ("codec_type", "subtitle"),
+                    ("codec_name", "hdmv_pgs_subtitle"),
+                    ("TAG:language", "ger"),
+                ]),
+            ],
+            "subtitle",
+        );
+        let select = |selector: &str| {
+            select_stream(&streams, &StreamSelector::parse(selector)).map(|stream| stream.index)
+        };
+        assert_eq!(select("lang=eng"), Some(2));
+        assert_eq!(select("title~Signs"), Some(3));
+        assert_eq!(select("forced"), Some(3));
+        assert_eq!(select("codec=hdmv_pgs_subtitle"), Some(4));
+        assert_eq!(select("index=2"), Some(4));
+        assert_eq!(select("lang=eng,codec=ass,forced"), Some(3));
+        assert_eq!(select("lang=ger,codec=ass"), None);
+    }
+}
@@ -0,0 +1,569 @@
+use crate::input::determine_input_type;
+use crate::input::InputTypes;
+use crate::parsers::ass::format_timestamp;
+use crate::parsers::ass::parse_timestamp;
+use crate::parsers::ass::AssEvent;
+use crate::parsers::ass::AssScript;
+use crate::selector::get_stream_infos;
+use crate::selector::select_stream;
+use crate::selector::select_streams;
+use crate::selector::StreamInfo;
+use crate::selector::StreamSelector;
+use lazy_static::lazy_static;
+use regex::Captures;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+lazy_static! {
+    static ref OVERRIDE_BLOCK_PATTERN: Regex = Regex::new(r"\{[^}]*\}").unwrap();
+    static ref STYLE_RESET_PATTERN: Regex = Regex::new(r"\\r([^\\}]*)").unwrap();
+}
+
+const MANIFEST_HEADER: &str = "track\tindex\tcodec\tlanguage\tforced\ttitle\tfile";
+
+/// The extension a subtitle codec is stored with, and whether ffmpeg can
+/// copy the stream into it as-is
+pub fn subtitle_extension(codec_name: &str) -> (&'static str, bool) {
+    match codec_name {
+        "ass" => ("ass", true),
+        "ssa" => ("ssa", true),
+        "subrip" | "srt" => ("srt", true),
+        "webvtt" => ("vtt", true),
+        "hdmv_pgs_subtitle" => ("sup", true),
+        // Extracted with mkvextract, which writes the .sub next to the .idx
+        "dvd_subtitle" => ("idx", true),
+        "mov_text" | "text" => ("srt", false),
+        // ffmpeg cannot write anything else without re-encoding it
+        _ => ("mks", true),
+    }
+}
+
+/// Builds `<name>.<track>.<language>[.<title>].<ext>` for a subtitle stream
+pub fn tagged_subtitle_path(in_file: &Path, stream: &StreamInfo) -> PathBuf {
+    let mut extension = format!(
+        "{}.{}",
+        stream.type_index,
+        stream.language.as_deref().unwrap_or("und")
+    );
+    if let Some(ref title) = stream.title {
+        let title: String = title
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '.' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect();
+        if !title.trim().is_empty() {
+            extension.push('.');
+            extension.push_str(title.trim());
+        }
+    }
+    extension.push('.');
+    extension.push_str(subtitle_extension(&stream.codec_name).0);
+    in_file.with_extension(extension)
+}
+
+/// Whether the codec is image based, so it can only be burned in as-is
+pub fn is_bitmap_subtitle(codec_name: &str) -> bool {
+    matches!(codec_name, "hdmv_pgs_subtitle" | "dvd_subtitle")
+}
+
+/// Extracts a single subtitle stream, converting text subtitles to ASS if
+/// the output file asks for it. VobSub goes through mkvextract, since ffmpeg
+/// cannot write .idx/.sub pairs
+pub fn extract_subtitle_stream(
+    in_file: &Path,
+    stream: &StreamInfo,
+    out_file: &Path,
+) -> Result<(), String> {
+    let status = if stream.codec_name == "dvd_subtitle" {
+        // ffmpeg numbers Matroska streams in track order, the same as mkvmerge's
+        // track IDs, but there is no such mapping for other containers
+        if !matches!(determine_input_type(in_file), Some(InputTypes::Matroska)) {
+            return Err(format!(
+                "VobSub track {} of {} can only be extracted from Matroska files",
+                stream.type_index,
+                in_file.to_str().unwrap()
+            ));
+        }
+        Command::new("mkvextract")
+            .args([
+                "tracks",
+                in_file.to_str().unwrap(),
+                &format!("{}:{}", stream.index, out_file.to_str().unwrap()),
+            ])
+            .status()
+    } else {
+        let map = format!("0:{}", stream.index);
+        let mut args = vec![
+            "-i",
+            in_file.to_str().unwrap(),
+            "-map",
+            &map,
+            "-map_chapters",
+            "-1",
+        ];
+        let (extension, copy) = subtitle_extension(&stream.codec_name);
+        if copy && out_file.extension().and_then(|ext| ext.to_str()) == Some(extension) {
+            args.extend_from_slice(&["-c:s", "copy"]);
+        }
+        args.push(out_file.to_str().unwrap());
+        Command::new("ffmpeg").args(&args).status()
+    };
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(format!(
+            "Failed to extract subtitle track {} from {}",
+            stream.type_index,
+            in_file.to_str().unwrap()
+        )),
+        Err(x) => Err(format!("{}", x)),
+    }
+}
+
+pub fn manifest_path(in_file: &Path) -> PathBuf {
+    in_file.with_extension("subtitles.tsv")
+}
+
+/// Extracts every subtitle track matching the selector (or all of them)
+/// to tagged file names, and lists them in `<name>.subtitles.tsv`
+pub fn extract_all_subtitles(
+    in_file: &Path,
+    selector: Option<&StreamSelector>,
+) -> Result<Vec<(StreamInfo, PathBuf)>, String> {
+    let streams = get_stream_infos(in_file, "subtitle")?;
+    let streams = match selector {
+        Some(selector) => select_streams(&streams, selector),
+        None => streams,
+    };
+    if streams.is_empty() {
+        eprintln!(
+            "Warning: no subtitle tracks to extract from {}",
+            in_file.to_str().unwrap()
+        );
+    }
+
+    let mut extracted = Vec::with_capacity(streams.len());
+    for stream in streams {
+        let out_file = tagged_subtitle_path(in_file, &stream);
+        if out_file.exists() {
+            println!(
+                "Cowardly refusing to overwrite existing subtitles: {}",
+                out_file.to_string_lossy()
+            );
+        } else {
+            extract_subtitle_stream(in_file, &stream, &out_file)?;
+        }
+        extracted.push((stream, out_file));
+    }
+
+    write_subtitle_manifest(&manifest_path(in_file), &extracted)?;
+    Ok(extracted)
+}
+
+fn write_subtitle_manifest(path: &Path, entries: &[(StreamInfo, PathBuf)]) -> Result<(), String> {
+    let mut manifest = File::create(path).map_err(|e| e.to_string())?;
+    manifest
+        .write_all(format_subtitle_manifest(entries).as_bytes())
+        .map_err(|e| e.to_string())
+}
+
+fn format_subtitle_manifest(entries: &[(StreamInfo, PathBuf)]) -> String {
+    let mut manifest = String::from(MANIFEST_HEADER);
+    manifest.push('\n');
+    for (stream, file) in entries {
+        manifest.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            stream.type_index,
+            stream.index,
+            stream.codec_name,
+            stream.language.as_deref().unwrap_or("und"),
+            if stream.forced { 1 } else { 0 },
+            stream.title.as_deref().unwrap_or("").replace('\t', " "),
+            file.file_name().unwrap().to_str().unwrap()
+        ));
+    }
+    manifest
+}
+
+/// Reads back a manifest written by `extract_all_subtitles`, with the file
+/// names resolved relative to the manifest
+pub fn read_subtitle_manifest(path: &Path) -> Result<Vec<(StreamInfo, PathBuf)>, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| e.to_string())?;
+    parse_subtitle_manifest(&contents)
+        .map(|entries| {
+            entries
+                .into_iter()
+                .map(|(stream, file)| (stream, path.with_file_name(file)))
+                .collect()
+        })
+        .ok_or_else(|| format!("Malformed subtitle manifest {}", path.to_str().unwrap()))
+}
+
+fn parse_subtitle_manifest(contents: &str) -> Option<Vec<(StreamInfo, String)>> {
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && *line != MANIFEST_HEADER)
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                return None;
+            }
+            Some((
+                StreamInfo {
+                    index: fields[1].parse().ok()?,
+                    type_index: fields[0].parse().ok()?,
+                    codec_name: fields[2].to_owned(),
+                    language: Some(fields[3])
+                        .filter(|language| *language != "und")
+                        .map(str::to_owned),
+                    title: Some(fields[5])
+                        .filter(|title| !title.is_empty())
+                        .map(str::to_owned),
+                    forced: fields[4] == "1",
+                    start_time: None,
+                },
+                fields[6].to_owned(),
+            ))
+        })
+        .collect()
+}
+
+/// Finds the subtitle file a script should use for a video: an entry from
+/// its manifest if a selector is given and one was written, else `<name>.ass`,
+/// falling back to bitmap subtitles in `<name>.sup` or `<name>.idx`
+pub fn find_subtitle_file(
+    in_file: &Path,
+    selector: Option<&StreamSelector>,
+) -> Result<PathBuf, String> {
+    let manifest = manifest_path(in_file);
+    if let (Some(selector), true) = (selector, manifest.exists()) {
+        let entries = read_subtitle_manifest(&manifest)?;
+        let streams: Vec<StreamInfo> = entries.iter().map(|(stream, _)| stream.clone()).collect();
+        return match select_stream(&streams, selector) {
+            Some(stream) => Ok(entries
+                .into_iter()
+                .find(|(entry, _)| entry.index == stream.index)
+                .unwrap()
+                .1),
+            None => Err(format!(
+                "No subtitle track matching {:?} in {}",
+                selector,
+                manifest.to_str().unwrap()
+            )),
+        };
+    }
+    Ok(["ass", "sup", "idx"]
+        .iter()
+        .map(|extension| in_file.with_extension(extension))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| in_file.with_extension("ass")))
+}
+
+/// A range of one script placed on the output timeline, all in centiseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetimedPart {
+    /// Index into the merged scripts
+    pub script: usize,
+    /// Start of the range in the script's own time
+    pub start: u64,
+    /// End of the range in the script's own time, exclusive
+    pub end: u64,
+    /// Where the range starts in the output
+    pub offset: u64,
+}
+
+/// Builds one script for a spliced timeline. Events are clipped to each part
+/// and shifted to its offset, and styles which clash with a differently
+/// defined style of an earlier script are renamed.
+pub fn merge_retimed_scripts(
+    scripts: &[AssScript],
+    parts: &[RetimedPart],
+) -> Result<AssScript, String> {
+    let first = scripts
+        .first()
+        .ok_or_else(|| "No subtitles to merge".to_owned())?;
+    let mut merged = AssScript {
+        styles: Vec::new(),
+        events: Vec::new(),
+        ..first.clone()
+    };
+    let name_field = merged
+        .style_field("Name")
+        .ok_or_else(|| "Subtitle styles have no Name field".to_owned())?;
+    let (start_field, end_field, style_field) = match (
+        merged.event_field("Start"),
+        merged.event_field("End"),
+        merged.event_field("Style"),
+    ) {
+        (Some(start), Some(end), Some(style)) => (start, end, style),
+        _ => return Err("Subtitle events have no Start, End or Style field".to_owned()),
+    };
+
+    let mut renames: Vec<HashMap<String, String>> = Vec::with_capacity(scripts.len());
+    for (i, script) in scripts.iter().enumerate() {
+        if i > 0 {
+            if script.info("PlayResX") != first.info("PlayResX")
+                || script.info("PlayResY") != first.info("PlayResY")
+            {
+                eprintln!(
+                    "Warning: merged subtitles have different resolutions, \
+                     typesetting from some segments will be misplaced"
+                );
+            }
+            for (section, lines) in &script.other_sections {
+                match merged
+                    .other_sections
+                    .iter_mut()
+                    .find(|(existing, _)| existing == section)
+                {
+                    Some((_, existing)) => existing.extend_from_slice(lines),
+                    None => merged.other_sections.push((section.clone(), lines.clone())),
+                }
+            }
+        }
+
+        let mut script_renames = HashMap::new();
+        for style in &script.styles {
+            let mut style = remap_fields(style, &script.style_format, &merged.style_format);
+            let name = style[name_field].clone();
+            match merged
+                .styles
+                .iter()
+                .find(|existing| existing[name_field] == name)
+            {
+                None => merged.styles.push(style),
+                Some(existing) if *existing == style => (),
+                Some(_) => {
+                    let mut suffix = i + 1;
+                    let mut new_name = format!("{}_{}", name, suffix);
+                    while merged
+                        .styles
+                        .iter()
+                        .any(|existing| existing[name_field] == new_name)
+                    {
+                        suffix += 1;
+                        new_name = format!("{}_{}", name, suffix);
+                    }
+                    style[name_field] = new_name.clone();
+                    merged.styles.push(style);
+                    script_renames.insert(name, new_name);
+                }
+            }
+        }
+        renames.push(script_renames);
+    }
+
+    for part in parts {
+        let script = &scripts[part.script];
+        for event in &script.events {
+            let mut fields =
+                remap_fields(&event.fields, &script.event_format, &merged.event_format);
+            let (start, end) = match (
+                parse_timestamp(&fields[start_field]),
+                parse_timestamp(&fields[end_field]),
+            ) {
+                (Some(start), Some(end)) => (start, end),
+                _ => continue,
+            };
+            if end <= part.start || start >= part.end {
+                continue;
+            }
+            fields[start_field] =
+                format_timestamp(start.max(part.start) - part.start + part.offset);
+            fields[end_field] = format_timestamp(end.min(part.end) - part.start + part.offset);
+            let script_renames = &renames[part.script];
+            if let Some(new) = script_renames.get(&fields[style_field]) {
+                fields[style_field] = new.clone();
+            }
+            // Inline style resets refer to styles by name as well
+            let text = fields.last_mut().unwrap();
+            *text = rename_style_resets(text, script_renames);
+            merged.events.push(AssEvent {
+                kind: event.kind.clone(),
+                fields,
+            });
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Renames the styles which `\r` tags in override blocks reset to, matching
+/// whole style names so that every tag is renamed at most once
+fn rename_style_resets(text: &str, renames: &HashMap<String, String>) -> String {
+    if renames.is_empty() {
+        return text.to_owned();
+    }
+    OVERRIDE_BLOCK_PATTERN
+        .replace_all(text, |block: &Captures| {
+            STYLE_RESET_PATTERN
+                .replace_all(&block[0], |reset: &Captures| match renames.get(&reset[1]) {
+                    Some(new) => format!("\\r{}", new),
+                    None => reset[0].to_owned(),
+                })
+                .into_owned()
+        })
+        .into_owned()
+}
+
+/// Reorders fields from one format line to another, by field name
+fn remap_fields(fields: &[String], from: &[String], to: &[String]) -> Vec<String> {
+    if from == to {
+        return fields.to_vec();
+    }
+    to.iter()
+        .map(|name| {
+            from.iter()
+                .position(|field| field.eq_ignore_ascii_case(name))
+                .and_then(|i| fields.get(i).cloned())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signs() -> StreamInfo {
+        StreamInfo {
+            index: 3,
+            type_index: 1,
+            codec_name: "ass".to_owned(),
+            language: Some("eng".to_owned()),
+            title: Some("Signs & Songs: [Group]".to_owned()),
+            forced: true,
+            start_time: None,
+        }
+    }
+
+    #[test]
+    fn tagged_subtitle_names() {
+        assert_eq!(
+            tagged_subtitle_path(Path::new("show/ep01.mkv"), &signs()),
+            PathBuf::from("show/ep01.1.eng.Signs & Songs_ [Group].ass")
+        );
+        let pgs = StreamInfo {
+            codec_name: "hdmv_pgs_subtitle".to_owned(),
+            language: None,
+            title: None,
+            ..signs()
+        };
+        assert_eq!(
+            tagged_subtitle_path(Path::new("ep01.mkv"), &pgs),
+            PathBuf::from("ep01.1.und.sup")
+        );
+    }
+
+    #[test]
+    fn vobsub_needs_matroska() {
+        let vobsub = StreamInfo {
+            codec_name: "dvd_subtitle".to_owned(),
+            ..signs()
+        };
+        assert!(
+            extract_subtitle_stream(Path::new("ep01.mp4"), &vobsub, Path::new("ep01.idx")).is_err()
+        );
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let entries = vec![(signs(), PathBuf::from("ep01.1.eng.Signs.ass"))];
+        let manifest = format_subtitle_manifest(&entries);
+        assert_eq!(
+            parse_subtitle_manifest(&manifest),
+            Some(vec![(signs(), "ep01.1.eng.Signs.ass".to_owned())])
+        );
+        assert_eq!(parse_subtitle_manifest("1\t2\tass\n"), None);
+    }
+
+    #[test]
+    fn rename_whole_style_names_once() {
+        let renames: HashMap<String, String> = [("Sign", "Sign_2"), ("Sign_2", "Sign_2_2")]
+            .iter()
+            .map(|&(old, new)| (old.to_owned(), new.to_owned()))
+            .collect();
+        assert_eq!(
+            rename_style_resets(r"{\rSign}Sign{\i1\rSign_2\b1}\rSign", &renames),
+            r"{\rSign_2}Sign{\i1\rSign_2_2\b1}\rSign"
+        );
+    }
+
+    #[test]
+    fn merge_ordered_chapter_subtitles() {
+        let episode = AssScript::parse(
+            "[Script Info]\nPlayResX: 1920\nPlayResY: 1080\n\n\
+             [V4+ Styles]\nFormat: Name, Fontname\nStyle: Default,Open Sans\n\n\
+             [Events]\nFormat: Layer, Start, End, Style, Text\n\
+             Dialogue: 0,0:00:01.00,0:00:02.00,Default,Before the OP\n\
+             Dialogue: 0,0:00:09.00,0:00:11.00,Default,Across the cut\n\
+             Dialogue: 0,0:00:12.00,0:00:13.00,Default,After the OP\n",
+        )
+        .unwrap();
+        let opening = AssScript::parse(
+            "[Script Info]\nPlayResX: 1920\nPlayResY: 1080\n\n\
+             [V4+ Styles]\nFormat: Name, Fontname\nStyle: Default,Kaushan Script\n\n\
+             [Events]\nFormat: Layer, Start, End, Style, Text\n\
+             Dialogue: 0,0:00:00.50,0:00:01.50,Default,{\\rDefault\\i1}Karaoke\n",
+        )
+        .unwrap();
+        let merged = merge_retimed_scripts(
+            &[episode, opening],
+            &[
+                RetimedPart {
+                    script: 0,
+                    start: 0,
+                    end: 1_000,
+                    offset: 0,
+                },
+                RetimedPart {
+                    script: 1,
+                    start: 0,
+                    end: 9_000,
+                    offset: 1_000,
+                },
+                RetimedPart {
+                    script: 0,
+                    start: 1_000,
+                    end: 2_000,
+                    offset: 10_000,
+                },
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            merged.styles,
+            vec![
+                vec!["Default".to_owned(), "Open Sans".to_owned()],
+                vec!["Default_2".to_owned(), "Kaushan Script".to_owned()],
+            ]
+        );
+        let events: Vec<String> = merged
+            .events
+            .iter()
+            .map(|event| event.fields.join(","))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "0,0:00:01.00,0:00:02.00,Default,Before the OP",
+                "0,0:00:09.00,0:00:10.00,Default,Across the cut",
+                "0,0:00:10.50,0:00:11.50,Default_2,{\\rDefault_2\\i1}Karaoke",
+                "0,0:01:40.00,0:01:41.00,Default,Across the cut",
+                "0,0:01:42.00,0:01:43.00,Default,After the OP",
+            ]
+        );
+    }
+}